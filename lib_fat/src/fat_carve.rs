@@ -0,0 +1,471 @@
+use super::{
+    cluster_size, extents, first_sector_of_cluster, free_extents, read_cluster,
+    Fat, FatError, FatExtent,
+};
+
+/// Largest file carved, longer ones are taken to have lost their end
+const MAX_CARVE_SIZE: usize = 64 << 20;
+/// Clusters read ahead at first when looking for the end of a file
+const READ_AHEAD: usize = 16;
+
+/// Kind of file recognised by its signature
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum FatCarveKind {
+    Jpeg,
+    Png,
+    Pdf,
+    Zip,
+}
+
+/// File found in free clusters
+#[derive(Debug, Clone)]
+pub struct FatCarvedFile {
+    pub kind: FatCarveKind,
+    // Size in bytes, from its header up to its end
+    pub size: u64,
+    // Runs of free clusters holding the file, in order
+    pub extents: Vec<FatExtent>,
+}
+
+/// Progress of looking for the end of a file
+#[derive(PartialEq, Debug)]
+enum FatCarveEnd {
+    // End found, at offset from the start of the file
+    Found(usize),
+    // End found, though more data may continue the file past it
+    Possible(usize),
+    // More data is needed to tell
+    Incomplete,
+    // Data does not continue the file
+    Invalid,
+}
+
+impl FatCarveKind {
+    /// Kinds of files carved
+    const ALL: [FatCarveKind; 4] = [
+        FatCarveKind::Jpeg,
+        FatCarveKind::Png,
+        FatCarveKind::Pdf,
+        FatCarveKind::Zip,
+    ];
+
+    /// Usual extension of names of the kind
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FatCarveKind::Jpeg => "jpg",
+            FatCarveKind::Png => "png",
+            FatCarveKind::Pdf => "pdf",
+            FatCarveKind::Zip => "zip",
+        }
+    }
+
+    /// Bytes files of the kind start with
+    fn header(&self) -> &'static [u8] {
+        match self {
+            FatCarveKind::Jpeg => b"\xFF\xD8\xFF",
+            FatCarveKind::Png => b"\x89PNG\r\n\x1A\n",
+            FatCarveKind::Pdf => b"%PDF-",
+            FatCarveKind::Zip => b"PK\x03\x04",
+        }
+    }
+
+    /// Looks for the end of a file of the kind at the start of data
+    fn end(&self, data: &[u8]) -> FatCarveEnd {
+        match self {
+            FatCarveKind::Jpeg => jpeg_end(data),
+            FatCarveKind::Png => png_end(data),
+            FatCarveKind::Pdf => pdf_end(data),
+            FatCarveKind::Zip => zip_end(data),
+        }
+    }
+}
+
+/// Big endian number of bytes at offset
+fn be(data: &[u8], offset: usize, length: usize) -> usize {
+    data[offset..offset + length]
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as usize)
+}
+
+/// Offset of first occurrence of pattern in data from offset
+fn find(data: &[u8], pattern: &[u8], offset: usize) -> Option<usize> {
+    data.get(offset..)?
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+        .map(|position| offset + position)
+}
+
+/// Follows the segments of JPEG up to the end of image marker
+/// Segments are skipped by their length, so embedded thumbnails are passed
+fn jpeg_end(data: &[u8]) -> FatCarveEnd {
+    let mut offset = 2;
+    loop {
+        if offset + 2 > data.len() {
+            return FatCarveEnd::Incomplete;
+        }
+        if data[offset] != 0xFF {
+            return FatCarveEnd::Invalid;
+        }
+        let marker = data[offset + 1];
+        match marker {
+            // Padding before a marker
+            0xFF => offset += 1,
+            // End of image
+            0xD9 => return FatCarveEnd::Found(offset + 2),
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => offset += 2,
+            _ => {
+                if offset + 4 > data.len() {
+                    return FatCarveEnd::Incomplete;
+                }
+                let length = be(data, offset + 2, 2);
+                if length < 2 {
+                    return FatCarveEnd::Invalid;
+                }
+                offset += 2 + length;
+                // Entropy coded data follows the start of scan, up to the
+                // next marker which is neither a stuffed byte nor a restart
+                if marker == 0xDA {
+                    loop {
+                        if offset + 2 > data.len() {
+                            return FatCarveEnd::Incomplete;
+                        }
+                        let next = data[offset + 1];
+                        if data[offset] == 0xFF
+                            && next != 0x00
+                            && !(0xD0..=0xD7).contains(&next)
+                        {
+                            break;
+                        }
+                        offset += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Follows the chunks of PNG up to the end of its IEND chunk
+fn png_end(data: &[u8]) -> FatCarveEnd {
+    let mut offset = 8;
+    loop {
+        if offset + 8 > data.len() {
+            return FatCarveEnd::Incomplete;
+        }
+        let length = be(data, offset, 4);
+        let kind = &data[offset + 4..offset + 8];
+        if length > MAX_CARVE_SIZE || !kind.iter().all(u8::is_ascii_alphabetic)
+        {
+            return FatCarveEnd::Invalid;
+        }
+        // Length, type, data and CRC
+        let end = offset + 12 + length;
+        if kind == b"IEND" {
+            return if end <= data.len() {
+                FatCarveEnd::Found(end)
+            } else {
+                FatCarveEnd::Incomplete
+            };
+        }
+        offset = end;
+    }
+}
+
+/// Whether data starts with an object or cross-reference table, as the
+/// incremental updates appended to PDF do, None if too little data to tell
+fn pdf_update_follows(data: &[u8]) -> Option<bool> {
+    let start = data.iter().position(|byte| !byte.is_ascii_whitespace())?;
+    let data = &data[start..];
+    if data.len() < 32 {
+        return None;
+    }
+    if data.starts_with(b"xref") {
+        return Some(true);
+    }
+    // Object header of object number, generation number and "obj"
+    let words: Vec<&[u8]> = data
+        .split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+        .take(3)
+        .collect();
+    let number = |word: &[u8]| word.iter().all(u8::is_ascii_digit);
+    Some(
+        words.len() == 3
+            && number(words[0])
+            && number(words[1])
+            && words[2].starts_with(b"obj"),
+    )
+}
+
+/// Ends PDF after its last end of file marker and the line break after it
+/// Incremental updates append to PDF, each ending with its own marker
+fn pdf_end(data: &[u8]) -> FatCarveEnd {
+    let mut offset = match find(data, b"%%EOF", 0) {
+        None => return FatCarveEnd::Incomplete,
+        Some(offset) => offset,
+    };
+    loop {
+        let mut end = offset + 5;
+        let rest = &data[end..];
+        // Line break is unknown until the byte after the marker is read
+        if rest.is_empty() || rest == b"\r" {
+            return FatCarveEnd::Possible(end);
+        }
+        if rest.starts_with(b"\r\n") {
+            end += 2;
+        } else if rest.starts_with(b"\n") || rest.starts_with(b"\r") {
+            end += 1;
+        }
+        match pdf_update_follows(&data[end..]) {
+            None => return FatCarveEnd::Possible(end),
+            Some(false) => return FatCarveEnd::Found(end),
+            Some(true) => {}
+        }
+        offset = match find(data, b"%%EOF", end) {
+            None => return FatCarveEnd::Possible(end),
+            Some(offset) => offset,
+        };
+    }
+}
+
+/// Ends ZIP after its end of central directory record and comment
+fn zip_end(data: &[u8]) -> FatCarveEnd {
+    let offset = match find(data, b"PK\x05\x06", 0) {
+        None => return FatCarveEnd::Incomplete,
+        Some(offset) => offset,
+    };
+    if offset + 22 > data.len() {
+        return FatCarveEnd::Incomplete;
+    }
+    // Comment length is little endian, unlike numbers of JPEG and PNG
+    let comment =
+        data[offset + 20] as usize | (data[offset + 21] as usize) << 8;
+    let end = offset + 22 + comment;
+    if end <= data.len() {
+        FatCarveEnd::Found(end)
+    } else {
+        FatCarveEnd::Incomplete
+    }
+}
+
+/// Size of file of kind starting at the first of clusters, None if its end
+/// is not found within them
+fn carve_size(
+    fat: &mut Fat,
+    clusters: &[u32],
+    kind: FatCarveKind,
+) -> Option<usize> {
+    let cluster_size = cluster_size(fat) as usize;
+    let limit = clusters.len().min(MAX_CARVE_SIZE / cluster_size);
+    let mut data = vec![];
+    let mut read = 0;
+    let mut possible = None;
+    while read < limit {
+        // Read ahead twice as far each time the end is not found
+        let until = limit.min((read * 2).max(READ_AHEAD));
+        for cluster in &clusters[read..until] {
+            let sector_number = first_sector_of_cluster(fat, *cluster);
+            data.extend(read_cluster(fat, sector_number));
+        }
+        read = until;
+        match kind.end(&data) {
+            FatCarveEnd::Found(size) => return Some(size),
+            FatCarveEnd::Possible(size) => possible = Some(size),
+            FatCarveEnd::Invalid => return None,
+            FatCarveEnd::Incomplete => {}
+        }
+    }
+    possible
+}
+
+/// Finds files in free clusters by their signatures
+/// Files are taken to start at a cluster and to fill the free clusters after
+/// it in order, as clusters are allocated in that order
+pub fn carve(fat: &mut Fat) -> Result<Vec<FatCarvedFile>, FatError> {
+    let cluster_size = cluster_size(fat) as usize;
+    let free: Vec<u32> = free_extents(fat)
+        .iter()
+        .flat_map(|extent| extent.start..extent.start + extent.length)
+        .collect();
+
+    let mut carved = vec![];
+    let mut index = 0;
+    while index < free.len() {
+        let sector_number = first_sector_of_cluster(fat, free[index]);
+        let head = read_cluster(fat, sector_number);
+        let kind = FatCarveKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| head.starts_with(kind.header()));
+        let size = match kind {
+            None => None,
+            Some(kind) => carve_size(fat, &free[index..], kind),
+        };
+        match (kind, size) {
+            (Some(kind), Some(size)) => {
+                let count = size.div_ceil(cluster_size);
+                carved.push(FatCarvedFile {
+                    kind,
+                    size: size as u64,
+                    extents: extents(&free[index..index + count]),
+                });
+                index += count;
+            }
+            _ => index += 1,
+        }
+    }
+    Ok(carved)
+}
+
+/// Reads contents of carved file
+pub fn read_carved(
+    fat: &mut Fat,
+    carved: &FatCarvedFile,
+) -> Result<Vec<u8>, FatError> {
+    let mut data = vec![];
+    for extent in carved.extents.iter() {
+        for cluster in extent.start..extent.start + extent.length {
+            let sector_number = first_sector_of_cluster(fat, cluster);
+            data.extend(read_cluster(fat, sector_number));
+        }
+    }
+    data.truncate(carved.size as usize);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{Scratch, STAMP};
+    use super::super::FatType;
+    use super::{FatCarveEnd, FatCarveKind};
+
+    const JPEG: &[u8] = include_bytes!("../tests/fixtures/image.jpg");
+    const PNG: &[u8] = include_bytes!("../tests/fixtures/image.png");
+    const PDF: &[u8] = include_bytes!("../tests/fixtures/updated.pdf");
+    const ZIP: &[u8] = include_bytes!("../tests/fixtures/archive.zip");
+
+    const FIXTURES: [(FatCarveKind, &[u8]); 4] = [
+        (FatCarveKind::Jpeg, JPEG),
+        (FatCarveKind::Png, PNG),
+        (FatCarveKind::Pdf, PDF),
+        (FatCarveKind::Zip, ZIP),
+    ];
+
+    /// Fixture followed by a cluster of zeros, as free clusters often are
+    fn padded(fixture: &[u8]) -> Vec<u8> {
+        let mut data = fixture.to_vec();
+        data.extend([0; 512]);
+        data
+    }
+
+    /// Offset just past the first end of file marker of PDF fixture
+    fn first_pdf_end() -> usize {
+        let offset = super::find(PDF, b"%%EOF", 0).unwrap();
+        offset + 6
+    }
+
+    #[test]
+    fn ends_where_fixtures_end() {
+        for (kind, fixture) in FIXTURES {
+            assert!(fixture.starts_with(kind.header()), "{:?}", kind);
+            let end = kind.end(&padded(fixture));
+            assert_eq!(end, FatCarveEnd::Found(fixture.len()), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn ends_at_end_of_data() {
+        for (kind, fixture) in FIXTURES {
+            let expected = match kind {
+                // Any more data might hold another incremental update
+                FatCarveKind::Pdf => FatCarveEnd::Possible(fixture.len()),
+                _ => FatCarveEnd::Found(fixture.len()),
+            };
+            assert_eq!(kind.end(fixture), expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn needs_whole_fixture() {
+        for (kind, fixture) in FIXTURES {
+            for length in 0..fixture.len() {
+                let end = kind.end(&fixture[..length]);
+                match kind {
+                    FatCarveKind::Pdf => {
+                        assert_ne!(end, FatCarveEnd::Invalid, "{}", length);
+                        assert!(
+                            !matches!(end, FatCarveEnd::Found(_)),
+                            "{}",
+                            length
+                        );
+                    }
+                    _ => assert_eq!(
+                        end,
+                        FatCarveEnd::Incomplete,
+                        "{:?} {}",
+                        kind,
+                        length
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jpeg_skips_thumbnail() {
+        let thumbnail = super::find(JPEG, b"\xFF\xD9", 0).unwrap();
+        assert!(thumbnail + 2 < JPEG.len());
+        let end = FatCarveKind::Jpeg.end(JPEG);
+        assert_eq!(end, FatCarveEnd::Found(JPEG.len()));
+    }
+
+    #[test]
+    fn jpeg_without_marker() {
+        let mut data = JPEG.to_vec();
+        data[2] = 0;
+        assert_eq!(FatCarveKind::Jpeg.end(&data), FatCarveEnd::Invalid);
+    }
+
+    #[test]
+    fn pdf_marker_at_end_of_data() {
+        let marker = first_pdf_end() - 1;
+        let end = FatCarveKind::Pdf.end(&PDF[..marker]);
+        assert_eq!(end, FatCarveEnd::Possible(marker));
+    }
+
+    #[test]
+    fn pdf_update_without_marker() {
+        let end = FatCarveKind::Pdf.end(&PDF[..PDF.len() - 8]);
+        assert_eq!(end, FatCarveEnd::Possible(first_pdf_end()));
+    }
+
+    #[test]
+    fn pdf_followed_by_other_data() {
+        let first = first_pdf_end();
+        let mut data = PDF[..first].to_vec();
+        data.extend([b'x'; 64]);
+        data.extend(b"%%EOF\n");
+        let end = FatCarveKind::Pdf.end(&data);
+        assert_eq!(end, FatCarveEnd::Found(first));
+    }
+
+    #[test]
+    fn carves_removed_files() {
+        let mut fat = Scratch::new("carve", 1 << 20, FatType::Fat12);
+        for (kind, fixture) in FIXTURES {
+            let path = format!("/file.{}", kind.extension());
+            fat.write_file(&path, fixture, &STAMP, &STAMP).unwrap();
+        }
+        for (kind, _) in FIXTURES {
+            fat.remove(&format!("/file.{}", kind.extension())).unwrap();
+        }
+        fat.remount();
+
+        let carved = fat.carve().unwrap();
+        assert_eq!(carved.len(), FIXTURES.len());
+        for ((kind, fixture), file) in FIXTURES.iter().zip(&carved) {
+            assert_eq!(file.kind, *kind);
+            assert_eq!(fat.read_carved(file).unwrap(), *fixture);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use super::{
+    cluster_chain, extents, fat_entry, first_sector_of_cluster, flush_fat,
+    free_clusters, free_extents, get_dir, invalidate_dir, is_dir,
+    link_clusters, locate, read_cluster, set_cluster_number, set_parent,
+    write_cluster, write_dir_slot, Fat, FatError, FatType,
+};
+
+/// Writes out the FAT and waits for everything written to reach the disk
+fn barrier(fat: &mut Fat) -> Result<(), FatError> {
+    flush_fat(fat)?;
+    fat.image.sync_data()?;
+    Ok(())
+}
+
+/// Moves contents of entry at path into free clusters, in the order given
+/// The new chain is written and linked before the entry is pointed at it, and
+/// the old chain is freed last, so an interruption at most leaks clusters
+/// Returns the clusters the entry was moved out of
+fn move_entry(
+    fat: &mut Fat,
+    path: &str,
+    clusters: &[u32],
+) -> Result<Vec<u32>, FatError> {
+    let (_, location) = locate(fat, path)?;
+    let entry = &fat.dir_cache[&location.parent][location.index];
+    let old_cluster = entry.cluster_number();
+    let moves_dir = is_dir(entry);
+    let offset = entry.offset;
+    let mut slot = entry.short_entry.to_bytes();
+    let chain = cluster_chain(fat, old_cluster)?;
+    let new_cluster = clusters[0];
+
+    for (index, (from, to)) in chain.iter().zip(clusters).enumerate() {
+        let sector_number = first_sector_of_cluster(fat, *from);
+        let mut data = read_cluster(fat, sector_number);
+        // "." entry of a directory refers to its own first cluster
+        if moves_dir && index == 0 && data[0..11] == *b".          " {
+            set_cluster_number(&mut data[0..32], new_cluster);
+        }
+        write_cluster(fat, *to, &data)?;
+    }
+    link_clusters(fat, clusters);
+    barrier(fat)?;
+
+    set_cluster_number(&mut slot, new_cluster);
+    write_dir_slot(fat, location.parent, offset, &slot)?;
+    if moves_dir {
+        invalidate_dir(fat, old_cluster);
+        // Subdirectories refer to their parent from ".."
+        let subdirs: Vec<u32> = get_dir(fat, new_cluster)?
+            .ok_or(FatError::NotFound)?
+            .iter()
+            .filter(|child| {
+                is_dir(child)
+                    && child.cluster_number() != 0
+                    && child.get_name() != "."
+                    && child.get_name() != ".."
+            })
+            .map(|child| child.cluster_number())
+            .collect();
+        for subdir in subdirs {
+            set_parent(fat, subdir, new_cluster)?;
+        }
+    }
+    barrier(fat)?;
+
+    free_clusters(fat, &chain);
+    barrier(fat)?;
+    Ok(chain)
+}
+
+/// Free clusters outside of the run from start of length, preferring the
+/// first run of free clusters long enough to hold count clusters
+fn free_outside(
+    fat: &Fat,
+    count: u32,
+    start: u32,
+    length: u32,
+) -> Result<Vec<u32>, FatError> {
+    let free: Vec<u32> = free_extents(fat)
+        .iter()
+        .flat_map(|extent| extent.start..extent.start + extent.length)
+        .filter(|cluster| *cluster < start || *cluster >= start + length)
+        .collect();
+    if (free.len() as u32) < count {
+        return Err(FatError::NoSpace);
+    }
+    let count = count as usize;
+    for extent in extents(&free) {
+        if extent.length as usize >= count {
+            let first = free.iter().position(|c| *c == extent.start).unwrap();
+            return Ok(free[first..first + count].to_vec());
+        }
+    }
+    Ok(free[..count].to_vec())
+}
+
+/// Moves entry at path into a single run of free clusters if it is fragmented
+/// Returns whether the entry was moved
+pub fn defragment(fat: &mut Fat, path: &str) -> Result<bool, FatError> {
+    let chain = entry_chain(fat, path)?;
+    if extents(&chain).len() <= 1 {
+        return Ok(false);
+    }
+
+    let count = chain.len() as u32;
+    let run = free_extents(fat)
+        .into_iter()
+        .find(|extent| extent.length >= count)
+        .ok_or(FatError::NoSpace)?;
+    let clusters: Vec<u32> = (run.start..run.start + count).collect();
+    move_entry(fat, path, &clusters)?;
+    Ok(true)
+}
+
+/// Clusters of the entry at path
+fn entry_chain(fat: &mut Fat, path: &str) -> Result<Vec<u32>, FatError> {
+    let (_, location) = locate(fat, path)?;
+    let cluster_number =
+        fat.dir_cache[&location.parent][location.index].cluster_number();
+    cluster_chain(fat, cluster_number)
+}
+
+/// Paths of entries owning clusters in use, None for the FAT32 root directory
+fn cluster_owners(
+    fat: &mut Fat,
+) -> Result<HashMap<u32, Option<String>>, FatError> {
+    let mut owners = HashMap::new();
+    if fat.fat_type == FatType::Fat32 {
+        let root = fat.get_root_cluster_number();
+        for cluster in cluster_chain(fat, root)? {
+            owners.insert(cluster, None);
+        }
+    }
+    for (path, entry) in fat.walk("/")? {
+        for cluster in cluster_chain(fat, entry.cluster_number())? {
+            owners.insert(cluster, Some(path.clone()));
+        }
+    }
+    Ok(owners)
+}
+
+/// Moves entry at path into clusters and records them as its own in owners
+fn relocate(
+    fat: &mut Fat,
+    owners: &mut HashMap<u32, Option<String>>,
+    path: &str,
+    clusters: &[u32],
+) -> Result<(), FatError> {
+    for cluster in move_entry(fat, path, clusters)? {
+        owners.remove(&cluster);
+    }
+    for cluster in clusters {
+        owners.insert(*cluster, Some(path.to_string()));
+    }
+    Ok(())
+}
+
+/// Moves entries at paths, in order, into single runs of clusters from the
+/// start of the data region, moving other entries out of the way
+/// Clusters which belong to no entry, such as bad clusters, are left alone,
+/// as is the FAT32 root directory, which is only referred to by the boot sector
+pub fn place_at_start(fat: &mut Fat, paths: &[&str]) -> Result<(), FatError> {
+    let mut owners = cluster_owners(fat)?;
+    let mut next = 2;
+    for path in paths {
+        let chain = entry_chain(fat, path)?;
+        let count = chain.len() as u32;
+        if count == 0 {
+            continue;
+        }
+
+        // Lowest run whose clusters are free or can be moved
+        let movable = |cluster: u32| match owners.get(&cluster) {
+            None => fat_entry(fat, cluster) == 0,
+            Some(owner) => owner.is_some(),
+        };
+        let end = fat.cluster_count + 2;
+        let mut start = next;
+        let mut cluster = start;
+        while cluster < start + count {
+            if start + count > end {
+                return Err(FatError::NoSpace);
+            }
+            if !movable(cluster) {
+                start = cluster + 1;
+            }
+            cluster += 1;
+        }
+        next = start + count;
+        if chain.iter().copied().eq(start..start + count) {
+            continue;
+        }
+
+        // Entries in the way go elsewhere, as do clusters of the entry itself
+        let mut evicted: Vec<String> = vec![];
+        for cluster in start..start + count {
+            if let Some(Some(owner)) = owners.get(&cluster) {
+                if !chain.contains(&cluster) && !evicted.contains(owner) {
+                    evicted.push(owner.clone());
+                }
+            }
+        }
+        for owner in evicted {
+            let owner_count = entry_chain(fat, &owner)?.len() as u32;
+            let clusters = free_outside(fat, owner_count, start, count)?;
+            relocate(fat, &mut owners, &owner, &clusters)?;
+        }
+        if chain.iter().any(|c| (start..start + count).contains(c)) {
+            let clusters = free_outside(fat, count, start, count)?;
+            relocate(fat, &mut owners, path, &clusters)?;
+        }
+
+        let clusters: Vec<u32> = (start..start + count).collect();
+        relocate(fat, &mut owners, path, &clusters)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{pattern, volumes, Scratch, STAMP};
+
+    /// Builds fragmented "/a" and fragmented directory "/d", holding
+    /// directory "/d/sub" with a file, returns files and their contents
+    fn fragment(fat: &mut Scratch) -> Vec<(String, Vec<u8>)> {
+        let cluster_size = fat.cluster_size() as usize;
+        let mut files = vec![];
+        let mut write = |fat: &mut Scratch, path: &str, size: usize| {
+            let data = pattern(size, files.len() as u8);
+            fat.write_file(path, &data, &STAMP, &STAMP).unwrap();
+            files.retain(|(other, _)| other != path);
+            files.push((path.to_string(), data));
+        };
+        write(fat, "/a", 2 * cluster_size);
+        write(fat, "/b", 2 * cluster_size);
+        fat.create_dir("/d", &STAMP).unwrap();
+        fat.create_dir("/d/sub", &STAMP).unwrap();
+        write(fat, "/d/sub/file", cluster_size + 1);
+        // Directory grows past clusters taken meanwhile
+        for i in 0..24 {
+            write(fat, &format!("/d/entry {}", i), 0);
+            if i % 8 == 0 {
+                write(fat, &format!("/x{}", i), cluster_size);
+            }
+        }
+        fat.remove("/b").unwrap();
+        write(fat, "/a", 5 * cluster_size);
+        files.retain(|(path, _)| path != "/b");
+        files
+    }
+
+    /// Checks contents of files after reading the volume afresh
+    fn verify(fat: &mut Scratch, files: &[(String, Vec<u8>)]) {
+        fat.remount();
+        for (path, data) in files {
+            assert_eq!(&fat.contents(path), data, "{}", path);
+        }
+        fat.check();
+    }
+
+    #[test]
+    fn defragments_files_and_directories() {
+        for mut fat in volumes("defragment") {
+            let files = fragment(&mut fat);
+            for path in ["/a", "/d"] {
+                assert!(fat.file_extents(path).unwrap().len() > 1, "{}", path);
+                assert!(fat.defragment(path).unwrap());
+                assert_eq!(fat.file_extents(path).unwrap().len(), 1);
+                assert!(!fat.defragment(path).unwrap());
+            }
+            // ".." of "/d/sub" follows "/d" to its new clusters
+            verify(&mut fat, &files);
+        }
+    }
+
+    #[test]
+    fn places_entries_at_start() {
+        for mut fat in volumes("place") {
+            let files = fragment(&mut fat);
+            // The FAT32 root directory stays in front
+            let start = match fat.file_extents("/").unwrap().first() {
+                None => 2,
+                Some(extent) => extent.start + extent.length,
+            };
+            fat.place_at_start(&["/d", "/d/sub", "/a"]).unwrap();
+
+            let mut next = start;
+            for path in ["/d", "/d/sub", "/a"] {
+                let extents = fat.file_extents(path).unwrap();
+                assert_eq!(extents.len(), 1, "{}", path);
+                assert_eq!(extents[0].start, next, "{}", path);
+                next += extents[0].length;
+            }
+            verify(&mut fat, &files);
+        }
+    }
+}
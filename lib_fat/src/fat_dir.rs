@@ -1,55 +1,66 @@
 use byteorder::{LittleEndian, ReadBytesExt};
+use std::cell::Cell;
 use std::char::{decode_utf16, REPLACEMENT_CHARACTER};
 use std::convert::TryInto;
 use std::io::{Cursor, Read};
 
 use super::{
-    file_cluster_count, read_file_full, read_sector, root_dir_sectors, Fat,
-    FatDirectoryEntry, FatDirectoryEntryContainer, FatFileType,
-    FatLongDirectoryEntry,
+    cluster_size, file_cluster_count, read_file_full, read_sector,
+    root_dir_sectors, Fat, FatDateTime, FatDirectoryEntry,
+    FatDirectoryEntryContainer, FatError, FatFileType, FatLongDirectoryEntry,
     FatType,
 };
 
+/// Flag in order of long entry which holds the end of the name
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+/// Long names are at most 255 characters, 13 per entry
+const MAX_LONG_ENTRIES: usize = 20;
+
 /// Reads/loads root directory
-pub fn read_root_dir(fat: &mut Fat) {
-    match fat.fat_type {
-        FatType::Fat12 | FatType::Fat16 => {
-            // Fixed location on disk following last FAT
-            let first_root_sector_num: u16 = fat.bpb.reserved_clusters
-                + (fat.bpb.num_fats as u16 * fat.bpb.fat_size_16);
-            // Find number of sectors occupied by root directory
-            let root_sector_count = root_dir_sectors(fat);
-            // Read root dir sectors to u8 vector
-            let mut root_dir: Vec<u8> = Vec::new();
-            for i in 0..root_sector_count {
-                root_dir.extend(read_sector(
-                    fat,
-                    (i + first_root_sector_num).into(),
-                ));
-            }
-            // Read root dir and assign root cluster number of 0
-            read_dir_chain(fat, 0, &root_dir, 0);
-        }
-        FatType::Fat32 => {
-            // For FAT32, treat root directory as file
-            let root_cluster = fat.ebpb32.as_ref().unwrap().root_cluster;
-            let root_dir = read_file_full(fat, root_cluster);
-            read_dir_chain(fat, root_cluster, &root_dir, 0);
-        }
-    }
+pub fn read_root_dir(fat: &mut Fat) -> Result<(), FatError> {
+    // Root cluster number is 0 for FAT12/16
+    let root_cluster = fat.get_root_cluster_number();
+    let root_dir = read_dir_data(fat, root_cluster)?;
+    read_dir_chain(fat, root_cluster, &root_dir, 0);
+    Ok(())
 }
 
 /// Retrieves the directory with specified inode
 pub fn get_dir(
     fat: &mut Fat,
     inode: u32,
-) -> Option<&Vec<FatDirectoryEntryContainer>> {
+) -> Result<Option<&Vec<FatDirectoryEntryContainer>>, FatError> {
     let cached = fat.dir_cache.contains_key(&inode);
     if !cached {
-        let dir_file = read_file_full(fat, inode);
+        let dir_file = read_dir_data(fat, inode)?;
         read_dir_chain(fat, inode, &dir_file, 0);
     }
-    fat.dir_cache.get(&inode)
+    Ok(fat.dir_cache.get(&inode))
+}
+
+/// Reads raw entries of directory with specified inode
+pub fn read_dir_data(fat: &mut Fat, inode: u32) -> Result<Vec<u8>, FatError> {
+    if inode == 0 && fat.fat_type != FatType::Fat32 {
+        // Fixed location on disk following last FAT
+        let first_root_sector_num = first_root_dir_sector(fat);
+        // Find number of sectors occupied by root directory
+        let root_sector_count = root_dir_sectors(fat) as u32;
+        // Read root dir sectors to u8 vector
+        let mut root_dir: Vec<u8> = Vec::new();
+        for i in 0..root_sector_count {
+            root_dir.extend(read_sector(fat, i + first_root_sector_num));
+        }
+        Ok(root_dir)
+    } else {
+        // For FAT32, treat root directory as file
+        read_file_full(fat, inode)
+    }
+}
+
+/// First sector of the fixed root directory of FAT12/16
+pub fn first_root_dir_sector(fat: &Fat) -> u32 {
+    fat.bpb.reserved_clusters as u32
+        + (fat.bpb.num_fats as u32 * fat.bpb.fat_size_16 as u32)
 }
 
 /// Reads a chain of directory entries
@@ -62,15 +73,17 @@ pub fn read_dir_chain(fat: &mut Fat, inode: u32, sector: &[u8], start: u16) {
     let mut current_buffer: &[u8];
     let mut current_long_entries: Vec<FatLongDirectoryEntry> = vec![];
 
-    loop {
+    // Stop at end of buffer if there is no terminating entry
+    while current + 32 <= sector.len() {
         // Read entry at offset
         current_buffer = &sector[current..current + 32];
         // Last entry
         if current_buffer[0] == 0x0 {
             break;
         }
-        // Free entry
+        // Free entry, orphans any pending long entries
         if current_buffer[0] == 0xE5 {
+            current_long_entries.clear();
             current += 32;
             continue;
         }
@@ -83,9 +96,12 @@ pub fn read_dir_chain(fat: &mut Fat, inode: u32, sector: &[u8], start: u16) {
             | FatFileType::AttrArchive as u8;
         let attr = current_buffer[11];
         if attr & attr_long_name_mask == FatFileType::AttrLongname as u8 {
-            // Long entry
-            current_long_entries
-                .push(FatLongDirectoryEntry::new(current_buffer));
+            // Long entry, the last entry of a set starts a new set
+            let long_entry = FatLongDirectoryEntry::new(current_buffer);
+            if long_entry.order & LAST_LONG_ENTRY != 0 {
+                current_long_entries.clear();
+            }
+            current_long_entries.push(long_entry);
         } else {
             let test_val = FatFileType::AttrDirectory as u8
                 | FatFileType::AttrVolumeId as u8;
@@ -98,29 +114,21 @@ pub fn read_dir_chain(fat: &mut Fat, inode: u32, sector: &[u8], start: u16) {
                 let short_entry = FatDirectoryEntry::new(current_buffer);
                 let checksum = chksum(&short_entry.name);
 
-                // Move long entries
-                let mut long_entries: Vec<FatLongDirectoryEntry> = vec![];
-                while !current_long_entries.is_empty() {
-                    let current_long_entry = current_long_entries.remove(0);
-                    // Ensure that N is correct
-                    let n: u8 = current_long_entries.len() as u8 + 1;
-                    if current_long_entry.attr & n == 0 {
-                        long_entries = vec![];
-                        break;
-                    }
-                    // Ensure that checksum is correct
-                    if current_long_entry.checksum != checksum {
-                        long_entries = vec![];
-                        break;
-                    }
-
-                    // Correct, so push
-                    long_entries.push(current_long_entry);
+                // Move long entries, dropping broken sets
+                let mut long_entries: Vec<FatLongDirectoryEntry> =
+                    std::mem::take(&mut current_long_entries);
+                if !valid_long_entries(&long_entries, checksum) {
+                    long_entries.clear();
                 }
 
-                // Determine cluster count
+                // Derive cluster count of files from size, directories have
+                // no size so their chain is only walked on demand
                 let cluster_count =
-                    file_cluster_count(fat, short_entry.cluster_number());
+                    if attr & FatFileType::AttrDirectory as u8 == 0 {
+                        Some(size_cluster_count(fat, short_entry.size))
+                    } else {
+                        None
+                    };
                 // Parse name
                 let name = FatDirectoryEntryContainer::parse_name(
                     &short_entry,
@@ -131,24 +139,190 @@ pub fn read_dir_chain(fat: &mut Fat, inode: u32, sector: &[u8], start: u16) {
                     short_entry,
                     long_entries,
                     cached_name: name,
-                    cached_cluster_count: cluster_count,
+                    cached_cluster_count: Cell::new(cluster_count),
+                    offset: current,
                 });
+            } else {
+                // Invalid entry, orphans any pending long entries
+                current_long_entries.clear();
             }
         }
         current += 32;
-        continue;
     }
 
-    // Cache parents
+    // Cache parents, dot entries refer to the directory and its parent
     for entry in directory_entries.iter() {
-        fat.inode_cache.insert(entry.cluster_number(), inode);
+        if entry.get_name() != "." && entry.get_name() != ".." {
+            fat.inode_cache.insert(entry.cluster_number(), inode);
+        }
     }
     // Cache entries
     fat.dir_cache.insert(inode, directory_entries);
 }
 
+/// Whether long entries form a complete set for the short entry
+/// Entries are in on-disk order, so the set must count down from N to 1
+fn valid_long_entries(
+    long_entries: &[FatLongDirectoryEntry],
+    checksum: u8,
+) -> bool {
+    let count = long_entries.len();
+    if count == 0 || count > MAX_LONG_ENTRIES {
+        return false;
+    }
+    for (index, entry) in long_entries.iter().enumerate() {
+        // Only the first entry on disk carries the last entry flag
+        let mut order = (count - index) as u8;
+        if index == 0 {
+            order |= LAST_LONG_ENTRY;
+        }
+        if entry.order != order || entry.checksum != checksum {
+            return false;
+        }
+    }
+    true
+}
+
+/// Characters the short name of a deleted entry may have started with
+pub const FIRST_CHARACTERS: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&'()-@^_`{}~";
+
+/// Reads entries of directory with specified inode which are marked as free
+/// The first character of short names is restored from the checksum of long
+/// entries which survived, returns entries along with whether it is known
+pub fn read_deleted_entries(
+    fat: &mut Fat,
+    inode: u32,
+) -> Result<Vec<(FatDirectoryEntryContainer, bool)>, FatError> {
+    let data = read_dir_data(fat, inode)?;
+    let mut deleted = vec![];
+    // Free long entries directly preceding the current slot
+    let mut pending: Vec<&[u8]> = vec![];
+
+    for (index, slot) in data.chunks_exact(32).enumerate() {
+        if slot[0] == 0x0 {
+            break;
+        }
+        if slot[0] != 0xE5 {
+            pending.clear();
+            continue;
+        }
+        if slot[11] & 0x3F == FatFileType::AttrLongname as u8 {
+            pending.push(slot);
+            continue;
+        }
+        if slot[11] & FatFileType::AttrVolumeId as u8 != 0 {
+            pending.clear();
+            continue;
+        }
+
+        // Long entries of the set share the checksum of the one closest
+        let checksum = pending.last().map(|long_slot| long_slot[13]);
+        let count = pending
+            .iter()
+            .rev()
+            .take(MAX_LONG_ENTRIES)
+            .take_while(|long_slot| Some(long_slot[13]) == checksum)
+            .count();
+        let group = &pending[pending.len() - count..];
+        // Number long entries again, counting down to 1
+        let mut long_entries: Vec<FatLongDirectoryEntry> = group
+            .iter()
+            .enumerate()
+            .map(|(position, long_slot)| {
+                let mut long_slot = long_slot.to_vec();
+                long_slot[0] = (count - position) as u8;
+                if position == 0 {
+                    long_slot[0] |= LAST_LONG_ENTRY;
+                }
+                FatLongDirectoryEntry::new(&long_slot)
+            })
+            .collect();
+
+        let mut short_slot = slot.to_vec();
+        let name = |first: u8| {
+            let mut name = [0u8; 11];
+            name.copy_from_slice(&short_slot[0..11]);
+            name[0] = first;
+            name
+        };
+        let candidates: Vec<u8> = FIRST_CHARACTERS
+            .iter()
+            .copied()
+            .filter(|first| Some(chksum(&name(*first))) == checksum)
+            .collect();
+        // Short names usually start like the long name
+        let preferred = if long_entries.is_empty() {
+            None
+        } else {
+            let placeholder = FatDirectoryEntry::new(&short_slot);
+            FatDirectoryEntryContainer::parse_name(&placeholder, &long_entries)
+                .chars()
+                .next()
+                .filter(|c| c.is_ascii())
+                .map(|c| c.to_ascii_uppercase() as u8)
+        };
+        let first = match preferred {
+            Some(c) if candidates.contains(&c) => Some(c),
+            _ => candidates.first().copied(),
+        };
+        match first {
+            Some(first) => short_slot[0] = first,
+            None => {
+                long_entries.clear();
+                short_slot[0] = b'?';
+            }
+        }
+
+        let short_entry = FatDirectoryEntry::new(&short_slot);
+        let name =
+            FatDirectoryEntryContainer::parse_name(&short_entry, &long_entries);
+        deleted.push((
+            FatDirectoryEntryContainer {
+                short_entry,
+                long_entries,
+                cached_name: name,
+                cached_cluster_count: Cell::new(None),
+                offset: index * 32,
+            },
+            first.is_some(),
+        ));
+        pending.clear();
+    }
+    Ok(deleted)
+}
+
+/// Number of clusters needed to hold size bytes
+fn size_cluster_count(fat: &Fat, size: u32) -> u32 {
+    let cluster_size = cluster_size(fat);
+    (size as u64).div_ceil(cluster_size as u64) as u32
+}
+
+/// Walks the chain of a directory if its cluster count is not yet known
+pub fn fill_cluster_count(fat: &Fat, entry: &FatDirectoryEntryContainer) {
+    if entry.cached_cluster_count.get().is_none() {
+        // Broken chains are reported once the directory itself is read
+        let count =
+            file_cluster_count(fat, entry.cluster_number()).unwrap_or(0);
+        entry.cached_cluster_count.set(Some(count));
+    }
+}
+
+/// Whether the cluster chain of a file is as long as its size requires
+pub fn verify_cluster_count(
+    fat: &Fat,
+    entry: &FatDirectoryEntryContainer,
+) -> Result<bool, FatError> {
+    let chain_count = file_cluster_count(fat, entry.cluster_number())?;
+    if entry.attribute() & FatFileType::AttrDirectory as u8 != 0 {
+        entry.cached_cluster_count.set(Some(chain_count));
+        return Ok(true);
+    }
+    Ok(chain_count == size_cluster_count(fat, entry.size()))
+}
+
 /// Calculates checksum of short name
-fn chksum(name: &[u8]) -> u8 {
+pub fn chksum(name: &[u8]) -> u8 {
     let mut sum: u8 = 0;
     for c in name.iter() {
         let p1: u8 = if sum & 1 != 0 { 0x80 } else { 0 };
@@ -166,6 +340,52 @@ impl FatDirectoryEntry {
     pub fn cluster_number(&self) -> u32 {
         (self.first_cluster_hi as u32) << 16 | self.first_cluster_low as u32
     }
+
+    /// Formats 8.3 name, such as "README.TXT"
+    pub fn short_name(&self) -> String {
+        let mut buf = vec![];
+        let name_bytes = self.name;
+        let name = &name_bytes[1..8];
+        let ext = &name_bytes[8..11];
+
+        // Special case for 0x05 -> 0xE5 for first character
+        match name_bytes[0] {
+            0x05 => buf.push(0xE5),
+            c => buf.push(c),
+        };
+
+        // Process the name
+        let mut end_index = 0;
+        // Find last character that is not 0x20
+        for (index, c) in name.iter().rev().enumerate() {
+            if *c != 0x20 {
+                end_index = name.len() - index;
+                break;
+            }
+        }
+        // Add that section
+        if end_index != 0 {
+            buf.extend(&name[0..end_index]);
+        }
+
+        // Process the ext
+        if ext[0] != 0x20 {
+            // Push a . and first character of extension
+            buf.push(0x2E);
+            buf.push(ext[0]);
+
+            // Push rest of extension as necessary
+            if ext[2] != 0x20 {
+                buf.push(ext[1]);
+                buf.push(ext[2]);
+            } else if ext[1] != 0x20 {
+                buf.push(ext[1]);
+            }
+        }
+
+        // Should technically also do a check for illegal characters...
+        String::from_utf8_lossy(&buf).into_owned()
+    }
 }
 
 impl FatDirectoryEntryContainer {
@@ -185,12 +405,13 @@ impl FatDirectoryEntryContainer {
     }
 
     /// Get cluster count of file
+    /// Directories report 0 until their chain has been walked
     pub fn cluster_count(&self, is_fat32: bool) -> u32 {
         // Root dir for FAT12/16
         if self.cluster_number() == 0 && !is_fat32 {
             return 1;
         }
-        self.cached_cluster_count
+        self.cached_cluster_count.get().unwrap_or(0)
     }
 
     /// Returns properly formatted name of directory/file
@@ -198,12 +419,17 @@ impl FatDirectoryEntryContainer {
         &self.cached_name
     }
 
-    /// Get creation time
-    pub fn get_creation_time(&self) -> (u16, u8, u8, u8, u8, u16) {
+    /// Returns 8.3 alias of directory/file
+    pub fn get_short_name(&self) -> String {
+        self.short_entry.short_name()
+    }
+
+    /// Get creation time, with centiseconds (0-199) past the second
+    pub fn get_creation_time(&self) -> (u16, u8, u8, u8, u8, u8, u8) {
         let (year, month, day) = parse_date(self.short_entry.created_date);
         let (hour, minute, second) = parse_time(self.short_entry.created_time);
-        let _ms = self.short_entry.created_time_tenth;
-        (year, month, day, hour, minute, second as u16)
+        let centiseconds = self.short_entry.created_time_tenth;
+        (year, month, day, hour, minute, second, centiseconds)
     }
 
     /// Get last accessed date
@@ -224,50 +450,7 @@ impl FatDirectoryEntryContainer {
         long_entries: &[FatLongDirectoryEntry],
     ) -> String {
         match long_entries.len() {
-            0 => {
-                let mut buf = vec![];
-                let name_bytes = short_entry.name;
-                let name = &name_bytes[1..8];
-                let ext = &name_bytes[8..11];
-
-                // Special case for 0x05 -> 0xE5 for first character
-                match name_bytes[0] {
-                    0x05 => buf.push(0xE5),
-                    c => buf.push(c),
-                };
-
-                // Process the name
-                let mut end_index = 0;
-                // Find last character that is not 0x20
-                for (index, c) in name.iter().rev().enumerate() {
-                    if *c != 0x20 {
-                        end_index = name.len() - index;
-                        break;
-                    }
-                }
-                // Add that section
-                if end_index != 0 {
-                    buf.extend(&name[0..end_index]);
-                }
-
-                // Process the ext
-                if ext[0] != 0x20 {
-                    // Push a . and first character of extension
-                    buf.push(0x2E);
-                    buf.push(ext[0]);
-
-                    // Push rest of extension as necessary
-                    if ext[2] != 0x20 {
-                        buf.push(ext[1]);
-                        buf.push(ext[2]);
-                    } else if ext[1] != 0x20 {
-                        buf.push(ext[1]);
-                    }
-                }
-
-                // Should technically also do a check for illegal characters...
-                String::from_utf8(buf).unwrap()
-            }
+            0 => short_entry.short_name(),
             _ => {
                 // Declare array
                 let num_long_entries = long_entries.len();
@@ -275,7 +458,7 @@ impl FatDirectoryEntryContainer {
 
                 // Add various portions of the name
                 for entry in long_entries.iter() {
-                    let entry_n = entry.order & !0x40;
+                    let entry_n = entry.order & !LAST_LONG_ENTRY;
                     let offset: usize = (entry_n as usize - 1) * 13;
                     replace_vec_section(&mut name_bytes, &entry.name1, offset);
                     replace_vec_section(
@@ -290,8 +473,12 @@ impl FatDirectoryEntryContainer {
                     );
                 }
 
-                // Find terminator and take slice
-                let index = name_bytes.iter().position(|&r| r == 0).unwrap();
+                // Find terminator and take slice, names filling the last
+                // entry exactly are not terminated
+                let index = name_bytes
+                    .iter()
+                    .position(|&r| r == 0)
+                    .unwrap_or(name_bytes.len());
                 let name: Vec<u16> = name_bytes[0..index].to_vec();
                 // To string
                 decode_utf16(name)
@@ -322,6 +509,36 @@ fn parse_time(time: u16) -> (u8, u8, u8) {
     )
 }
 
+/// Encodes date as FAT directory entry date stamp
+/// Dates outside of 1980-2107 are clamped to the range
+pub fn encode_date(datetime: &FatDateTime) -> u16 {
+    if datetime.year < 1980 {
+        return (1 << 5) | 1;
+    }
+    if datetime.year > 2107 {
+        return (127 << 9) | (12 << 5) | 31;
+    }
+    ((datetime.year - 1980) << 9)
+        | ((datetime.month as u16) << 5)
+        | datetime.day as u16
+}
+
+/// Encodes time as FAT directory entry time stamp and centiseconds (0-199)
+pub fn encode_time(datetime: &FatDateTime) -> (u16, u8) {
+    if datetime.year < 1980 {
+        return (0, 0);
+    }
+    if datetime.year > 2107 {
+        return ((23 << 11) | (59 << 5) | 29, 100);
+    }
+    let time = ((datetime.hour as u16) << 11)
+        | ((datetime.minute as u16) << 5)
+        | (datetime.second as u16 / 2);
+    // Odd seconds are carried by the centiseconds
+    let centiseconds = (datetime.second % 2) * 100 + datetime.centiseconds;
+    (time, centiseconds)
+}
+
 /// Overwrites section of vector starting at 'start' with contents of array
 fn replace_vec_section(v: &mut Vec<u16>, a: &[u16], start: usize) {
     for (index, c) in a.iter().enumerate() {
@@ -367,6 +584,26 @@ impl FatDirectoryEntry {
     }
 }
 
+impl FatDirectoryEntry {
+    /// Encodes directory entry as stored on disk
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0..11].copy_from_slice(&self.name);
+        bytes[11] = self.attribute;
+        bytes[12] = self.nt_reserved;
+        bytes[13] = self.created_time_tenth;
+        bytes[14..16].copy_from_slice(&self.created_time.to_le_bytes());
+        bytes[16..18].copy_from_slice(&self.created_date.to_le_bytes());
+        bytes[18..20].copy_from_slice(&self.last_accessed.to_le_bytes());
+        bytes[20..22].copy_from_slice(&self.first_cluster_hi.to_le_bytes());
+        bytes[22..24].copy_from_slice(&self.write_time.to_le_bytes());
+        bytes[24..26].copy_from_slice(&self.write_date.to_le_bytes());
+        bytes[26..28].copy_from_slice(&self.first_cluster_low.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+}
+
 /// Reads long directory entry
 impl FatLongDirectoryEntry {
     fn new(entry_bytes: &[u8]) -> FatLongDirectoryEntry {
@@ -397,3 +634,258 @@ impl FatLongDirectoryEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_helper::{eof_marker, write_fat_entry};
+    use super::super::fat_test::{pattern, volumes, Scratch, STAMP};
+    use super::super::{
+        cluster_chain, cluster_size, file_cluster_count, Fat, FatError,
+        FatFileType, FatType,
+    };
+    use super::{chksum, read_dir_chain, LAST_LONG_ENTRY};
+
+    /// Inode the directories of tests are cached under
+    const DIR: u32 = 5;
+    const SHORT_NAME: &[u8; 11] = b"ALONGF~1TXT";
+
+    /// Short entry of a file
+    fn short(name: &[u8; 11]) -> Vec<u8> {
+        let mut slot = vec![0; 32];
+        slot[0..11].copy_from_slice(name);
+        slot[11] = FatFileType::AttrArchive as u8;
+        slot
+    }
+
+    /// Long entry holding 13 characters of a name from offset, terminated and
+    /// padded as on disk
+    fn long(order: u8, checksum: u8, name: &str, offset: usize) -> Vec<u8> {
+        let mut units: Vec<u16> =
+            name.encode_utf16().skip(offset).take(13).collect();
+        if units.len() < 13 {
+            units.push(0);
+        }
+        units.resize(13, 0xFFFF);
+        let mut slot = vec![0; 32];
+        slot[0] = order;
+        slot[11] = FatFileType::AttrLongname as u8;
+        slot[13] = checksum;
+        let positions = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (unit, position) in units.iter().zip(positions) {
+            slot[position..position + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        slot
+    }
+
+    /// Long entries of name in on-disk order, the last part first
+    fn long_set(name: &str, checksum: u8) -> Vec<Vec<u8>> {
+        let count = name.encode_utf16().count().div_ceil(13);
+        (1..=count)
+            .rev()
+            .map(|order| {
+                let flag = if order == count { LAST_LONG_ENTRY } else { 0 };
+                long(order as u8 | flag, checksum, name, (order - 1) * 13)
+            })
+            .collect()
+    }
+
+    /// Names of entries read from directory data
+    fn names(fat: &mut Fat, slots: &[Vec<u8>]) -> Vec<String> {
+        read_dir_chain(fat, DIR, &slots.concat(), 0);
+        fat.dir_cache[&DIR]
+            .iter()
+            .map(|entry| entry.get_name().clone())
+            .collect()
+    }
+
+    #[test]
+    fn reads_long_names() {
+        let mut fat = Scratch::new("long-names", 1 << 20, FatType::Fat12);
+        let name = "A long file name.txt";
+        let mut slots = long_set(name, chksum(SHORT_NAME));
+        slots.push(short(SHORT_NAME));
+        slots.push(short(b"README     "));
+        assert_eq!(names(&mut fat, &slots), [name, "README"]);
+    }
+
+    #[test]
+    fn drops_long_names_with_checksum_mismatch() {
+        let mut fat = Scratch::new("long-checksum", 1 << 20, FatType::Fat12);
+        let checksum = chksum(SHORT_NAME);
+        let mut slots = long_set("A long file name.txt", checksum);
+        slots.push(short(b"OTHERF~1TXT"));
+        assert_eq!(names(&mut fat, &slots), ["OTHERF~1.TXT"]);
+
+        // Only one entry of the set is off
+        let mut slots = long_set("A long file name.txt", checksum);
+        slots[1][13] = checksum.wrapping_add(1);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+    }
+
+    #[test]
+    fn drops_orphaned_long_entries() {
+        let mut fat = Scratch::new("long-orphans", 1 << 20, FatType::Fat12);
+        let checksum = chksum(SHORT_NAME);
+        let name = "A long file name.txt";
+
+        // Set interrupted by a free entry
+        let mut slots = long_set(name, checksum);
+        slots.insert(1, vec![0xE5; 32]);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+
+        // Set followed by an entry which is neither long nor short
+        let mut slots = long_set(name, checksum);
+        let mut invalid = short(b"INVALID    ");
+        invalid[11] =
+            FatFileType::AttrDirectory as u8 | FatFileType::AttrVolumeId as u8;
+        slots.push(invalid);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+
+        // Set whose first entry was lost, a later set is not affected
+        let mut slots = long_set(name, checksum);
+        slots.remove(0);
+        slots.push(short(SHORT_NAME));
+        slots.extend(long_set("Another name", chksum(b"ANOTHE~1   ")));
+        slots.push(short(b"ANOTHE~1   "));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT", "Another name"]);
+    }
+
+    #[test]
+    fn drops_long_entries_out_of_order() {
+        let mut fat = Scratch::new("long-order", 1 << 20, FatType::Fat12);
+        let checksum = chksum(SHORT_NAME);
+        let name = "A rather longer file name.txt";
+
+        // Entries swapped
+        let mut slots = long_set(name, checksum);
+        slots.swap(1, 2);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+
+        // Entry of the middle missing
+        let mut slots = long_set(name, checksum);
+        slots.remove(1);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+
+        // Same entry twice
+        let mut slots = long_set(name, checksum);
+        slots.insert(2, slots[1].clone());
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+    }
+
+    #[test]
+    fn limits_long_names_to_20_entries() {
+        let mut fat = Scratch::new("long-limit", 1 << 20, FatType::Fat12);
+        let checksum = chksum(SHORT_NAME);
+
+        // Longest name allowed, 255 characters in 20 entries
+        let name = "n".repeat(255);
+        let mut slots = long_set(&name, checksum);
+        assert_eq!(slots.len(), 20);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), [name]);
+
+        let name = "n".repeat(20 * 13 + 1);
+        let mut slots = long_set(&name, checksum);
+        assert_eq!(slots.len(), 21);
+        slots.push(short(SHORT_NAME));
+        assert_eq!(names(&mut fat, &slots), ["ALONGF~1.TXT"]);
+    }
+
+    #[test]
+    fn stops_at_truncated_sectors() {
+        let mut fat = Scratch::new("truncated", 1 << 20, FatType::Fat12);
+        let mut slots = vec![short(b"FIRST   TXT"), short(b"SECOND  TXT")];
+        // Partial entry at the end of the data is ignored
+        slots.push(short(b"THIRD   TXT")[..20].to_vec());
+        assert_eq!(names(&mut fat, &slots), ["FIRST.TXT", "SECOND.TXT"]);
+
+        let slots = vec![short(b"FIRST   TXT")[..31].to_vec()];
+        assert!(names(&mut fat, &slots).is_empty());
+        assert!(names(&mut fat, &[]).is_empty());
+
+        // Long entries without their short entry are dropped
+        let mut slots = long_set("A long file name.txt", chksum(SHORT_NAME));
+        slots.push(short(SHORT_NAME)[..16].to_vec());
+        assert!(names(&mut fat, &slots).is_empty());
+    }
+
+    #[test]
+    fn derives_cluster_counts_of_files_from_size() {
+        for mut fat in volumes("size-counts") {
+            let cluster_size = cluster_size(&fat) as usize;
+            let sizes =
+                [0, 1, cluster_size, cluster_size + 1, 5 * cluster_size];
+            for (seed, size) in sizes.iter().enumerate() {
+                let path = format!("/f{}", size);
+                let data = pattern(*size, seed as u8);
+                fat.write_file(&path, &data, &STAMP, &STAMP).unwrap();
+            }
+            fat.remount();
+
+            for size in sizes {
+                let entry = fat.entry_at(&format!("/f{}", size)).unwrap();
+                let entry = entry.unwrap();
+                let chain_count =
+                    file_cluster_count(&fat, entry.cluster_number()).unwrap();
+                assert_eq!(entry.cached_cluster_count.get(), Some(chain_count));
+                assert_eq!(fat.verify_cluster_count(&entry), Ok(true));
+            }
+        }
+    }
+
+    #[test]
+    fn lists_directories_without_walking_chains() {
+        let mut fat = Scratch::new("lazy-counts", 1 << 20, FatType::Fat12);
+        fat.create_dir_with_capacity("/dir", &STAMP, 20).unwrap();
+        let data = pattern(3 * cluster_size(&fat) as usize, 1);
+        fat.write_file("/file", &data, &STAMP, &STAMP).unwrap();
+        // A chain which loops is only noticed once it is walked
+        let file = fat.entry_at("/file").unwrap().unwrap().cluster_number();
+        write_fat_entry(&mut fat, file, file);
+        fat.remount();
+
+        let root = fat.get_root_cluster_number();
+        let entries = fat.list_directory(root).unwrap().unwrap();
+        let count = |name: &str| {
+            let entry = entries.iter().find(|entry| entry.get_name() == name);
+            entry.unwrap().cached_cluster_count.get()
+        };
+        assert_eq!(count("file"), Some(3));
+        assert_eq!(count("dir"), None);
+
+        // Chains of directories are walked on demand
+        let dir = fat.entry_at("/dir").unwrap().unwrap();
+        assert_eq!(dir.cluster_count(fat.is_fat32()), 2);
+        let file = fat.entry_at("/file").unwrap().unwrap();
+        let result = fat.verify_cluster_count(&file);
+        assert_eq!(result, Err(FatError::ChainCycle(file.cluster_number())));
+    }
+
+    #[test]
+    fn reports_truncated_chains() {
+        for mut fat in volumes("truncated-chains") {
+            let size = 3 * cluster_size(&fat) as usize;
+            fat.write_file("/file", &pattern(size, 2), &STAMP, &STAMP)
+                .unwrap();
+            let entry = fat.entry_at("/file").unwrap().unwrap();
+            assert_eq!(fat.verify_cluster_count(&entry), Ok(true));
+
+            let chain = cluster_chain(&fat, entry.cluster_number()).unwrap();
+            let eof = eof_marker(fat.fat_type);
+            write_fat_entry(&mut fat, chain[1], eof);
+            write_fat_entry(&mut fat, chain[2], 0);
+            assert_eq!(fat.verify_cluster_count(&entry), Ok(false));
+            // The count derived from the size is kept
+            assert_eq!(entry.cluster_count(fat.is_fat32()), 3);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors found while reading or modifying the volume
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum FatError {
+    /// Chain links back to a cluster already visited
+    ChainCycle(u32),
+    /// Chain links to a cluster outside of the data region
+    ClusterOutOfRange(u32),
+    /// Chain contains a cluster marked as bad
+    BadCluster(u32),
+    /// Directory at cluster is reached again while walking the tree
+    DirectoryCycle(u32),
+    /// Path does not exist
+    NotFound,
+    /// Path already exists
+    AlreadyExists,
+    /// Path component is not a directory
+    NotADirectory,
+    /// Path is a directory where a file is expected
+    IsADirectory,
+    /// Directory still holds entries
+    DirectoryNotEmpty,
+    /// Name cannot be stored in a directory entry
+    InvalidName,
+    /// No free clusters or directory entries left
+    NoSpace,
+    /// Volume size does not suit the FAT type
+    UnsuitableSize,
+    /// Clusters or slots of a removed entry have been reused
+    Overwritten,
+    /// Boot sector does not describe a FAT volume which fits the image
+    InvalidBootSector(&'static str),
+    /// Reading or writing the image failed
+    Io(io::ErrorKind),
+}
+
+impl FatError {
+    /// Cluster which caused the error, if any
+    pub fn cluster(&self) -> Option<u32> {
+        match *self {
+            FatError::ChainCycle(cluster)
+            | FatError::ClusterOutOfRange(cluster)
+            | FatError::BadCluster(cluster)
+            | FatError::DirectoryCycle(cluster) => Some(cluster),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FatError {
+    fn from(error: io::Error) -> FatError {
+        FatError::Io(error.kind())
+    }
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FatError::ChainCycle(cluster) => {
+                write!(f, "Cluster chain loops back to cluster {}", cluster)
+            }
+            FatError::ClusterOutOfRange(cluster) => {
+                write!(f, "Cluster {} is out of range", cluster)
+            }
+            FatError::BadCluster(cluster) => {
+                write!(f, "Cluster {} is marked as bad", cluster)
+            }
+            FatError::DirectoryCycle(cluster) => write!(
+                f,
+                "Directory at cluster {} is reached more than once",
+                cluster
+            ),
+            FatError::NotFound => write!(f, "No such file or directory"),
+            FatError::AlreadyExists => write!(f, "File exists"),
+            FatError::NotADirectory => write!(f, "Not a directory"),
+            FatError::IsADirectory => write!(f, "Is a directory"),
+            FatError::DirectoryNotEmpty => write!(f, "Directory not empty"),
+            FatError::InvalidName => write!(f, "Invalid file name"),
+            FatError::NoSpace => write!(f, "No space left on volume"),
+            FatError::UnsuitableSize => {
+                write!(f, "Volume size does not suit the FAT type")
+            }
+            FatError::Overwritten => {
+                write!(f, "Removed entry has been overwritten")
+            }
+            FatError::InvalidBootSector(reason) => {
+                write!(f, "Invalid boot sector: {}", reason)
+            }
+            FatError::Io(kind) => write!(f, "I/O error: {:?}", kind),
+        }
+    }
+}
+
+impl Error for FatError {}
@@ -0,0 +1,131 @@
+use super::{
+    cluster_chain, fat_entry, locate, Fat, FatError, FatExtent, FatType,
+};
+
+/// Splits clusters into runs of consecutive clusters, in chain order
+pub fn extents(clusters: &[u32]) -> Vec<FatExtent> {
+    let mut extents: Vec<FatExtent> = vec![];
+    for cluster in clusters {
+        match extents.last_mut() {
+            Some(extent) if extent.start + extent.length == *cluster => {
+                extent.length += 1
+            }
+            _ => extents.push(FatExtent {
+                start: *cluster,
+                length: 1,
+            }),
+        }
+    }
+    extents
+}
+
+/// Runs of clusters holding the entry at path
+/// The fixed root directory of FAT12/16 has no clusters
+pub fn file_extents(
+    fat: &mut Fat,
+    path: &str,
+) -> Result<Vec<FatExtent>, FatError> {
+    let cluster_number = if path.trim_end_matches('/').is_empty() {
+        match fat.fat_type {
+            FatType::Fat32 => fat.get_root_cluster_number(),
+            _ => 0,
+        }
+    } else {
+        let (_, location) = locate(fat, path)?;
+        fat.dir_cache[&location.parent][location.index].cluster_number()
+    };
+    chain_extents(fat, cluster_number)
+}
+
+/// Runs of clusters of the chain starting at cluster number
+pub fn chain_extents(
+    fat: &Fat,
+    cluster_number: u32,
+) -> Result<Vec<FatExtent>, FatError> {
+    Ok(extents(&cluster_chain(fat, cluster_number)?))
+}
+
+/// Runs of free clusters in the data region, in cluster order
+pub fn free_extents(fat: &Fat) -> Vec<FatExtent> {
+    let free: Vec<u32> = (2..fat.cluster_count + 2)
+        .filter(|cluster| fat_entry(fat, *cluster) == 0)
+        .collect();
+    extents(&free)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{pattern, volumes, STAMP};
+    use super::super::{FatError, FatExtent, FatType};
+    use super::extents;
+
+    /// Extent of length clusters from start
+    fn run(start: u32, length: u32) -> FatExtent {
+        FatExtent { start, length }
+    }
+
+    #[test]
+    fn splits_chains_into_runs() {
+        assert_eq!(extents(&[]), vec![]);
+        assert_eq!(extents(&[9]), vec![run(9, 1)]);
+        assert_eq!(
+            extents(&[5, 6, 7, 20, 3, 4]),
+            vec![run(5, 3), run(20, 1), run(3, 2)]
+        );
+        // Going backwards starts a new run
+        assert_eq!(extents(&[8, 7]), vec![run(8, 1), run(7, 1)]);
+    }
+
+    #[test]
+    fn reports_extents_of_fragmented_files() {
+        for mut fat in volumes("extents") {
+            let cluster_size = fat.cluster_size() as usize;
+            for (seed, name) in ["/a", "/b", "/c"].iter().enumerate() {
+                let data = pattern(2 * cluster_size, seed as u8);
+                fat.write_file(name, &data, &STAMP, &STAMP).unwrap();
+            }
+            let b = fat.file_extents("/b").unwrap();
+            let c = fat.file_extents("/c").unwrap();
+            assert_eq!(b.len(), 1);
+            fat.remove("/b").unwrap();
+
+            // Fill the gap left behind first, then continue after /c
+            fat.next_free = b[0].start;
+            let data = pattern(3 * cluster_size, 7);
+            fat.write_file("/d", &data, &STAMP, &STAMP).unwrap();
+            let after = c[0].start + c[0].length;
+            assert_eq!(
+                fat.file_extents("/d").unwrap(),
+                vec![b[0], run(after, 1)]
+            );
+            assert_eq!(fat.file_extents("/empty"), Err(FatError::NotFound));
+
+            // Only the FAT32 root directory has clusters
+            let root = fat.file_extents("/").unwrap();
+            assert_eq!(root.is_empty(), fat.fat_type != FatType::Fat32);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn reports_free_extents() {
+        for mut fat in volumes("free-extents") {
+            let total = fat.cluster_count;
+            let free = fat.free_extents();
+            let used = total - free.iter().map(|e| e.length).sum::<u32>();
+            assert_eq!(free.len(), 1);
+
+            let cluster_size = fat.cluster_size() as usize;
+            for name in ["/a", "/b", "/c"] {
+                let data = pattern(cluster_size, 1);
+                fat.write_file(name, &data, &STAMP, &STAMP).unwrap();
+            }
+            let b = fat.file_extents("/b").unwrap()[0];
+            fat.remove("/b").unwrap();
+            let free = fat.free_extents();
+            assert_eq!(free[0], b);
+            let length = free.iter().map(|e| e.length).sum::<u32>();
+            assert_eq!(length, total - used - 2);
+        }
+    }
+}
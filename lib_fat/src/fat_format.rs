@@ -0,0 +1,501 @@
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+
+use super::{
+    eof_marker, new_short_entry, reserve_slots, Fat, FatDateTime, FatError,
+    FatFileType, FatType,
+    FatType::{Fat12, Fat16, Fat32},
+};
+
+/// Bytes per sector of new volumes
+const BYTES_PER_SECTOR: u32 = 512;
+/// Number of FATs of new volumes
+const NUM_FATS: u32 = 2;
+/// Media descriptor of fixed disks
+const MEDIA_DESCRIPTOR: u8 = 0xF8;
+/// Reserved sectors of FAT32 volumes, holding boot sector, FSInfo and backups
+const FAT32_RESERVED_SECTORS: u32 = 32;
+/// Sector holding the FSInfo structure of FAT32 volumes
+const FSINFO_SECTOR: u32 = 1;
+/// Sector holding the backup boot sector of FAT32 volumes
+const BACKUP_BOOT_SECTOR: u32 = 6;
+
+/// Parameters of a new volume
+#[derive(Debug, Clone)]
+pub struct FatFormat {
+    // Size of volume in bytes
+    pub size: u64,
+    // Chosen from the size if not given
+    pub fat_type: Option<FatType>,
+    // Up to 11 characters valid in short names
+    pub label: Option<String>,
+    // Serial number
+    pub volume_id: u32,
+    // Entries the root directory should hold without growing
+    pub root_slots: usize,
+    // Time stamp of the volume label entry
+    pub created: FatDateTime,
+}
+
+/// Layout of a new volume
+struct Geometry {
+    fat_type: FatType,
+    total_sectors: u32,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    root_entry_count: u32,
+    fat_size: u32,
+}
+
+/// FAT type suiting a volume of given size, as chosen by common formatters
+fn default_fat_type(size: u64) -> FatType {
+    if size < 16 * 1024 * 1024 {
+        Fat12
+    } else if size < 512 * 1024 * 1024 {
+        Fat16
+    } else {
+        Fat32
+    }
+}
+
+/// Whether a count of clusters is determined to be of FAT type
+fn suits(fat_type: FatType, cluster_count: u32) -> bool {
+    match fat_type {
+        Fat12 => cluster_count < 4085,
+        Fat16 => (4085..65525).contains(&cluster_count),
+        Fat32 => (65525..0x0FFFFFF5).contains(&cluster_count),
+    }
+}
+
+/// Finds the smallest cluster size giving a count of clusters of FAT type
+fn geometry(format: &FatFormat) -> Result<Geometry, FatError> {
+    let fat_type = format
+        .fat_type
+        .unwrap_or_else(|| default_fat_type(format.size));
+    let total_sectors = u32::try_from(format.size / BYTES_PER_SECTOR as u64)
+        .map_err(|_| FatError::UnsuitableSize)?;
+
+    let (reserved_sectors, root_entry_count) = match fat_type {
+        Fat32 => (FAT32_RESERVED_SECTORS, 0),
+        _ => {
+            // Floppy sized volumes traditionally hold 224 root entries
+            let default = if total_sectors <= 5760 { 224 } else { 512 };
+            // Fill whole sectors
+            let per_sector = (BYTES_PER_SECTOR / 32) as usize;
+            let requested = format.root_slots.div_ceil(per_sector) * per_sector;
+            let count = requested.max(default);
+            if count > 0xFFF0 {
+                return Err(FatError::NoSpace);
+            }
+            (1, count as u32)
+        }
+    };
+    let root_dir_sectors = root_entry_count * 32 / BYTES_PER_SECTOR;
+
+    for shift in 0..8 {
+        let sectors_per_cluster = 1u32 << shift;
+        // The FAT size depends on the cluster count it leaves
+        let mut fat_size = 1u32;
+        let cluster_count = loop {
+            let overhead =
+                reserved_sectors + NUM_FATS * fat_size + root_dir_sectors;
+            if overhead >= total_sectors {
+                return Err(FatError::UnsuitableSize);
+            }
+            let cluster_count =
+                (total_sectors - overhead) / sectors_per_cluster;
+            let fat_bytes = match fat_type {
+                Fat12 => ((cluster_count as u64 + 2) * 3).div_ceil(2),
+                Fat16 => (cluster_count as u64 + 2) * 2,
+                Fat32 => (cluster_count as u64 + 2) * 4,
+            };
+            let needed = fat_bytes.div_ceil(BYTES_PER_SECTOR as u64) as u32;
+            if needed <= fat_size {
+                break cluster_count;
+            }
+            fat_size = needed;
+        };
+        if suits(fat_type, cluster_count) {
+            return Ok(Geometry {
+                fat_type,
+                total_sectors,
+                sectors_per_cluster,
+                reserved_sectors,
+                root_entry_count,
+                fat_size,
+            });
+        }
+    }
+    Err(FatError::UnsuitableSize)
+}
+
+/// Pads label with spaces, it may only hold characters valid in short names
+fn label_bytes(label: &Option<String>) -> Result<Option<[u8; 11]>, FatError> {
+    let label = match label {
+        None => return Ok(None),
+        Some(label) => label.to_uppercase(),
+    };
+    if label.is_empty()
+        || label.len() > 11
+        || !label.chars().all(|c| {
+            c.is_ascii_uppercase()
+                || c.is_ascii_digit()
+                || " !#$%&'()-@^_`{}~".contains(c)
+        })
+    {
+        return Err(FatError::InvalidName);
+    }
+    let mut bytes = [b' '; 11];
+    bytes[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(Some(bytes))
+}
+
+/// Builds boot sector of new volume
+fn boot_sector(
+    geometry: &Geometry,
+    format: &FatFormat,
+    label: &[u8; 11],
+) -> Vec<u8> {
+    let mut sector = vec![0u8; BYTES_PER_SECTOR as usize];
+    let is_fat32 = geometry.fat_type == Fat32;
+    // Jump over the parameter blocks
+    let jump: [u8; 3] = if is_fat32 {
+        [0xEB, 0x58, 0x90]
+    } else {
+        [0xEB, 0x3C, 0x90]
+    };
+    sector[0..3].copy_from_slice(&jump);
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+
+    // BPB
+    sector[11..13].copy_from_slice(&(BYTES_PER_SECTOR as u16).to_le_bytes());
+    sector[13] = geometry.sectors_per_cluster as u8;
+    sector[14..16]
+        .copy_from_slice(&(geometry.reserved_sectors as u16).to_le_bytes());
+    sector[16] = NUM_FATS as u8;
+    sector[17..19]
+        .copy_from_slice(&(geometry.root_entry_count as u16).to_le_bytes());
+    if !is_fat32 && geometry.total_sectors < 0x10000 {
+        sector[19..21]
+            .copy_from_slice(&(geometry.total_sectors as u16).to_le_bytes());
+    } else {
+        sector[32..36].copy_from_slice(&geometry.total_sectors.to_le_bytes());
+    }
+    sector[21] = MEDIA_DESCRIPTOR;
+    if !is_fat32 {
+        sector[22..24]
+            .copy_from_slice(&(geometry.fat_size as u16).to_le_bytes());
+    }
+    // Sectors per track and heads, only meaningful to BIOS
+    sector[24..26].copy_from_slice(&32u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&64u16.to_le_bytes());
+
+    // EBPB
+    let ebpb = if is_fat32 {
+        sector[36..40].copy_from_slice(&geometry.fat_size.to_le_bytes());
+        // Root directory starts at the first cluster
+        sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+        sector[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+        sector[50..52]
+            .copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+        64
+    } else {
+        36
+    };
+    sector[ebpb] = 0x80;
+    sector[ebpb + 2] = 0x29;
+    sector[ebpb + 3..ebpb + 7].copy_from_slice(&format.volume_id.to_le_bytes());
+    sector[ebpb + 7..ebpb + 18].copy_from_slice(label);
+    let fs_type: &[u8; 8] = match geometry.fat_type {
+        Fat12 => b"FAT12   ",
+        Fat16 => b"FAT16   ",
+        Fat32 => b"FAT32   ",
+    };
+    sector[ebpb + 18..ebpb + 26].copy_from_slice(fs_type);
+
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    sector
+}
+
+/// Builds FSInfo sector of new FAT32 volume, with the root cluster in use
+fn fsinfo_sector(geometry: &Geometry) -> Vec<u8> {
+    let cluster_count = (geometry.total_sectors
+        - geometry.reserved_sectors
+        - NUM_FATS * geometry.fat_size)
+        / geometry.sectors_per_cluster;
+    let mut sector = vec![0u8; BYTES_PER_SECTOR as usize];
+    sector[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+    sector[488..492].copy_from_slice(&(cluster_count - 1).to_le_bytes());
+    sector[492..496].copy_from_slice(&3u32.to_le_bytes());
+    sector[508..512].copy_from_slice(&0xAA550000u32.to_le_bytes());
+    sector
+}
+
+/// Builds first sector of a FAT, with the reserved entries set
+fn first_fat_sector(fat_type: FatType) -> Vec<u8> {
+    let mut sector = vec![0u8; BYTES_PER_SECTOR as usize];
+    let eof = eof_marker(fat_type);
+    // Entry 0 holds the media descriptor, entry 1 marks end of chain
+    let reserved = (0xFFFFFF00 | MEDIA_DESCRIPTOR as u32) & eof;
+    match fat_type {
+        Fat12 => {
+            let entries = reserved | eof << 12;
+            sector[0..3].copy_from_slice(&entries.to_le_bytes()[0..3]);
+        }
+        Fat16 => {
+            sector[0..2].copy_from_slice(&(reserved as u16).to_le_bytes());
+            sector[2..4].copy_from_slice(&(eof as u16).to_le_bytes());
+        }
+        Fat32 => {
+            sector[0..4].copy_from_slice(&reserved.to_le_bytes());
+            sector[4..8].copy_from_slice(&eof.to_le_bytes());
+            // Root directory
+            sector[8..12].copy_from_slice(&eof.to_le_bytes());
+        }
+    }
+    sector
+}
+
+/// Creates image file holding a new, empty volume and mounts it for writing
+pub fn format(filename: &str, format: &FatFormat) -> Result<Fat, FatError> {
+    let geometry = geometry(format)?;
+    let label = label_bytes(&format.label)?;
+
+    let mut image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(filename)?;
+    // Unwritten sectors read as zeros
+    image.set_len(geometry.total_sectors as u64 * BYTES_PER_SECTOR as u64)?;
+
+    let mut write_sector = |sector_number: u32, data: &[u8]| {
+        image.seek(SeekFrom::Start(
+            sector_number as u64 * BYTES_PER_SECTOR as u64,
+        ))?;
+        image.write_all(data)
+    };
+
+    let boot_sector =
+        boot_sector(&geometry, format, &label.unwrap_or(*b"NO NAME    "));
+    write_sector(0, &boot_sector)?;
+    if geometry.fat_type == Fat32 {
+        let fsinfo_sector = fsinfo_sector(&geometry);
+        write_sector(FSINFO_SECTOR, &fsinfo_sector)?;
+        write_sector(BACKUP_BOOT_SECTOR, &boot_sector)?;
+        write_sector(BACKUP_BOOT_SECTOR + 1, &fsinfo_sector)?;
+    }
+    for i in 0..NUM_FATS {
+        write_sector(
+            geometry.reserved_sectors + i * geometry.fat_size,
+            &first_fat_sector(geometry.fat_type),
+        )?;
+    }
+
+    // Root directory follows the FATs, for FAT32 as its first cluster
+    if let Some(label) = label {
+        let mut slot = new_short_entry(
+            FatFileType::AttrVolumeId as u8,
+            0,
+            0,
+            &format.created,
+            &format.created,
+        );
+        slot[0..11].copy_from_slice(&label);
+        write_sector(
+            geometry.reserved_sectors + NUM_FATS * geometry.fat_size,
+            &slot,
+        )?;
+    }
+    image.flush()?;
+    // Mounting reads the boot sector from the current position
+    image.seek(SeekFrom::Start(0))?;
+
+    let mut fat = Fat::open(image)?;
+    let root = fat.get_root_cluster_number();
+    reserve_slots(&mut fat, root, format.root_slots)?;
+    Ok(fat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{pattern, Scratch, STAMP};
+    use super::super::FatError;
+    use super::{
+        default_fat_type, geometry, Fat, FatFormat, FatType, Geometry,
+    };
+    use super::{Fat12, Fat16, Fat32, BYTES_PER_SECTOR, NUM_FATS};
+
+    /// Most clusters volumes of each FAT type can hold
+    const MAX_CLUSTERS: [(FatType, u32); 3] =
+        [(Fat12, 4084), (Fat16, 65524), (Fat32, 0x0FFFFFF4)];
+
+    /// Least sectors of volumes of each FAT type with clusters of a sector
+    const SMALLEST: [(FatType, u32); 3] =
+        [(Fat12, 18), (Fat16, 4134), (Fat32, 66599)];
+
+    /// Least sectors of volumes given clusters of the number of sectors
+    const BOUNDARIES: [(FatType, u32, u32); 18] = [
+        (Fat12, 4126, 2),
+        (Fat12, 8229, 4),
+        (Fat12, 16397, 8),
+        (Fat12, 32737, 16),
+        (Fat12, 65417, 32),
+        (Fat12, 130777, 64),
+        (Fat12, 261497, 128),
+        (Fat16, 66074, 2),
+        (Fat16, 131597, 4),
+        (Fat16, 262647, 8),
+        (Fat16, 524747, 16),
+        (Fat16, 1048947, 32),
+        (Fat16, 2097347, 64),
+        (Fat16, 4194145, 128),
+        (Fat32, 272696359, 2),
+        (Fat32, 541098252, 4),
+        (Fat32, 1077952566, 8),
+        (Fat32, 2151686104, 16),
+    ];
+
+    fn format_of(fat_type: FatType, sectors: u32) -> FatFormat {
+        FatFormat {
+            size: sectors as u64 * BYTES_PER_SECTOR as u64,
+            fat_type: Some(fat_type),
+            label: None,
+            volume_id: 0,
+            root_slots: 0,
+            created: STAMP,
+        }
+    }
+
+    fn cluster_count(geometry: &Geometry) -> u32 {
+        let root_dir_sectors =
+            geometry.root_entry_count * 32 / BYTES_PER_SECTOR;
+        let overhead = geometry.reserved_sectors
+            + NUM_FATS * geometry.fat_size
+            + root_dir_sectors;
+        (geometry.total_sectors - overhead) / geometry.sectors_per_cluster
+    }
+
+    #[test]
+    fn smallest_volumes() {
+        for (fat_type, sectors) in SMALLEST {
+            let smallest = geometry(&format_of(fat_type, sectors)).unwrap();
+            assert_eq!(smallest.sectors_per_cluster, 1);
+            let smaller = geometry(&format_of(fat_type, sectors - 1));
+            assert!(smaller.is_err(), "{:?}", fat_type);
+        }
+    }
+
+    #[test]
+    fn cluster_size_boundaries() {
+        for (fat_type, max) in MAX_CLUSTERS {
+            for (_, sectors, sectors_per_cluster) in
+                BOUNDARIES.iter().filter(|boundary| boundary.0 == fat_type)
+            {
+                // Smaller clusters are used as long as the count allows
+                let below =
+                    geometry(&format_of(fat_type, sectors - 1)).unwrap();
+                assert_eq!(below.sectors_per_cluster, sectors_per_cluster / 2);
+                assert_eq!(cluster_count(&below), max);
+
+                let at = geometry(&format_of(fat_type, *sectors)).unwrap();
+                assert_eq!(at.sectors_per_cluster, *sectors_per_cluster);
+                // The FAT covers every cluster, along with the 2 reserved
+                let bits = match fat_type {
+                    Fat12 => 12,
+                    Fat16 => 16,
+                    Fat32 => 32,
+                };
+                let fat_bytes = at.fat_size as u64 * BYTES_PER_SECTOR as u64;
+                let entries = fat_bytes * 8 / bits;
+                assert!(entries >= cluster_count(&at) as u64 + 2);
+            }
+        }
+    }
+
+    #[test]
+    fn unsuitable_sizes() {
+        let too_large = [
+            (Fat12, 2 << 20),
+            (Fat16, 16 << 20),
+            (Fat32, u32::MAX as u64 + 1),
+        ];
+        for (fat_type, sectors) in too_large {
+            let mut format = format_of(fat_type, 0);
+            format.size = sectors * BYTES_PER_SECTOR as u64;
+            let result = geometry(&format).map(|_| ());
+            assert_eq!(result, Err(FatError::UnsuitableSize), "{:?}", fat_type);
+        }
+    }
+
+    #[test]
+    fn default_fat_types() {
+        assert_eq!(default_fat_type((16 << 20) - 1), Fat12);
+        assert_eq!(default_fat_type(16 << 20), Fat16);
+        assert_eq!(default_fat_type((512 << 20) - 1), Fat16);
+        assert_eq!(default_fat_type(512 << 20), Fat32);
+    }
+
+    #[test]
+    fn formats_mountable_volumes() {
+        let mut sizes: Vec<(FatType, u32)> = SMALLEST.to_vec();
+        // Volumes of FAT32 with larger clusters have FATs too large to read
+        for (fat_type, sectors, _) in BOUNDARIES {
+            if fat_type != Fat32 {
+                sizes.push((fat_type, sectors - 1));
+                sizes.push((fat_type, sectors));
+            }
+        }
+        for (fat_type, sectors) in sizes {
+            let format = format_of(fat_type, sectors);
+            let expected = geometry(&format).unwrap();
+            let test = format!("format-{}", sectors);
+            let size = format.size;
+            let mut fat = Scratch::new(&test, size, fat_type);
+            fat.remount();
+
+            assert_eq!(fat.fat_type, fat_type);
+            assert_eq!(fat.cluster_count(), cluster_count(&expected));
+            let cluster_size = expected.sectors_per_cluster * BYTES_PER_SECTOR;
+            let sectors_per_cluster = fat.bpb.sectors_per_cluster as u32;
+            assert_eq!(sectors_per_cluster, expected.sectors_per_cluster);
+            let (label, volume_id) = match (&fat.ebpb16, &fat.ebpb32) {
+                (Some(ebpb), _) => (ebpb.volume_label, ebpb.volume_id),
+                (_, Some(ebpb)) => (ebpb.volume_label, ebpb.volume_id),
+                _ => unreachable!(),
+            };
+            assert_eq!(&label, b"SCRATCH    ");
+            assert_eq!(u32::from_le_bytes(volume_id), 0x1234_5678);
+            // As the ioctls of the mount report them
+            assert_eq!(fat.volume_id(), 0x1234_5678);
+            assert_eq!(fat.volume_label(), "SCRATCH");
+
+            let clusters = fat.cluster_count().min(3) as usize;
+            let data = pattern(clusters * cluster_size as usize - 1, 5);
+            fat.write_file("/file", &data, &STAMP, &STAMP).unwrap();
+            fat.remount();
+            assert_eq!(fat.contents("/file"), data);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_labels() {
+        for label in ["", "TWELVE CHARS", "a.b", "ü"] {
+            let mut format = format_of(Fat12, 2880);
+            format.label = Some(label.to_string());
+            let path = std::env::temp_dir().join(format!(
+                "lib_fat-invalid-labels-{}.img",
+                std::process::id()
+            ));
+            let result = Fat::format(path.to_str().unwrap(), &format);
+            assert_eq!(result.err(), Some(FatError::InvalidName), "{}", label);
+            // Labels are checked before the image is created
+            assert!(!path.exists(), "{}", label);
+        }
+    }
+}
@@ -1,9 +1,10 @@
+use std::collections::HashSet;
 use std::io::prelude::*;
 use std::io::Read;
 use std::io::SeekFrom;
 
 use super::{
-    Fat,
+    Fat, FatError, FatType,
     FatType::{Fat12, Fat16, Fat32},
 };
 
@@ -49,26 +50,32 @@ pub fn determine_fat_entry_offset(
         + (fat_offset / fat.bpb.bytes_per_sector as u32);
     let fat_entry_offset = fat_offset % fat.bpb.bytes_per_sector as u32;
 
-    // Read FAT sector
-    return match fat.fat_type {
+    match active_fat(fat) {
+        // FAT is mirrored to all FATs
+        None => (fat_sector_number, fat_entry_offset),
+        Some(active_fat) => {
+            let fat_size: u32 = calculate_fat_size(fat);
+            (fat_sector_number + active_fat * fat_size, fat_entry_offset)
+        }
+    }
+}
+
+/// FAT in use if FAT32 mirroring is disabled, None if FATs are mirrored
+fn active_fat(fat: &Fat) -> Option<u32> {
+    match fat.fat_type {
         Fat32 => {
             // Read ebpb extension flags
             let flags = fat.ebpb32.as_ref().unwrap().flags;
             if flags & 0b0000000100000000 == 0 {
                 // 7th bit not set, FAT is mirrored to all FATs
-                (fat_sector_number, fat_entry_offset)
+                None
             } else {
                 // FAT is not mirrored
-                let active_fat = (flags & 0b1111000000000000) >> 12;
-                let fat_size: u32 = calculate_fat_size(fat);
-                (
-                    fat_sector_number + active_fat as u32 * fat_size,
-                    fat_entry_offset,
-                )
+                Some(((flags & 0b1111000000000000) >> 12) as u32)
             }
         }
-        _ => (fat_sector_number, fat_entry_offset),
-    };
+        _ => None,
+    }
 }
 
 /// Read FAT entry
@@ -129,7 +136,7 @@ pub fn root_dir_sectors(fat: &Fat) -> u16 {
 }
 
 /// Calculate FAT size
-fn calculate_fat_size(fat: &Fat) -> u32 {
+pub fn calculate_fat_size(fat: &Fat) -> u32 {
     if fat.bpb.fat_size_16 != 0 {
         fat.bpb.fat_size_16.into()
     } else {
@@ -137,6 +144,11 @@ fn calculate_fat_size(fat: &Fat) -> u32 {
     }
 }
 
+/// Bytes per cluster
+pub fn cluster_size(fat: &Fat) -> u32 {
+    fat.bpb.bytes_per_sector as u32 * fat.bpb.sectors_per_cluster as u32
+}
+
 /// Determine first sector of cluster
 pub fn first_sector_of_cluster(fat: &mut Fat, cluster_number: u32) -> u32 {
     // Sectors occupied by root directory
@@ -152,34 +164,57 @@ pub fn first_sector_of_cluster(fat: &mut Fat, cluster_number: u32) -> u32 {
         + first_data_sector
 }
 
-/// Determine number of clusters of file
-pub fn file_cluster_count(fat: &Fat, cluster_number: u32) -> u32 {
+/// Follows the cluster chain starting at cluster number
+/// Stops at cycles, bad clusters and clusters outside of the data region
+pub fn cluster_chain(
+    fat: &Fat,
+    cluster_number: u32,
+) -> Result<Vec<u32>, FatError> {
+    let mut chain: Vec<u32> = Vec::new();
     // Empty file
     if cluster_number == 0 {
-        return 0;
+        return Ok(chain);
     }
 
-    let mut n_blocks = 0;
-    let mut eof = false;
-    let mut current_block = cluster_number;
+    let mut visited: HashSet<u32> = HashSet::new();
+    let mut current_cluster = cluster_number;
+    loop {
+        // Data clusters are numbered from 2
+        if current_cluster < 2 || current_cluster > fat.cluster_count + 1 {
+            return Err(FatError::ClusterOutOfRange(current_cluster));
+        }
+        if !visited.insert(current_cluster) {
+            return Err(FatError::ChainCycle(current_cluster));
+        }
+        chain.push(current_cluster);
 
-    while !eof {
         // Determine FAT entry location
         let (fat_sector_number, fat_entry_offset) =
-            determine_fat_entry_offset(fat, current_block);
+            determine_fat_entry_offset(fat, current_cluster);
         // Lookup FAT entry
         let fat_entry = read_fat_entry(
             fat,
-            cluster_number,
+            current_cluster,
             fat_sector_number,
             fat_entry_offset,
         );
-        // Is EOF and set next block
-        eof = is_eof(fat, fat_entry) || fat_entry == 0;
-        current_block = fat_entry;
-        n_blocks += 1;
+
+        if is_bad(fat, fat_entry) {
+            return Err(FatError::BadCluster(current_cluster));
+        }
+        if is_eof(fat, fat_entry) || fat_entry == 0 {
+            return Ok(chain);
+        }
+        current_cluster = fat_entry;
     }
-    n_blocks
+}
+
+/// Determine number of clusters of file
+pub fn file_cluster_count(
+    fat: &Fat,
+    cluster_number: u32,
+) -> Result<u32, FatError> {
+    Ok(cluster_chain(fat, cluster_number)?.len() as u32)
 }
 
 /// Whether FAT entry indicate end of file
@@ -191,52 +226,362 @@ fn is_eof(fat: &Fat, fat_entry: u32) -> bool {
     }
 }
 
-/// Read data
-pub fn read_data(fat: &mut Fat, cluster_number: u32) -> (Vec<u8>, Option<u32>) {
-    // Empty file
-    if cluster_number == 0 {
-        return (vec![], None);
+/// Whether FAT entry marks the cluster as bad
+fn is_bad(fat: &Fat, fat_entry: u32) -> bool {
+    match fat.fat_type {
+        Fat12 => fat_entry == 0x0FF7,
+        Fat16 => fat_entry == 0xFFF7,
+        Fat32 => fat_entry == 0x0FFFFFF7,
     }
+}
 
-    // Find sector number
+/// Read all sectors of file
+pub fn read_file_full(
+    fat: &mut Fat,
+    cluster_number: u32,
+) -> Result<Vec<u8>, FatError> {
+    let mut data: Vec<u8> = Vec::new();
+    for cluster in cluster_chain(fat, cluster_number)? {
+        // Find sector number and append cluster
+        let sector_number = first_sector_of_cluster(fat, cluster);
+        data.append(&mut read_cluster(fat, sector_number));
+    }
+    Ok(data)
+}
+
+/// Writes bytes to consecutive sectors starting at sector number
+/// Cached reserved and FAT sectors are kept in sync
+pub fn write_sectors(
+    fat: &mut Fat,
+    sector_number: u32,
+    data: &[u8],
+) -> Result<(), FatError> {
+    let bytes_per_sector = fat.bpb.bytes_per_sector as usize;
+    fat.image.seek(SeekFrom::Start(
+        bytes_per_sector as u64 * sector_number as u64,
+    ))?;
+    fat.image.write_all(data)?;
+
+    for (index, sector) in data.chunks(bytes_per_sector).enumerate() {
+        if let Some(cached) = fat.fat.get_mut(&(sector_number + index as u32)) {
+            cached[..sector.len()].copy_from_slice(sector);
+        }
+    }
+    Ok(())
+}
+
+/// Writes data to cluster, padding the rest of the cluster with zeros
+pub fn write_cluster(
+    fat: &mut Fat,
+    cluster_number: u32,
+    data: &[u8],
+) -> Result<(), FatError> {
+    let mut buffer = vec![0u8; cluster_size(fat) as usize];
+    buffer[..data.len()].copy_from_slice(data);
     let sector_number = first_sector_of_cluster(fat, cluster_number);
-    // Read cluster
-    let sector = read_cluster(fat, sector_number);
+    write_sectors(fat, sector_number, &buffer)
+}
 
-    // FAT lookup to see there is more
+/// Lookup FAT entry of cluster
+pub fn fat_entry(fat: &Fat, cluster_number: u32) -> u32 {
     let (fat_sector_number, fat_entry_offset) =
         determine_fat_entry_offset(fat, cluster_number);
-    let fat_entry = read_fat_entry(
-        fat,
-        cluster_number,
-        fat_sector_number,
-        fat_entry_offset,
+    read_fat_entry(fat, cluster_number, fat_sector_number, fat_entry_offset)
+}
+
+/// Sets FAT entry of cluster in the cached FAT, written out by flush_fat
+pub fn write_fat_entry(fat: &mut Fat, cluster_number: u32, value: u32) {
+    if let Some(free_count) = fat.free_count {
+        let was_free = fat_entry(fat, cluster_number) == 0;
+        fat.free_count = Some(match (was_free, value == 0) {
+            (true, false) => free_count - 1,
+            (false, true) => free_count + 1,
+            _ => free_count,
+        });
+    }
+
+    let (fat_sector_number, fat_entry_offset) =
+        determine_fat_entry_offset(fat, cluster_number);
+    let offset = fat_entry_offset as usize;
+    let split = fat.bpb.bytes_per_sector as usize - 1;
+
+    // Byte locations of entry, (sector number, offset)
+    let locations: Vec<(u32, usize)> = match fat.fat_type {
+        // Entry spans over 2 sectors if it starts at the last byte
+        Fat12 if offset == split => {
+            vec![(fat_sector_number, offset), (fat_sector_number + 1, 0)]
+        }
+        Fat12 | Fat16 => {
+            vec![(fat_sector_number, offset), (fat_sector_number, offset + 1)]
+        }
+        Fat32 => (0..4).map(|i| (fat_sector_number, offset + i)).collect(),
+    };
+    let old_value = locations.iter().enumerate().fold(
+        0u32,
+        |value, (index, (sector, offset))| {
+            value | (fat.fat[sector][*offset] as u32) << (8 * index)
+        },
     );
 
-    let is_eof = is_eof(fat, fat_entry) || fat_entry == 0;
-    match is_eof {
-        true => (sector, None),
-        false => (sector, Some(fat_entry)),
+    let new_value = match fat.fat_type {
+        // Entries share the middle byte
+        Fat12 if cluster_number & 0x0001 != 0 => {
+            (old_value & 0x000F) | (value & 0x0FFF) << 4
+        }
+        Fat12 => (old_value & 0xF000) | (value & 0x0FFF),
+        Fat16 => value & 0xFFFF,
+        // Higher 4 bits are reserved
+        Fat32 => (old_value & 0xF0000000) | (value & 0x0FFFFFFF),
+    };
+
+    for (index, (sector, offset)) in locations.iter().enumerate() {
+        fat.fat.get_mut(sector).unwrap()[*offset] =
+            (new_value >> (8 * index)) as u8;
+        fat.dirty_fat_sectors.insert(*sector);
     }
+    fat.free_cache = None;
 }
 
-/// Read all sectors of file
-pub fn read_file_full(fat: &mut Fat, cluster_number: u32) -> Vec<u8> {
-    let mut data: Vec<u8> = Vec::new();
+/// FAT entry which marks the end of a chain
+pub fn eof_marker(fat_type: FatType) -> u32 {
+    match fat_type {
+        Fat12 => 0x0FFF,
+        Fat16 => 0xFFFF,
+        Fat32 => 0x0FFFFFFF,
+    }
+}
 
-    // Read extent
-    let (mut sector, mut fat_entry_option) = read_data(fat, cluster_number);
+/// Finds free clusters and links them into a chain
+pub fn allocate_clusters(
+    fat: &mut Fat,
+    count: u32,
+) -> Result<Vec<u32>, FatError> {
+    let mut clusters: Vec<u32> = Vec::new();
+    if count == 0 {
+        return Ok(clusters);
+    }
 
-    while let Some(fat_entry) = fat_entry_option {
-        // Append sector
-        data.append(&mut sector);
+    // Continue after the last allocation, wrapping around once
+    let start = fat.next_free.max(2) - 2;
+    for i in 0..fat.cluster_count {
+        let cluster = 2 + (start + i) % fat.cluster_count;
+        if fat_entry(fat, cluster) == 0 {
+            clusters.push(cluster);
+            if clusters.len() as u32 == count {
+                break;
+            }
+        }
+    }
+    if (clusters.len() as u32) < count {
+        return Err(FatError::NoSpace);
+    }
+
+    link_clusters(fat, &clusters);
+    fat.next_free = clusters.last().unwrap() + 1;
+    Ok(clusters)
+}
 
-        // Read following cluster
-        let new_data = read_data(fat, fat_entry);
-        sector = new_data.0;
-        fat_entry_option = new_data.1;
+/// Links clusters into a chain, in the order given
+pub fn link_clusters(fat: &mut Fat, clusters: &[u32]) {
+    for pair in clusters.windows(2) {
+        write_fat_entry(fat, pair[0], pair[1]);
+    }
+    if let Some(last) = clusters.last() {
+        write_fat_entry(fat, *last, eof_marker(fat.fat_type));
+    }
+}
+
+/// Marks clusters as free
+pub fn free_clusters(fat: &mut Fat, clusters: &[u32]) {
+    for cluster in clusters {
+        write_fat_entry(fat, *cluster, 0);
+    }
+}
+
+/// Replaces contents of the chain starting at cluster number with data
+/// The chain is grown or truncated to fit, returns its new first cluster
+pub fn write_chain(
+    fat: &mut Fat,
+    cluster_number: u32,
+    data: &[u8],
+) -> Result<u32, FatError> {
+    let cluster_size = cluster_size(fat) as usize;
+    let needed = data.len().div_ceil(cluster_size) as u32;
+    let mut chain = cluster_chain(fat, cluster_number)?;
+
+    let current = chain.len() as u32;
+    if needed > current {
+        let extra = allocate_clusters(fat, needed - current)?;
+        if let Some(last) = chain.last() {
+            write_fat_entry(fat, *last, extra[0]);
+        }
+        chain.extend(extra);
+    } else if needed < current {
+        free_clusters(fat, &chain[needed as usize..]);
+        chain.truncate(needed as usize);
+        if let Some(last) = chain.last() {
+            write_fat_entry(fat, *last, eof_marker(fat.fat_type));
+        }
+    }
+
+    for (cluster, chunk) in chain.iter().zip(data.chunks(cluster_size)) {
+        write_cluster(fat, *cluster, chunk)?;
+    }
+    Ok(chain.first().copied().unwrap_or(0))
+}
+
+/// Writes dirty FAT sectors to every FAT and updates the FAT32 FSInfo
+pub fn flush_fat(fat: &mut Fat) -> Result<(), FatError> {
+    let fat_size = calculate_fat_size(fat);
+    let first_fat_sector = fat.bpb.reserved_clusters as u32;
+    let mut sectors: Vec<u32> = fat.dirty_fat_sectors.drain().collect();
+    sectors.sort_unstable();
+
+    for sector_number in sectors {
+        let data = fat.fat[&sector_number].clone();
+        match active_fat(fat) {
+            None => {
+                // Mirror to all FATs
+                let relative = (sector_number - first_fat_sector) % fat_size;
+                for i in 0..fat.bpb.num_fats as u32 {
+                    let copy = first_fat_sector + i * fat_size + relative;
+                    write_sectors(fat, copy, &data)?;
+                }
+            }
+            Some(_) => write_sectors(fat, sector_number, &data)?,
+        }
+    }
+
+    if fat.fat_type == Fat32 {
+        update_fsinfo(fat)?;
+    }
+    fat.image.flush()?;
+    Ok(())
+}
+
+/// Count of free clusters, the FAT is only scanned the first time
+pub fn free_cluster_count(fat: &mut Fat) -> u32 {
+    match fat.free_count {
+        Some(free_count) => free_count,
+        None => {
+            let free_count = (2..fat.cluster_count + 2)
+                .filter(|cluster| fat_entry(fat, *cluster) == 0)
+                .count() as u32;
+            fat.free_count = Some(free_count);
+            free_count
+        }
+    }
+}
+
+/// Records free cluster count and next free cluster in FSInfo sector
+fn update_fsinfo(fat: &mut Fat) -> Result<(), FatError> {
+    let sector_number = fat.ebpb32.as_ref().unwrap().fsinfo_sector as u32;
+    let mut sector = match fat.fat.get(&sector_number) {
+        None => return Ok(()),
+        Some(sector) => sector.clone(),
+    };
+    // Lead and struct signatures, leave sector alone if missing
+    if sector[0..4] != [0x52, 0x52, 0x61, 0x41]
+        || sector[484..488] != [0x72, 0x72, 0x41, 0x61]
+    {
+        return Ok(());
+    }
+
+    let free_count = free_cluster_count(fat);
+    sector[488..492].copy_from_slice(&free_count.to_le_bytes());
+    sector[492..496].copy_from_slice(&fat.next_free.to_le_bytes());
+    write_sectors(fat, sector_number, &sector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::volumes;
+    use super::super::{FatError, FatType};
+    use super::{cluster_chain, eof_marker, fat_entry, write_fat_entry};
+
+    /// Markers of end of chain and bad clusters
+    fn markers(fat_type: FatType) -> (u32, u32) {
+        match fat_type {
+            FatType::Fat12 => (0x0FFF, 0x0FF7),
+            FatType::Fat16 => (0xFFFF, 0xFFF7),
+            FatType::Fat32 => (0x0FFF_FFFF, 0x0FFF_FFF7),
+        }
+    }
+
+    #[test]
+    fn follows_chains() {
+        for mut fat in volumes("follow-chain") {
+            let (eof, _) = markers(fat.fat_type);
+            write_fat_entry(&mut fat, 3, 4);
+            write_fat_entry(&mut fat, 4, 6);
+            write_fat_entry(&mut fat, 6, eof);
+            assert_eq!(cluster_chain(&fat, 3), Ok(vec![3, 4, 6]));
+            assert_eq!(cluster_chain(&fat, 0), Ok(vec![]));
+        }
+    }
+
+    #[test]
+    fn reports_chain_cycles() {
+        for mut fat in volumes("chain-cycle") {
+            write_fat_entry(&mut fat, 3, 4);
+            write_fat_entry(&mut fat, 4, 5);
+            write_fat_entry(&mut fat, 5, 4);
+            assert_eq!(cluster_chain(&fat, 3), Err(FatError::ChainCycle(4)));
+            // Cluster linking to itself
+            write_fat_entry(&mut fat, 7, 7);
+            assert_eq!(cluster_chain(&fat, 7), Err(FatError::ChainCycle(7)));
+        }
+    }
+
+    #[test]
+    fn reports_bad_clusters() {
+        for mut fat in volumes("bad-cluster") {
+            let (_, bad) = markers(fat.fat_type);
+            write_fat_entry(&mut fat, 3, 4);
+            write_fat_entry(&mut fat, 4, bad);
+            assert_eq!(cluster_chain(&fat, 3), Err(FatError::BadCluster(4)));
+        }
+    }
+
+    #[test]
+    fn reports_clusters_out_of_range() {
+        for mut fat in volumes("cluster-range") {
+            let last = fat.cluster_count + 1;
+            write_fat_entry(&mut fat, 3, last + 1);
+            let result = cluster_chain(&fat, 3);
+            assert_eq!(result, Err(FatError::ClusterOutOfRange(last + 1)));
+            // Clusters 0 and 1 hold the media type and end of chain marker
+            write_fat_entry(&mut fat, 5, 1);
+            assert_eq!(
+                cluster_chain(&fat, 5),
+                Err(FatError::ClusterOutOfRange(1))
+            );
+            assert_eq!(
+                cluster_chain(&fat, last + 1),
+                Err(FatError::ClusterOutOfRange(last + 1))
+            );
+            let (eof, _) = markers(fat.fat_type);
+            write_fat_entry(&mut fat, last, eof);
+            assert_eq!(cluster_chain(&fat, last), Ok(vec![last]));
+        }
+    }
+
+    #[test]
+    fn round_trips_fat_entries() {
+        for mut fat in volumes("fat-entries") {
+            let mask = eof_marker(fat.fat_type);
+            let value =
+                |cluster: u32| (cluster.wrapping_mul(0x9E37_79B9)) & mask;
+            // FAT12 entries of odd clusters share a byte with the next one,
+            // some of them spanning two sectors, the FAT32 root is kept
+            let clusters = 3..fat.cluster_count.min(2000) + 2;
+            for cluster in clusters.clone() {
+                write_fat_entry(&mut fat, cluster, value(cluster));
+            }
+            fat.remount();
+            for cluster in clusters {
+                assert_eq!(fat_entry(&fat, cluster), value(cluster));
+            }
+        }
     }
-    // Append last sector
-    data.append(&mut sector);
-    data
 }
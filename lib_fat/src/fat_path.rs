@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use super::{get_dir, Fat, FatDirectoryEntryContainer, FatError, FatFileType};
+
+/// Entry within a directory, index into cached entries of parent inode
+pub struct Location {
+    pub parent: u32,
+    pub index: usize,
+}
+
+/// Walks to the directory holding the last component of path
+/// Returns inodes of directories walked from the root, and the component
+pub fn walk_parent<'a>(
+    fat: &mut Fat,
+    path: &'a str,
+) -> Result<(Vec<u32>, &'a str), FatError> {
+    let components: Vec<&str> =
+        path.split('/').filter(|c| !c.is_empty()).collect();
+    let (name, dirs) = match components.split_last() {
+        // The root directory has no entry of its own
+        None => return Err(FatError::InvalidName),
+        Some(split) => split,
+    };
+
+    let mut inodes = vec![fat.get_root_cluster_number()];
+    for component in dirs {
+        let parent = *inodes.last().unwrap();
+        let index =
+            find_entry(fat, parent, component)?.ok_or(FatError::NotFound)?;
+        let entry = &fat.dir_cache[&parent][index];
+        if !is_dir(entry) {
+            return Err(FatError::NotADirectory);
+        }
+        inodes.push(dir_inode(fat, entry.cluster_number()));
+    }
+    Ok((inodes, name))
+}
+
+/// Finds existing entry at path, which may not be a dot entry
+pub fn locate(
+    fat: &mut Fat,
+    path: &str,
+) -> Result<(Vec<u32>, Location), FatError> {
+    let (dirs, name) = walk_parent(fat, path)?;
+    if name == "." || name == ".." {
+        return Err(FatError::InvalidName);
+    }
+    let parent = *dirs.last().unwrap();
+    let index = find_entry(fat, parent, name)?.ok_or(FatError::NotFound)?;
+    Ok((dirs, Location { parent, index }))
+}
+
+/// Inode of directory at path
+pub fn dir_at(fat: &mut Fat, path: &str) -> Result<u32, FatError> {
+    if path.trim_end_matches('/').is_empty() {
+        return Ok(fat.get_root_cluster_number());
+    }
+    let (_, location) = locate(fat, path)?;
+    let entry = &fat.dir_cache[&location.parent][location.index];
+    if !is_dir(entry) {
+        return Err(FatError::NotADirectory);
+    }
+    Ok(dir_inode(fat, entry.cluster_number()))
+}
+
+/// Index of entry with long or short name in directory, case insensitive
+pub fn find_entry(
+    fat: &mut Fat,
+    inode: u32,
+    name: &str,
+) -> Result<Option<usize>, FatError> {
+    let name = name.to_lowercase();
+    let dir = get_dir(fat, inode)?.ok_or(FatError::NotFound)?;
+    Ok(dir.iter().position(|entry| {
+        // Volume label is not a file
+        entry.attribute() & FatFileType::AttrVolumeId as u8 == 0
+            && (entry.get_name().to_lowercase() == name
+                || entry.get_short_name().to_lowercase() == name)
+    }))
+}
+
+/// Whether entry is a directory
+pub fn is_dir(entry: &FatDirectoryEntryContainer) -> bool {
+    entry.attribute() & FatFileType::AttrDirectory as u8 != 0
+}
+
+/// Inode of directory, ".." entries refer to the root as cluster 0
+pub fn dir_inode(fat: &Fat, cluster_number: u32) -> u32 {
+    if cluster_number == 0 {
+        fat.get_root_cluster_number()
+    } else {
+        cluster_number
+    }
+}
+
+/// Lists entries below path in pre-order, directories before their contents
+/// Paths are absolute, such as "/SUBDIR/INNER.TXT", and a file lists itself
+/// Names are spelled as stored, whichever case or alias path used
+/// Fails on directories reached twice, rather than looping through them
+pub fn walk(
+    fat: &mut Fat,
+    path: &str,
+) -> Result<Vec<(String, FatDirectoryEntryContainer)>, FatError> {
+    let mut entries = vec![];
+    let mut visited = HashSet::new();
+    if path.trim_end_matches('/').is_empty() {
+        let root = fat.get_root_cluster_number();
+        walk_dir(fat, root, "", &mut entries, &mut visited)?;
+        return Ok(entries);
+    }
+
+    let (dirs, location) = locate(fat, path)?;
+    let mut prefix = String::new();
+    let components = path.split('/').filter(|c| !c.is_empty());
+    for (inode, component) in dirs.iter().zip(components) {
+        let index =
+            find_entry(fat, *inode, component)?.ok_or(FatError::NotFound)?;
+        prefix.push('/');
+        prefix.push_str(fat.dir_cache[inode][index].get_name());
+    }
+
+    let entry = fat.dir_cache[&location.parent][location.index].clone();
+    let cluster_number = entry.cluster_number();
+    let descend = is_dir(&entry);
+    entries.push((prefix.clone(), entry));
+    if descend {
+        let inode = dir_inode(fat, cluster_number);
+        walk_dir(fat, inode, &prefix, &mut entries, &mut visited)?;
+    }
+    Ok(entries)
+}
+
+/// Appends entries of directory and its subdirectories
+/// Inodes of directories walked so far are kept in visited
+fn walk_dir(
+    fat: &mut Fat,
+    inode: u32,
+    prefix: &str,
+    entries: &mut Vec<(String, FatDirectoryEntryContainer)>,
+    visited: &mut HashSet<u32>,
+) -> Result<(), FatError> {
+    if !visited.insert(inode) {
+        return Err(FatError::DirectoryCycle(inode));
+    }
+    let children: Vec<FatDirectoryEntryContainer> = get_dir(fat, inode)?
+        .ok_or(FatError::NotFound)?
+        .iter()
+        .filter(|entry| {
+            entry.attribute() & FatFileType::AttrVolumeId as u8 == 0
+                && entry.get_name() != "."
+                && entry.get_name() != ".."
+        })
+        .cloned()
+        .collect();
+
+    for child in children {
+        let path = format!("{}/{}", prefix, child.get_name());
+        // Directories with cluster 0 are broken, do not descend to root
+        let subdir = if is_dir(&child) && child.cluster_number() != 0 {
+            Some(child.cluster_number())
+        } else {
+            None
+        };
+        entries.push((path.clone(), child));
+        if let Some(inode) = subdir {
+            walk_dir(fat, inode, &path, entries, visited)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{volumes, STAMP};
+
+    #[test]
+    fn looks_up_long_and_short_names() {
+        for mut fat in volumes("lookup") {
+            fat.write_file("/Long File Name.txt", b"long", &STAMP, &STAMP)
+                .unwrap();
+            fat.write_file("/README.TXT", b"short", &STAMP, &STAMP)
+                .unwrap();
+            fat.remount();
+
+            let root = fat.get_root_cluster_number();
+            let long = fat.entry_at("/Long File Name.txt").unwrap().unwrap();
+            for name in ["long file name.TXT", "LONGFI~1.TXT", "longfi~1.txt"] {
+                let entry = fat.lookup(root, name).unwrap().unwrap();
+                assert_eq!(entry.cluster_number(), long.cluster_number());
+                let path = format!("/{}", name);
+                let entry = fat.entry_at(&path).unwrap().unwrap();
+                assert_eq!(entry.cluster_number(), long.cluster_number());
+            }
+            assert!(fat.lookup(root, "readme.txt").unwrap().is_some());
+            // Volume label is not a file
+            assert!(fat.lookup(root, "SCRATCH").unwrap().is_none());
+            assert!(fat.lookup(root, "missing").unwrap().is_none());
+        }
+    }
+}
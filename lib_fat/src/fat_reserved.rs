@@ -1,30 +1,30 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{Cursor, Read, Seek, SeekFrom};
 
 use super::{
-    first_sector_of_cluster, read_sector, root_dir_sectors, Fat, Fat32Ebpb,
-    FatBpb, FatBs, FatEbpb, FatType,
+    calculate_fat_size, first_sector_of_cluster, read_sector, root_dir_sectors,
+    Fat, Fat32Ebpb, FatBpb, FatBs, FatEbpb, FatError, FatType,
     FatType::{Fat12, Fat16, Fat32},
 };
 
 // Reads reserved and inits Fat struct
-pub fn read_reserved(mut f: File) -> Fat {
+pub fn read_reserved(mut f: File) -> Result<Fat, FatError> {
     let mut buffer: [u8; 512] = [0; 512];
-    f.read_exact(&mut buffer).expect("Cannot read boot sector");
+    f.read_exact(&mut buffer)?;
 
     // Verify signature
     if buffer[510] != 0x55 || buffer[511] != 0xAA {
         // Try to seek for sector 6 - backup boot sector
-        f.seek(SeekFrom::Start(512 * 6))
-            .expect("Boot sector corrupt, seek failed for backup boot sector");
-        f.read_exact(&mut buffer)
-            .expect("Boot sector corrupt, cannot read backup boot sector");
+        f.seek(SeekFrom::Start(512 * 6))?;
+        f.read_exact(&mut buffer)?;
 
         // Verify signature
         if buffer[510] != 0x55 || buffer[511] != 0xAA {
-            panic!("Boot and backup boot sector do not have valid signature");
+            return Err(FatError::InvalidBootSector(
+                "Boot and backup boot sector do not have valid signature",
+            ));
         }
     }
 
@@ -32,6 +32,7 @@ pub fn read_reserved(mut f: File) -> Fat {
     let bs = FatBs::new(&buffer);
     // Read bpb
     let bpb = FatBpb::new(&buffer);
+    validate_bpb(&bpb)?;
 
     // Declare
     let mut fat: Fat = Fat {
@@ -41,9 +42,14 @@ pub fn read_reserved(mut f: File) -> Fat {
         ebpb16: None,
         ebpb32: None,
         fat_type: Fat32,
+        cluster_count: 0,
         fat: HashMap::new(),
+        dirty_fat_sectors: HashSet::new(),
+        next_free: 2,
         dir_cache: HashMap::new(),
         inode_cache: HashMap::new(),
+        free_cache: None,
+        free_count: None,
     };
 
     // Read ebpb
@@ -59,32 +65,43 @@ pub fn read_reserved(mut f: File) -> Fat {
     }
 
     // Ensure that total sectors is not larger than disk size
-    let file_size = fat.image.metadata().unwrap().len();
-    match fat.bpb.total_sectors_16 {
-        0 => {
-            assert!(
-                file_size
-                    >= fat.bpb.total_sectors_32 as u64
-                        * fat.bpb.bytes_per_sector as u64,
-                "Total sectors larger than disk size"
-            );
-        }
-        _ => {
-            assert!(
-                file_size
-                    >= fat.bpb.total_sectors_16 as u64
-                        * fat.bpb.bytes_per_sector as u64,
-                "Total sectors larger than disk size"
-            );
-        }
+    let file_size = fat.image.metadata()?.len();
+    let total_sectors = match fat.bpb.total_sectors_16 {
+        0 => fat.bpb.total_sectors_32,
+        total_sectors_16 => total_sectors_16 as u32,
+    };
+    if file_size < total_sectors as u64 * fat.bpb.bytes_per_sector as u64 {
+        return Err(FatError::InvalidBootSector(
+            "Total sectors larger than disk size",
+        ));
     }
 
     // Set type and cluster count
-    let fat_type = determine_fat_type(&fat);
+    let fat_type = determine_fat_type(&fat)?;
+    fat.cluster_count = fat_type.0;
     fat.fat_type = fat_type.1;
 
-    // Read FAT
-    assert!(fat.bpb.num_fats >= 2);
+    // FAT must hold an entry for every cluster
+    let fat_bytes = match fat.fat_type {
+        Fat12 => ((fat.cluster_count as u64 + 2) * 3).div_ceil(2),
+        Fat16 => (fat.cluster_count as u64 + 2) * 2,
+        Fat32 => (fat.cluster_count as u64 + 2) * 4,
+    };
+    let fat_size = calculate_fat_size(&fat) as u64;
+    if fat_bytes > fat_size * fat.bpb.bytes_per_sector as u64 {
+        return Err(FatError::InvalidBootSector(
+            "FAT is too small for the count of clusters",
+        ));
+    }
+    if fat.fat_type == Fat32 {
+        let root = fat.get_root_cluster_number();
+        if root < 2 || root > fat.cluster_count + 1 {
+            return Err(FatError::InvalidBootSector(
+                "Root directory cluster is out of range",
+            ));
+        }
+    }
+
     // Read all reserved sectors
     // First data sector is cluster 2
     for i in 0..first_sector_of_cluster(&mut fat, 2) {
@@ -93,7 +110,28 @@ pub fn read_reserved(mut f: File) -> Fat {
         fat.fat.insert(i as u32, sector);
     }
 
-    fat
+    Ok(fat)
+}
+
+/// Checks fields of the BPB which sizes and offsets are derived from
+fn validate_bpb(bpb: &FatBpb) -> Result<(), FatError> {
+    if !matches!(bpb.bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+        return Err(FatError::InvalidBootSector(
+            "Bytes per sector is not 512, 1024, 2048 or 4096",
+        ));
+    }
+    if !bpb.sectors_per_cluster.is_power_of_two() {
+        return Err(FatError::InvalidBootSector(
+            "Sectors per cluster is not a power of 2",
+        ));
+    }
+    if bpb.reserved_clusters == 0 {
+        return Err(FatError::InvalidBootSector("No reserved sectors"));
+    }
+    if bpb.num_fats < 2 {
+        return Err(FatError::InvalidBootSector("Fewer than 2 FATs"));
+    }
+    Ok(())
 }
 
 impl FatBs {
@@ -213,16 +251,14 @@ impl Fat32Ebpb {
 }
 
 // Determines FAT type
-fn determine_fat_type(fat: &Fat) -> (u32, FatType) {
+fn determine_fat_type(fat: &Fat) -> Result<(u32, FatType), FatError> {
     // Find count of sectors occupied by root directory
     let root_dir_sectors = root_dir_sectors(fat);
 
     // Find FAT size
-    let fat_size: u32;
-    if fat.bpb.fat_size_16 != 0 {
-        fat_size = fat.bpb.fat_size_16.into();
-    } else {
-        fat_size = fat.ebpb32.as_ref().unwrap().fat_size_32;
+    let fat_size = calculate_fat_size(fat);
+    if fat_size == 0 {
+        return Err(FatError::InvalidBootSector("FAT size is 0"));
     }
 
     // Find total number of sectors
@@ -234,20 +270,25 @@ fn determine_fat_type(fat: &Fat) -> (u32, FatType) {
     }
 
     // Find count of sectors in data region
-    let data_sectors: u32 = total_sectors
-        - (fat.bpb.reserved_clusters as u32
-            + (fat.bpb.num_fats as u32 * fat_size)
-            + root_dir_sectors as u32);
+    let overhead = fat.bpb.reserved_clusters as u64
+        + (fat.bpb.num_fats as u64 * fat_size as u64)
+        + root_dir_sectors as u64;
+    if overhead > total_sectors as u64 {
+        return Err(FatError::InvalidBootSector(
+            "Reserved sectors and FATs exceed total sectors",
+        ));
+    }
+    let data_sectors: u32 = total_sectors - overhead as u32;
 
     // Determine count of clusters
     let cluster_count: u32 = data_sectors / fat.bpb.sectors_per_cluster as u32;
 
     // Determine type
     if cluster_count < 4085 {
-        (cluster_count, Fat12)
+        Ok((cluster_count, Fat12))
     } else if cluster_count < 65525 {
-        (cluster_count, Fat16)
+        Ok((cluster_count, Fat16))
     } else {
-        (cluster_count, Fat32)
+        Ok((cluster_count, Fat32))
     }
 }
@@ -0,0 +1,178 @@
+use super::{
+    cluster_chain, cluster_size, first_sector_of_cluster, free_extents, is_dir,
+    read_cluster, Fat, FatDirectoryEntryContainer, FatError, FatExtent,
+};
+
+/// Count of bytes between the end of file and the end of its last cluster
+/// Directories record no size and have no slack
+pub fn slack_size(fat: &Fat, entry: &FatDirectoryEntryContainer) -> u32 {
+    if is_dir(entry) || entry.size() == 0 {
+        return 0;
+    }
+    let cluster_size = cluster_size(fat);
+    (cluster_size - entry.size() % cluster_size) % cluster_size
+}
+
+/// Reads up to size bytes from offset of slack of file with inode
+/// Files whose chain is shorter than their size have no slack
+pub fn get_slack(
+    fat: &mut Fat,
+    ino: u32,
+    offset: u64,
+    size: u32,
+) -> Result<Option<Vec<u8>>, FatError> {
+    let (file_size, slack_size) = match fat.get_inode(ino) {
+        None => return Ok(None),
+        Some(entry) => (entry.size(), slack_size(fat, entry)),
+    };
+    if offset >= slack_size as u64 {
+        return Ok(Some(vec![]));
+    }
+
+    let cluster_size = cluster_size(fat);
+    let last = ((file_size - 1) / cluster_size) as usize;
+    let cluster = match cluster_chain(fat, ino)?.get(last) {
+        None => return Ok(Some(vec![])),
+        Some(cluster) => *cluster,
+    };
+    let sector_number = first_sector_of_cluster(fat, cluster);
+    let data = read_cluster(fat, sector_number);
+    let head = (file_size % cluster_size) as usize + offset as usize;
+    let tail = (head + size as usize).min(data.len());
+    Ok(Some(data[head..tail].to_vec()))
+}
+
+/// Runs of free clusters, kept until the FAT changes
+fn cached_free_extents(fat: &mut Fat) -> Vec<FatExtent> {
+    if fat.free_cache.is_none() {
+        fat.free_cache = Some(free_extents(fat));
+    }
+    fat.free_cache.clone().unwrap()
+}
+
+/// Count of bytes in free clusters
+pub fn unallocated_size(fat: &mut Fat) -> u64 {
+    let free: u64 = cached_free_extents(fat)
+        .iter()
+        .map(|extent| extent.length as u64)
+        .sum();
+    free * cluster_size(fat) as u64
+}
+
+/// Reads up to size bytes from offset of the free clusters, one after the
+/// other in cluster order
+pub fn read_unallocated(
+    fat: &mut Fat,
+    offset: u64,
+    size: u32,
+) -> Result<Vec<u8>, FatError> {
+    let cluster_size = cluster_size(fat) as u64;
+    let end = offset + size as u64;
+    let mut data = vec![];
+    // Position of the first cluster of extent among the free clusters
+    let mut position = 0;
+    for extent in cached_free_extents(fat) {
+        if position >= end {
+            break;
+        }
+        let extent_end = position + extent.length as u64 * cluster_size;
+        if extent_end > offset {
+            let first = offset.max(position);
+            let last = end.min(extent_end);
+            for index in (first - position) / cluster_size
+                ..(last - position).div_ceil(cluster_size)
+            {
+                let sector_number =
+                    first_sector_of_cluster(fat, extent.start + index as u32);
+                let cluster = read_cluster(fat, sector_number);
+                let cluster_start = position + index * cluster_size;
+                let head = first.max(cluster_start) - cluster_start;
+                let tail =
+                    last.min(cluster_start + cluster_size) - cluster_start;
+                data.extend(&cluster[head as usize..tail as usize]);
+            }
+        }
+        position = extent_end;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{pattern, volumes, STAMP};
+    use super::super::{cluster_chain, write_cluster};
+
+    #[test]
+    fn reads_slack_past_end_of_files() {
+        for mut fat in volumes("slack") {
+            let cluster_size = fat.cluster_size() as usize;
+            let data = pattern(cluster_size + 100, 1);
+            fat.write_file("/file", &data, &STAMP, &STAMP).unwrap();
+            fat.write_file("/full", &data[..cluster_size], &STAMP, &STAMP)
+                .unwrap();
+            fat.write_file("/empty", b"", &STAMP, &STAMP).unwrap();
+            fat.create_dir("/dir", &STAMP).unwrap();
+
+            // Leftovers of an earlier file in the last cluster
+            let ino = fat.entry_at("/file").unwrap().unwrap().cluster_number();
+            let last = cluster_chain(&fat, ino).unwrap()[1];
+            let leftover = pattern(cluster_size, 9);
+            let mut cluster = data[cluster_size..].to_vec();
+            cluster.extend_from_slice(&leftover[100..]);
+            write_cluster(&mut fat, last, &cluster).unwrap();
+
+            // Inodes are known once their directory is listed
+            let root = fat.get_root_cluster_number();
+            fat.list_directory(root).unwrap();
+            for (path, size) in [("/file", cluster_size - 100), ("/full", 0)] {
+                let entry = fat.entry_at(path).unwrap().unwrap();
+                assert_eq!(fat.slack_size(&entry), size as u32, "{}", path);
+            }
+            for path in ["/empty", "/dir"] {
+                let entry = fat.entry_at(path).unwrap().unwrap();
+                assert_eq!(fat.slack_size(&entry), 0, "{}", path);
+            }
+            let slack = fat.get_slack(ino, 0, 4096).unwrap().unwrap();
+            assert_eq!(slack, leftover[100..]);
+            let slack = fat.get_slack(ino, 10, 5).unwrap().unwrap();
+            assert_eq!(slack, leftover[110..115]);
+            let end = (cluster_size - 100) as u64;
+            assert!(fat.get_slack(ino, end, 10).unwrap().unwrap().is_empty());
+            assert_eq!(fat.get_slack(12345, 0, 10), Ok(None));
+        }
+    }
+
+    #[test]
+    fn reads_free_clusters_one_after_the_other() {
+        for mut fat in volumes("unallocated") {
+            let cluster_size = fat.cluster_size() as usize;
+            let free_before = fat.unallocated_size();
+            for (seed, name) in ["/a", "/b", "/c"].iter().enumerate() {
+                let data = pattern(cluster_size, seed as u8);
+                fat.write_file(name, &data, &STAMP, &STAMP).unwrap();
+            }
+            let b = fat.entry_at("/b").unwrap().unwrap().cluster_number();
+            fat.remove("/b").unwrap();
+            let size = fat.unallocated_size();
+            assert_eq!(size, free_before - 2 * cluster_size as u64);
+
+            // The cluster of "/b" comes first, followed by the clusters
+            // past "/c", which were never written
+            let head = fat.read_unallocated(0, cluster_size as u32).unwrap();
+            assert_eq!(head, pattern(cluster_size, 1));
+            let offset = cluster_size as u64 - 4;
+            let span = fat.read_unallocated(offset, 8).unwrap();
+            assert_eq!(span[..4], pattern(cluster_size, 1)[cluster_size - 4..]);
+            assert_eq!(span[4..], [0; 4]);
+            assert_eq!(fat.read_unallocated(size - 3, 10).unwrap().len(), 3);
+            assert!(fat.read_unallocated(size, 10).unwrap().is_empty());
+
+            // Changes of the FAT are picked up
+            fat.next_free = b;
+            fat.write_file("/d", b"d", &STAMP, &STAMP).unwrap();
+            assert_eq!(fat.unallocated_size(), size - cluster_size as u64);
+            let head = fat.read_unallocated(0, 4).unwrap();
+            assert_eq!(head, [0; 4]);
+        }
+    }
+}
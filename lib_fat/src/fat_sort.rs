@@ -0,0 +1,255 @@
+use std::cmp::Ordering;
+
+use super::{
+    dir_at, get_dir, write_entries, Fat, FatDirectoryEntryContainer, FatError,
+    FatFileType,
+};
+
+/// Order to sort directory entries in
+#[derive(PartialEq, Debug, Clone)]
+pub enum FatSortOrder {
+    // Names by their bytes, so upper case sorts before lower case
+    Ascii,
+    // Names ignoring case, with runs of digits compared by their value
+    Natural,
+    // Modification times, oldest first, then names in natural order
+    Modified,
+    // Names listed first, in the order given, others follow as they were
+    List(Vec<String>),
+}
+
+/// Splits name into runs of digits and runs of other characters
+fn chunks(name: &str) -> Vec<String> {
+    let mut chunks: Vec<String> = vec![];
+    let mut last_digit = None;
+    for c in name.chars() {
+        let digit = c.is_ascii_digit();
+        match chunks.last_mut() {
+            Some(chunk) if last_digit == Some(digit) => chunk.push(c),
+            _ => chunks.push(c.to_string()),
+        }
+        last_digit = Some(digit);
+    }
+    chunks
+}
+
+/// Compares names ignoring case, runs of digits by their value
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    for (x, y) in chunks(a).iter().zip(chunks(b).iter()) {
+        let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+        let ordering = if digits(x) && digits(y) {
+            let x = x.trim_start_matches('0');
+            let y = y.trim_start_matches('0');
+            x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+        } else {
+            x.to_lowercase().cmp(&y.to_lowercase())
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    chunks(a).len().cmp(&chunks(b).len()).then_with(|| a.cmp(b))
+}
+
+/// Position of entry in list, matching long or short name ignoring case
+fn list_position(list: &[String], entry: &FatDirectoryEntryContainer) -> usize {
+    let name = entry.get_name().to_lowercase();
+    let short_name = entry.get_short_name().to_lowercase();
+    list.iter()
+        .position(|listed| {
+            let listed = listed.to_lowercase();
+            listed == name || listed == short_name
+        })
+        .unwrap_or(list.len())
+}
+
+/// Compares entries in order
+fn compare(
+    order: &FatSortOrder,
+    a: &FatDirectoryEntryContainer,
+    b: &FatDirectoryEntryContainer,
+) -> Ordering {
+    match order {
+        FatSortOrder::Ascii => a.get_name().cmp(b.get_name()),
+        FatSortOrder::Natural => natural_cmp(a.get_name(), b.get_name()),
+        FatSortOrder::Modified => a
+            .get_write_time()
+            .cmp(&b.get_write_time())
+            .then_with(|| natural_cmp(a.get_name(), b.get_name())),
+        // Sorting is stable, unlisted entries keep their order
+        FatSortOrder::List(list) => {
+            list_position(list, a).cmp(&list_position(list, b))
+        }
+    }
+}
+
+/// Whether entry keeps its place at the start, such as "." and the label
+fn is_fixed(entry: &FatDirectoryEntryContainer) -> bool {
+    entry.attribute() & FatFileType::AttrVolumeId as u8 != 0
+        || entry.get_name() == "."
+        || entry.get_name() == ".."
+}
+
+/// Rewrites entries of directory at path in order, long name entries stay
+/// with their short entry and free slots are dropped
+pub fn sort_directory(
+    fat: &mut Fat,
+    path: &str,
+    order: &FatSortOrder,
+) -> Result<(), FatError> {
+    let inode = dir_at(fat, path)?;
+    let mut entries: Vec<FatDirectoryEntryContainer> =
+        get_dir(fat, inode)?.ok_or(FatError::NotFound)?.clone();
+    // Fixed entries keep their order in front of the others
+    entries.sort_by(|a, b| match (is_fixed(a), is_fixed(b)) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => compare(order, a, b),
+    });
+    write_entries(fat, inode, &entries, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::super::fat_test::{pattern, volumes, Scratch, STAMP};
+    use super::super::{FatDateTime, FatFileType};
+    use super::{natural_cmp, FatSortOrder};
+
+    /// Names written, in the order written
+    const NAMES: [&str; 6] = [
+        "file10.txt",
+        "File2.txt",
+        "alpha",
+        "file1.txt",
+        "Beta",
+        "file02.txt",
+    ];
+
+    /// Names of directory at path in the order of their entries, along with
+    /// whether the first entry is the volume label
+    fn listing(fat: &mut Scratch, path: &str) -> (Vec<String>, bool) {
+        let inode = match fat.entry_at(path).unwrap() {
+            None => fat.get_root_cluster_number(),
+            Some(entry) => entry.cluster_number(),
+        };
+        let entries = fat.list_directory(inode).unwrap().unwrap();
+        let label = FatFileType::AttrVolumeId as u8;
+        let labelled = entries[0].attribute() & label != 0;
+        let names = entries
+            .iter()
+            .filter(|entry| entry.attribute() & label == 0)
+            .map(|entry| entry.get_name().clone())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        (names, labelled)
+    }
+
+    #[test]
+    fn compares_naturally() {
+        let less = [
+            ("file2", "file10"),
+            ("file9.txt", "file10.txt"),
+            ("abc", "abd"),
+            ("a", "a1"),
+            ("a01", "a1"),
+            ("File1", "file1"),
+            ("alpha", "Beta"),
+            ("10", "z"),
+            ("x0099", "x100"),
+        ];
+        for (a, b) in less {
+            assert_eq!(natural_cmp(a, b), Ordering::Less, "{} {}", a, b);
+            assert_eq!(natural_cmp(b, a), Ordering::Greater, "{} {}", b, a);
+        }
+        assert_eq!(natural_cmp("same2", "same2"), Ordering::Equal);
+    }
+
+    #[test]
+    fn sorts_directories() {
+        let orders = [
+            (
+                FatSortOrder::Natural,
+                [
+                    "alpha",
+                    "Beta",
+                    "file1.txt",
+                    "File2.txt",
+                    "file02.txt",
+                    "file10.txt",
+                ],
+            ),
+            (
+                FatSortOrder::Ascii,
+                [
+                    "Beta",
+                    "File2.txt",
+                    "alpha",
+                    "file02.txt",
+                    "file1.txt",
+                    "file10.txt",
+                ],
+            ),
+            // Oldest first, as written after the first
+            (
+                FatSortOrder::Modified,
+                [
+                    "file02.txt",
+                    "Beta",
+                    "file1.txt",
+                    "alpha",
+                    "File2.txt",
+                    "file10.txt",
+                ],
+            ),
+            // Listed names first, others keep the order they had
+            (
+                FatSortOrder::List(vec!["FILE1.TXT".into(), "BETA".into()]),
+                [
+                    "file1.txt",
+                    "Beta",
+                    "file02.txt",
+                    "alpha",
+                    "File2.txt",
+                    "file10.txt",
+                ],
+            ),
+        ];
+        for mut fat in volumes("sort") {
+            fat.create_dir("/dir", &STAMP).unwrap();
+            for dir in ["", "/dir"] {
+                for (index, name) in NAMES.iter().enumerate() {
+                    // Written later, modified earlier
+                    let modified = FatDateTime {
+                        minute: 50 - index as u8,
+                        ..STAMP
+                    };
+                    let path = format!("{}/{}", dir, name);
+                    let data = pattern(index * 300, index as u8);
+                    fat.write_file(&path, &data, &STAMP, &modified).unwrap();
+                }
+            }
+
+            for (order, expected) in &orders {
+                for dir in ["/", "/dir"] {
+                    fat.sort_directory(dir, order).unwrap();
+                }
+                fat.remount();
+                // The directory sorts among the files, the label stays first
+                let (mut names, labelled) = listing(&mut fat, "/");
+                assert!(labelled, "{:?}", order);
+                names.retain(|name| name != "dir");
+                assert_eq!(names, *expected, "{:?}", order);
+                let (names, _) = listing(&mut fat, "/dir");
+                assert_eq!(names, *expected, "{:?}", order);
+            }
+            for (index, name) in NAMES.iter().enumerate() {
+                let data = pattern(index * 300, index as u8);
+                assert_eq!(fat.contents(&format!("/dir/{}", name)), data);
+            }
+            fat.check();
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::fmt;
 
 /// FAT type
@@ -130,7 +131,7 @@ pub struct Fat32FsInfo {
 
 /// FAT directory structure
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FatDirectoryEntry {
     // 0: Short name
     // If name[0]==0xE5, entry is free
@@ -173,7 +174,7 @@ pub enum FatFileType {
 
 /// FAT long directory structure
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FatLongDirectoryEntry {
     // 0: Order
     pub(crate) order: u8,
@@ -196,10 +197,125 @@ pub struct FatLongDirectoryEntry {
 /// FAT directory entry container
 /// For file, there must be 1 short entry and possibly multiple long entries
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FatDirectoryEntryContainer {
     pub(crate) short_entry: FatDirectoryEntry,
     pub(crate) long_entries: Vec<FatLongDirectoryEntry>,
     pub(crate) cached_name: String,
-    pub(crate) cached_cluster_count: u32,
+    // Derived from size for files, walked lazily for directories
+    pub(crate) cached_cluster_count: Cell<Option<u32>>,
+    // Byte offset of short entry within directory data
+    pub(crate) offset: usize,
+}
+
+/// Date and time to record in a directory entry, in the zone of the volume
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct FatDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    // Hundredths of the second
+    pub centiseconds: u8,
+}
+
+/// Run of consecutive clusters
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct FatExtent {
+    // First cluster of the run
+    pub start: u32,
+    // Count of clusters
+    pub length: u32,
+}
+
+/// Entry found in a directory after it was removed
+#[derive(Debug, Clone)]
+pub struct FatDeletedEntry {
+    // Entry as it would be restored, with "?" for a first character not known
+    pub entry: FatDirectoryEntryContainer,
+    // Whether the first character of the short name could be worked out
+    pub first_char_known: bool,
+    // Free clusters likely to hold the contents, None once they are reused
+    pub clusters: Option<Vec<u32>>,
+    // Inode of directory holding the entry
+    pub(crate) parent: u32,
+}
+
+/// Writes field name and value on its own line
+macro_rules! write_field {
+    ($f:expr, $name:expr, $value:expr) => {
+        writeln!($f, "{:<24}{}", concat!($name, ":"), $value)
+    };
+}
+
+/// Formats bytes as text, dropping padding
+fn padded_text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end().to_string()
+}
+
+/// Formats volume ID as serial number, such as "1234-ABCD"
+fn volume_serial(volume_id: &[u8; 4]) -> String {
+    let serial = u32::from_le_bytes(*volume_id);
+    format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)
+}
+
+impl fmt::Display for FatBpb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_field!(f, "Bytes per sector", self.bytes_per_sector)?;
+        write_field!(f, "Sectors per cluster", self.sectors_per_cluster)?;
+        write_field!(f, "Reserved sectors", self.reserved_clusters)?;
+        write_field!(f, "Number of FATs", self.num_fats)?;
+        write_field!(f, "Root entry count", self.root_entry_count)?;
+        write_field!(f, "Total sectors (16)", self.total_sectors_16)?;
+        write_field!(
+            f,
+            "Media descriptor",
+            format!("0x{:02X}", self.media_descriptor)
+        )?;
+        write_field!(f, "Sectors per FAT (16)", self.fat_size_16)?;
+        write_field!(f, "Sectors per track", self.sectors_per_track)?;
+        write_field!(f, "Number of heads", self.heads)?;
+        write_field!(f, "Hidden sectors", self.hidden_sectors_count)?;
+        write_field!(f, "Total sectors (32)", self.total_sectors_32)
+    }
+}
+
+impl fmt::Display for FatEbpb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_field!(
+            f,
+            "Drive number",
+            format!("0x{:02X}", self.drive_number)
+        )?;
+        write_field!(
+            f,
+            "Boot signature",
+            format!("0x{:02X}", self.boot_signature)
+        )?;
+        write_field!(f, "Volume ID", volume_serial(&self.volume_id))?;
+        write_field!(f, "Volume label", padded_text(&self.volume_label))?;
+        write_field!(f, "Filesystem type", padded_text(&self.fs_type))
+    }
+}
+
+impl fmt::Display for Fat32Ebpb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_field!(f, "Sectors per FAT (32)", self.fat_size_32)?;
+        write_field!(f, "Flags", format!("0x{:04X}", self.flags))?;
+        write_field!(f, "Version", format!("0x{:04X}", self.version))?;
+        write_field!(f, "Root cluster", self.root_cluster)?;
+        write_field!(f, "FSInfo sector", self.fsinfo_sector)?;
+        write_field!(f, "Backup boot sector", self.backup_sector)?;
+        write_field!(
+            f,
+            "Drive number",
+            format!("0x{:02X}", self.drive_number)
+        )?;
+        write_field!(f, "Signature", format!("0x{:02X}", self.signature))?;
+        write_field!(f, "Volume ID", volume_serial(&self.volume_id))?;
+        write_field!(f, "Volume label", padded_text(&self.volume_label))?;
+        write_field!(f, "Filesystem type", padded_text(&self.fs_type))
+    }
 }
@@ -0,0 +1,275 @@
+use std::io::Write;
+
+use super::{
+    cluster_chain, cluster_size, first_sector_of_cluster, is_dir, read_cluster,
+    Fat, FatDirectoryEntryContainer, FatError, FatFileType,
+};
+
+/// Size of tar headers and the unit file data is padded to
+const BLOCK_SIZE: usize = 512;
+/// Longest name the name field of a ustar header holds
+const NAME_SIZE: usize = 100;
+/// Name of extended headers, as written by GNU tar and Python
+const PAX_HEADER_NAME: &str = "././@PaxHeader";
+
+/// Type flags of headers
+const REGULAR_FILE: u8 = b'0';
+const DIRECTORY: u8 = b'5';
+const PAX_HEADER: u8 = b'x';
+
+/// Writes octal number into field, terminated by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    // Fields are wide enough for any size and time stamp of FAT
+    let digits = digits.as_bytes();
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+/// Builds ustar header, name must fit the name field
+fn header(name: &[u8], mode: u32, size: u64, mtime: u64, kind: u8) -> Vec<u8> {
+    let mut header = vec![0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name);
+    write_octal(&mut header[100..108], mode as u64);
+    // Owner is not recorded on FAT
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // Checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    write_octal(&mut header[148..155], checksum as u64);
+    header[155] = b' ';
+    header
+}
+
+/// Formats PAX record, whose length counts the digits of the length itself
+fn pax_record(key: &str, value: &str) -> String {
+    let content = format!(" {}={}\n", key, value);
+    let mut length = content.len() + 1;
+    while length != content.len() + length.to_string().len() {
+        length = content.len() + length.to_string().len();
+    }
+    format!("{}{}", length, content)
+}
+
+/// Writes data followed by zeros up to the next block
+fn write_padded<W: Write>(writer: &mut W, data: &[u8]) -> Result<(), FatError> {
+    writer.write_all(data)?;
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    writer.write_all(&vec![0u8; padding])?;
+    Ok(())
+}
+
+/// Writes headers of entry, preceded by a PAX header if the name needs one
+fn write_header<W: Write>(
+    writer: &mut W,
+    name: &str,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    kind: u8,
+) -> Result<(), FatError> {
+    let short_enough = name.len() <= NAME_SIZE;
+    if short_enough && name.is_ascii() {
+        return writer
+            .write_all(&header(name.as_bytes(), mode, size, mtime, kind))
+            .map_err(FatError::from);
+    }
+
+    let records = pax_record("path", name);
+    writer.write_all(&header(
+        PAX_HEADER_NAME.as_bytes(),
+        0o644,
+        records.len() as u64,
+        mtime,
+        PAX_HEADER,
+    ))?;
+    write_padded(writer, records.as_bytes())?;
+
+    // Readers without PAX support see an ASCII approximation of the name
+    let fallback: Vec<u8> = name
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'_' })
+        .collect();
+    let fallback = &fallback[fallback.len().saturating_sub(NAME_SIZE)..];
+    writer.write_all(&header(fallback, mode, size, mtime, kind))?;
+    Ok(())
+}
+
+/// Permissions of entry, read-only entries lose their write bits
+fn mode(entry: &FatDirectoryEntryContainer) -> u32 {
+    let mode = if is_dir(entry) { 0o755 } else { 0o644 };
+    if entry.attribute() & FatFileType::AttrReadOnly as u8 != 0 {
+        mode & !0o222
+    } else {
+        mode
+    }
+}
+
+/// Writes tar archive of entries below path, named by their path in the volume
+/// Modification times are unix timestamps given by mtime
+pub fn export_tar<W, F>(
+    fat: &mut Fat,
+    path: &str,
+    mut writer: W,
+    mtime: F,
+) -> Result<(), FatError>
+where
+    W: Write,
+    F: Fn(&FatDirectoryEntryContainer) -> i64,
+{
+    for (entry_path, entry) in fat.walk(path)? {
+        let name = entry_path.trim_start_matches('/');
+        let entry_mtime = mtime(&entry).max(0) as u64;
+        if is_dir(&entry) {
+            let name = format!("{}/", name);
+            write_header(
+                &mut writer,
+                &name,
+                mode(&entry),
+                0,
+                entry_mtime,
+                DIRECTORY,
+            )?;
+            continue;
+        }
+
+        // Files whose chain is shorter than their size are cut short
+        let chain = if entry.size() == 0 {
+            vec![]
+        } else {
+            cluster_chain(fat, entry.cluster_number())?
+        };
+        let cluster_size = cluster_size(fat) as u64;
+        let size = (entry.size() as u64).min(chain.len() as u64 * cluster_size);
+        write_header(
+            &mut writer,
+            name,
+            mode(&entry),
+            size,
+            entry_mtime,
+            REGULAR_FILE,
+        )?;
+
+        let mut remaining = size as usize;
+        for cluster in chain {
+            if remaining == 0 {
+                break;
+            }
+            let sector_number = first_sector_of_cluster(fat, cluster);
+            let data = read_cluster(fat, sector_number);
+            let length = remaining.min(data.len());
+            writer.write_all(&data[..length])?;
+            remaining -= length;
+        }
+        let padding = (BLOCK_SIZE - size as usize % BLOCK_SIZE) % BLOCK_SIZE;
+        writer.write_all(&vec![0u8; padding])?;
+    }
+
+    // Archive ends with two zeroed blocks
+    writer.write_all(&[0u8; 2 * BLOCK_SIZE])?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{pattern, Scratch, STAMP};
+    use super::super::FatType;
+    use super::{pax_record, BLOCK_SIZE, DIRECTORY, PAX_HEADER, REGULAR_FILE};
+
+    /// Reads octal number of field, up to a NUL or space
+    fn octal(field: &[u8]) -> u64 {
+        let digits: String = field
+            .iter()
+            .take_while(|b| **b != 0 && **b != b' ')
+            .map(|b| *b as char)
+            .collect();
+        u64::from_str_radix(&digits, 8).unwrap()
+    }
+
+    /// Entries of archive as (name, type flag, contents), with names taken
+    /// from PAX headers where given
+    fn read_archive(archive: &[u8]) -> Vec<(String, u8, Vec<u8>)> {
+        assert_eq!(archive.len() % BLOCK_SIZE, 0);
+        let mut entries = vec![];
+        let mut pax_path = None;
+        let mut offset = 0;
+        loop {
+            let header = &archive[offset..offset + BLOCK_SIZE];
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            let mut blank = header.to_vec();
+            blank[148..156].copy_from_slice(b"        ");
+            let checksum: u64 = blank.iter().map(|b| *b as u64).sum();
+            assert_eq!(octal(&header[148..156]), checksum);
+            assert_eq!(&header[257..265], b"ustar\x0000");
+
+            let size = octal(&header[124..136]) as usize;
+            let data = archive[offset + BLOCK_SIZE..][..size].to_vec();
+            offset += BLOCK_SIZE + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            let name_length = header[..100].iter().position(|b| *b == 0);
+            let name = &header[..name_length.unwrap_or(100)];
+            let name = String::from_utf8(name.to_vec()).unwrap();
+            if header[156] == PAX_HEADER {
+                let records = String::from_utf8(data).unwrap();
+                let (length, record) = records.split_once(' ').unwrap();
+                assert_eq!(length.parse::<usize>().unwrap(), records.len());
+                let path = record.strip_prefix("path=").unwrap();
+                pax_path = Some(path.trim_end_matches('\n').to_string());
+                continue;
+            }
+            entries.push((pax_path.take().unwrap_or(name), header[156], data));
+        }
+        // Archive ends with two zeroed blocks
+        assert_eq!(archive.len(), offset + 2 * BLOCK_SIZE);
+        entries
+    }
+
+    #[test]
+    fn formats_pax_records() {
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+        // Length grows by a digit as the record reaches 10 bytes
+        assert_eq!(pax_record("path", "ab"), "11 path=ab\n");
+        let long = "x".repeat(90);
+        assert_eq!(pax_record("path", &long), format!("99 path={}\n", long));
+        // A length of 100 would not count its third digit
+        let long = "x".repeat(91);
+        assert_eq!(pax_record("path", &long), format!("101 path={}\n", long));
+    }
+
+    #[test]
+    fn exports_entries_with_long_and_unicode_names() {
+        let mut fat = Scratch::new("tar", 4 << 20, FatType::Fat16);
+        let data = pattern(1300, 3);
+        let long = "a directory name long enough".repeat(3);
+        let deep = format!("/{}/{}", long, "file in a long path.txt");
+        fat.write_file("/short.txt", &data, &STAMP, &STAMP).unwrap();
+        fat.write_file("/ünïcödé.txt", b"unicode", &STAMP, &STAMP)
+            .unwrap();
+        fat.create_dir(&format!("/{}", long), &STAMP).unwrap();
+        fat.write_file(&deep, b"deep", &STAMP, &STAMP).unwrap();
+
+        let mut archive = vec![];
+        fat.export_tar("/", &mut archive, |_| 1_582_983_462)
+            .unwrap();
+        let mut entries = read_archive(&archive);
+        entries.sort();
+        let mut expected = vec![
+            ("short.txt".to_string(), REGULAR_FILE, data),
+            ("ünïcödé.txt".to_string(), REGULAR_FILE, b"unicode".to_vec()),
+            (format!("{}/", long), DIRECTORY, vec![]),
+            (deep[1..].to_string(), REGULAR_FILE, b"deep".to_vec()),
+        ];
+        expected.sort();
+        assert_eq!(entries, expected);
+        assert!(deep.len() > 100);
+    }
+}
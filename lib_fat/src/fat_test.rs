@@ -0,0 +1,169 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::{Deref, DerefMut};
+
+use super::fat_helper::fat_entry;
+use super::{
+    calculate_fat_size, cluster_chain, is_dir, Fat, FatDateTime, FatFormat,
+    FatType,
+};
+
+/// Time stamp of everything tests write
+pub const STAMP: FatDateTime = FatDateTime {
+    year: 2020,
+    month: 2,
+    day: 29,
+    hour: 13,
+    minute: 37,
+    second: 42,
+    centiseconds: 0,
+};
+
+/// Image formatted for a test, removed again when dropped
+pub struct Scratch {
+    pub fat: Fat,
+    pub path: String,
+}
+
+impl Scratch {
+    /// Formats image of size in bytes, named after test so tests running at
+    /// the same time do not share images
+    pub fn new(test: &str, size: u64, fat_type: FatType) -> Scratch {
+        let path = std::env::temp_dir()
+            .join(format!("lib_fat-{}-{}.img", test, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let format = FatFormat {
+            size,
+            fat_type: Some(fat_type),
+            label: Some("SCRATCH".to_string()),
+            volume_id: 0x1234_5678,
+            root_slots: 0,
+            created: STAMP,
+        };
+        let fat = Fat::format(&path, &format).unwrap();
+        Scratch { fat, path }
+    }
+
+    /// Writes out the FAT and opens the image afresh, dropping all caches
+    pub fn remount(&mut self) {
+        self.fat.flush().unwrap();
+        self.fat = Fat::mount_volume_rw(&self.path).unwrap();
+    }
+
+    /// Contents of file at path
+    pub fn contents(&mut self, path: &str) -> Vec<u8> {
+        let entry = self.fat.entry_at(path).unwrap().unwrap();
+        if entry.size() == 0 {
+            return vec![];
+        }
+        let inode = entry.cluster_number();
+        self.fat.get_data(inode, 0, entry.size()).unwrap().unwrap()
+    }
+
+    /// Reads bytes of the image file from offset
+    fn read_image(&self, offset: u64, length: usize) -> Vec<u8> {
+        let mut file = File::open(&self.path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        let mut data = vec![0; length];
+        file.read_exact(&mut data).unwrap();
+        data
+    }
+
+    /// Flushes changes and checks the volume as a file system checker would
+    /// Clusters in use belong to exactly one entry, chains fit the sizes of
+    /// files, dot entries point at the right directories, all FATs agree and
+    /// FSInfo of FAT32 holds the count of free clusters
+    pub fn check(&mut self) {
+        self.fat.flush().unwrap();
+        let fat = &mut self.fat;
+        let root = fat.get_root_cluster_number();
+        let mut chains = vec![cluster_chain(fat, root).unwrap()];
+        let mut dirs = HashMap::new();
+        dirs.insert(String::new(), 0);
+        for (path, entry) in fat.walk("/").unwrap() {
+            assert!(fat.verify_cluster_count(&entry).unwrap(), "{}", path);
+            chains.push(cluster_chain(fat, entry.cluster_number()).unwrap());
+            if is_dir(&entry) {
+                dirs.insert(path, entry.cluster_number());
+            }
+        }
+        let mut owned = HashSet::new();
+        for cluster in chains.into_iter().flatten() {
+            assert!(owned.insert(cluster), "cluster {} is shared", cluster);
+        }
+        for cluster in 2..fat.cluster_count + 2 {
+            let used = fat_entry(fat, cluster) != 0;
+            assert_eq!(used, owned.contains(&cluster), "cluster {}", cluster);
+        }
+
+        for (path, cluster) in dirs.iter().filter(|(path, _)| !path.is_empty())
+        {
+            let parent = &path[..path.rfind('/').unwrap()];
+            let children = fat.list_directory(*cluster).unwrap().unwrap();
+            let dots: Vec<(String, u32)> = children
+                .iter()
+                .take(2)
+                .map(|child| (child.get_name().clone(), child.cluster_number()))
+                .collect();
+            let expected = [(".", *cluster), ("..", dirs[parent])];
+            let expected = expected.map(|(name, c)| (name.to_string(), c));
+            assert_eq!(dots, expected, "{}", path);
+        }
+
+        let bytes_per_sector = fat.bpb.bytes_per_sector as u64;
+        let fat_size = calculate_fat_size(fat) as u64 * bytes_per_sector;
+        let first = fat.bpb.reserved_clusters as u64 * bytes_per_sector;
+        let num_fats = fat.bpb.num_fats as u64;
+        let free = fat.cluster_count - owned.len() as u32;
+        let fsinfo = fat.ebpb32.as_ref().map(|ebpb| ebpb.fsinfo_sector);
+        let copies: Vec<Vec<u8>> = (0..num_fats)
+            .map(|i| self.read_image(first + i * fat_size, fat_size as usize))
+            .collect();
+        assert!(copies.windows(2).all(|pair| pair[0] == pair[1]));
+        if let Some(sector) = fsinfo {
+            let offset = sector as u64 * bytes_per_sector + 488;
+            let count = self.read_image(offset, 4);
+            assert_eq!(u32::from_le_bytes(count.try_into().unwrap()), free);
+        }
+    }
+}
+
+/// Volumes of each FAT type, the smallest sizes giving clusters of a sector
+pub fn volumes(test: &str) -> Vec<Scratch> {
+    vec![
+        Scratch::new(&format!("{}-12", test), 1 << 20, FatType::Fat12),
+        Scratch::new(&format!("{}-16", test), 4 << 20, FatType::Fat16),
+        Scratch::new(&format!("{}-32", test), 40 << 20, FatType::Fat32),
+    ]
+}
+
+/// Bytes of a file of length, different for each seed
+pub fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+impl Deref for Scratch {
+    type Target = Fat;
+
+    fn deref(&self) -> &Fat {
+        &self.fat
+    }
+}
+
+impl DerefMut for Scratch {
+    fn deref_mut(&mut self) -> &mut Fat {
+        &mut self.fat
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,263 @@
+use super::{
+    cluster_size, dir_at, fat_entry, find_entry, first_sector_of_cluster,
+    is_dir, link_clusters, read_cluster, read_deleted_entries, read_dir_data,
+    write_dir_data, Fat, FatDeletedEntry, FatDirectoryEntryContainer, FatError,
+    FIRST_CHARACTERS, LAST_LONG_ENTRY,
+};
+
+/// Free clusters likely to hold contents of removed entry
+/// Contents are assumed to start at the first cluster of the entry and take
+/// the following free clusters, as clusters are allocated in that order
+fn guess_clusters(
+    fat: &Fat,
+    entry: &FatDirectoryEntryContainer,
+) -> Option<Vec<u32>> {
+    let cluster_size = cluster_size(fat);
+    // Directories record no size, only their first cluster is recovered
+    let count = if is_dir(entry) {
+        1
+    } else {
+        entry.size().div_ceil(cluster_size)
+    };
+    if count == 0 {
+        return Some(vec![]);
+    }
+
+    let first = entry.cluster_number();
+    let end = fat.cluster_count + 2;
+    if first < 2 || first >= end || fat_entry(fat, first) != 0 {
+        return None;
+    }
+    let clusters: Vec<u32> = (first..end)
+        .filter(|cluster| fat_entry(fat, *cluster) == 0)
+        .take(count as usize)
+        .collect();
+    if (clusters.len() as u32) < count {
+        return None;
+    }
+    Some(clusters)
+}
+
+/// Lists entries removed from directory at path
+pub fn deleted_entries(
+    fat: &mut Fat,
+    path: &str,
+) -> Result<Vec<FatDeletedEntry>, FatError> {
+    let inode = dir_at(fat, path)?;
+    Ok(read_deleted_entries(fat, inode)?
+        .into_iter()
+        .map(|(entry, first_char_known)| FatDeletedEntry {
+            clusters: guess_clusters(fat, &entry),
+            entry,
+            first_char_known,
+            parent: inode,
+        })
+        .collect())
+}
+
+/// Reads up to size bytes from offset of removed entry, from the clusters
+/// guessed for it
+pub fn read_deleted(
+    fat: &mut Fat,
+    deleted: &FatDeletedEntry,
+    offset: u64,
+    size: u32,
+) -> Result<Vec<u8>, FatError> {
+    let clusters = deleted.clusters.as_ref().ok_or(FatError::Overwritten)?;
+    let end = (offset + size as u64).min(deleted.entry.size() as u64);
+    if offset >= end {
+        return Ok(vec![]);
+    }
+
+    let cluster_size = cluster_size(fat) as u64;
+    let mut data = vec![];
+    for index in offset / cluster_size..end.div_ceil(cluster_size) {
+        let sector_number =
+            first_sector_of_cluster(fat, clusters[index as usize]);
+        data.extend(read_cluster(fat, sector_number));
+    }
+    let start = (offset % cluster_size) as usize;
+    Ok(data[start..start + (end - offset) as usize].to_vec())
+}
+
+/// Restores removed entry, whose short name starts with first_char if given
+/// Long name entries are only restored along with the first character they
+/// were recorded with, the clusters guessed for it must still be free
+pub fn undelete(
+    fat: &mut Fat,
+    deleted: &FatDeletedEntry,
+    first_char: Option<u8>,
+) -> Result<(), FatError> {
+    let entry = &deleted.entry;
+    let recorded = entry.short_entry.to_bytes();
+    let first_char = match first_char {
+        Some(c) => c.to_ascii_uppercase(),
+        None if deleted.first_char_known => recorded[0],
+        None => return Err(FatError::InvalidName),
+    };
+    if !FIRST_CHARACTERS.contains(&first_char) {
+        return Err(FatError::InvalidName);
+    }
+    let clusters = guess_clusters(fat, entry).ok_or(FatError::Overwritten)?;
+
+    // Slots must still hold the removed entry
+    let mut data = read_dir_data(fat, deleted.parent)?;
+    let offset = entry.offset;
+    let long_count = entry.long_entries.len();
+    let slot = &data[offset..offset + 32];
+    if slot[0] != 0xE5 || slot[1..] != recorded[1..] {
+        return Err(FatError::Overwritten);
+    }
+    let keep_long = deleted.first_char_known && first_char == recorded[0];
+    if keep_long && (0..long_count).any(|i| data[offset - 32 * (i + 1)] != 0xE5)
+    {
+        return Err(FatError::Overwritten);
+    }
+
+    // Names of the restored entry must be free in the directory
+    let mut short_slot = recorded;
+    short_slot[0] = first_char;
+    let short_name = entry.get_short_name();
+    let mut names = vec![format!("{}{}", first_char as char, &short_name[1..])];
+    if keep_long {
+        names.push(entry.get_name().clone());
+    }
+    for name in names {
+        if find_entry(fat, deleted.parent, &name)?.is_some() {
+            return Err(FatError::AlreadyExists);
+        }
+    }
+
+    link_clusters(fat, &clusters);
+    data[offset..offset + 32].copy_from_slice(&short_slot);
+    if keep_long {
+        // Long entries are numbered down to 1, closest to the short entry
+        for i in 0..long_count {
+            let long_offset = offset - 32 * (long_count - i);
+            data[long_offset] = (long_count - i) as u8;
+            if i == 0 {
+                data[long_offset] |= LAST_LONG_ENTRY;
+            }
+        }
+    }
+    write_dir_data(fat, deleted.parent, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{pattern, volumes, Scratch, STAMP};
+    use super::super::{FatDeletedEntry, FatError};
+
+    /// Removed entry of root directory with name
+    fn deleted(fat: &mut Scratch, name: &str) -> FatDeletedEntry {
+        fat.deleted_entries("/")
+            .unwrap()
+            .into_iter()
+            .find(|deleted| deleted.entry.get_name() == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn restores_removed_files() {
+        for mut fat in volumes("undelete") {
+            let cluster_size = fat.cluster_size() as usize;
+            let data = pattern(3 * cluster_size - 10, 1);
+            fat.write_file("/Long Name.bin", &data, &STAMP, &STAMP)
+                .unwrap();
+            fat.write_file("/after", b"after", &STAMP, &STAMP).unwrap();
+            fat.remove("/Long Name.bin").unwrap();
+            fat.remount();
+
+            let removed = deleted(&mut fat, "Long Name.bin");
+            assert!(removed.first_char_known);
+            assert_eq!(removed.clusters.as_ref().unwrap().len(), 3);
+            let read = fat.read_deleted(&removed, 0, data.len() as u32);
+            assert_eq!(read.unwrap(), data);
+            // Reads spanning clusters stop at the size of the file
+            let read = fat.read_deleted(&removed, 500, 100).unwrap();
+            assert_eq!(read, data[500..600]);
+            let end = data.len() as u64 - 4;
+            assert_eq!(fat.read_deleted(&removed, end, 100).unwrap().len(), 4);
+
+            fat.undelete(&removed, None).unwrap();
+            fat.remount();
+            assert_eq!(fat.contents("/Long Name.bin"), data);
+            assert!(fat.deleted_entries("/").unwrap().is_empty());
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn skips_clusters_in_use() {
+        for mut fat in volumes("undelete-gap") {
+            let cluster_size = fat.cluster_size() as usize;
+            for name in ["/a", "/b", "/c"] {
+                let data = pattern(cluster_size, 0);
+                fat.write_file(name, &data, &STAMP, &STAMP).unwrap();
+            }
+            let start = fat.file_extents("/a").unwrap()[0].start;
+            fat.remove("/a").unwrap();
+            fat.remove("/c").unwrap();
+            // Allocation takes the free clusters either side of "/b"
+            fat.next_free = start;
+            let data = pattern(2 * cluster_size, 2);
+            fat.write_file("/split", &data, &STAMP, &STAMP).unwrap();
+            fat.remove("/split").unwrap();
+
+            let removed = deleted(&mut fat, "split");
+            assert_eq!(removed.clusters, Some(vec![start, start + 2]));
+            fat.undelete(&removed, None).unwrap();
+            fat.remount();
+            assert_eq!(fat.contents("/split"), data);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn needs_first_character_of_short_names() {
+        for mut fat in volumes("undelete-short") {
+            fat.create_dir("/DIR", &STAMP).unwrap();
+            fat.write_file("/DIR/INNER", b"inner", &STAMP, &STAMP)
+                .unwrap();
+            fat.remove("/DIR/INNER").unwrap();
+            fat.remove("/DIR").unwrap();
+
+            let removed = deleted(&mut fat, "?IR");
+            assert!(!removed.first_char_known);
+            let result = fat.undelete(&removed, None);
+            assert_eq!(result, Err(FatError::InvalidName));
+            let result = fat.undelete(&removed, Some(b'/'));
+            assert_eq!(result, Err(FatError::InvalidName));
+            fat.undelete(&removed, Some(b'd')).unwrap();
+            fat.remount();
+
+            // Directories are restored along with their first cluster
+            assert!(fat.entry_at("/DIR").unwrap().is_some());
+            let removed = fat.deleted_entries("/DIR").unwrap();
+            assert_eq!(removed.len(), 1);
+            assert_eq!(removed[0].entry.get_name(), "?NNER");
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn refuses_reused_clusters() {
+        for mut fat in volumes("undelete-reused") {
+            let cluster_size = fat.cluster_size() as usize;
+            let data = pattern(2 * cluster_size, 3);
+            fat.write_file("/old", &data, &STAMP, &STAMP).unwrap();
+            let start = fat.file_extents("/old").unwrap()[0].start;
+            fat.remove("/old").unwrap();
+            fat.next_free = start;
+            fat.write_file("/new", b"new", &STAMP, &STAMP).unwrap();
+
+            let removed = deleted(&mut fat, "old");
+            assert_eq!(removed.clusters, None);
+            let result = fat.read_deleted(&removed, 0, 10);
+            assert_eq!(result, Err(FatError::Overwritten));
+            let result = fat.undelete(&removed, None);
+            assert_eq!(result, Err(FatError::Overwritten));
+            fat.check();
+        }
+    }
+}
@@ -0,0 +1,998 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+use super::{
+    allocate_clusters, chksum, cluster_chain, cluster_size, dir_at,
+    encode_date, encode_time, find_entry, first_root_dir_sector,
+    first_sector_of_cluster, free_clusters, get_dir, is_dir, locate,
+    read_dir_data, read_sector, walk_parent, write_chain, write_cluster,
+    write_sectors, Fat, FatDateTime, FatDirectoryEntryContainer, FatError,
+    FatFileType, FatType, LAST_LONG_ENTRY,
+};
+
+/// Attributes which can be changed on existing entries
+pub const CHANGEABLE_ATTRIBUTES: u8 = FatFileType::AttrReadOnly as u8
+    | FatFileType::AttrHidden as u8
+    | FatFileType::AttrSystem as u8
+    | FatFileType::AttrArchive as u8;
+
+/// Characters which are not allowed in any name
+const INVALID_CHARACTERS: &str = "\"*/:<>?\\|";
+/// Characters besides letters and digits which are allowed in short names
+const SHORT_NAME_CHARACTERS: &str = "!#$%&'()-@^_`{}~";
+
+/// Cluster number recorded in ".." entries for directory with inode
+fn parent_cluster(fat: &Fat, inode: u32) -> u32 {
+    if inode == fat.get_root_cluster_number() {
+        0
+    } else {
+        inode
+    }
+}
+
+/// Whether inode is the fixed size root directory of FAT12/16
+fn is_fixed_root(fat: &Fat, inode: u32) -> bool {
+    inode == 0 && fat.fat_type != FatType::Fat32
+}
+
+/// Slots taken by entry in directory data, (offset of first slot, count)
+fn entry_slots(entry: &FatDirectoryEntryContainer) -> (usize, usize) {
+    let long_count = entry.long_entries.len();
+    (entry.offset - 32 * long_count, long_count + 1)
+}
+
+/// Drops cached entries of directory, they are read again on demand
+pub fn invalidate_dir(fat: &mut Fat, inode: u32) {
+    fat.dir_cache.remove(&inode);
+    fat.inode_cache.retain(|_, parent| *parent != inode);
+}
+
+/// Replaces raw entries of directory with given inode
+pub fn write_dir_data(
+    fat: &mut Fat,
+    inode: u32,
+    data: &[u8],
+) -> Result<(), FatError> {
+    if is_fixed_root(fat, inode) {
+        let first_root_sector = first_root_dir_sector(fat);
+        write_sectors(fat, first_root_sector, data)?;
+    } else {
+        write_chain(fat, inode, data)?;
+    }
+    invalidate_dir(fat, inode);
+    Ok(())
+}
+
+/// Overwrites slot at offset in directory, only the sector holding it is
+/// written
+pub fn write_dir_slot(
+    fat: &mut Fat,
+    inode: u32,
+    offset: usize,
+    slot: &[u8; 32],
+) -> Result<(), FatError> {
+    let bytes_per_sector = fat.bpb.bytes_per_sector as usize;
+    let sector_number = if is_fixed_root(fat, inode) {
+        first_root_dir_sector(fat) + (offset / bytes_per_sector) as u32
+    } else {
+        let cluster_size = cluster_size(fat) as usize;
+        let chain = cluster_chain(fat, inode)?;
+        let cluster_number =
+            *chain.get(offset / cluster_size).ok_or(FatError::NotFound)?;
+        first_sector_of_cluster(fat, cluster_number)
+            + (offset % cluster_size / bytes_per_sector) as u32
+    };
+    let mut sector = read_sector(fat, sector_number);
+    let start = offset % bytes_per_sector;
+    sector[start..start + 32].copy_from_slice(slot);
+    write_sectors(fat, sector_number, &sector)?;
+    invalidate_dir(fat, inode);
+    Ok(())
+}
+
+/// Rewrites directory to hold entries in the order given, without free slots
+/// Unless the directory is the fixed root, shrink drops clusters left unused
+pub fn write_entries(
+    fat: &mut Fat,
+    inode: u32,
+    entries: &[FatDirectoryEntryContainer],
+    shrink: bool,
+) -> Result<(), FatError> {
+    let old_data = read_dir_data(fat, inode)?;
+    let mut data = Vec::with_capacity(old_data.len());
+    // Long name entries go along with their short entry
+    for entry in entries {
+        let (offset, count) = entry_slots(entry);
+        data.extend_from_slice(&old_data[offset..offset + count * 32]);
+    }
+
+    let length = if shrink && !is_fixed_root(fat, inode) {
+        // Directories keep at least one cluster
+        let cluster_size = cluster_size(fat) as usize;
+        let clusters = data.len().div_ceil(cluster_size).max(1);
+        clusters * cluster_size
+    } else {
+        old_data.len()
+    };
+    data.resize(length, 0);
+    if data != old_data {
+        write_dir_data(fat, inode, &data)?;
+    }
+    Ok(())
+}
+
+/// Marks slots of an entry as free
+fn remove_slots(
+    fat: &mut Fat,
+    inode: u32,
+    offset: usize,
+    count: usize,
+) -> Result<(), FatError> {
+    let mut data = read_dir_data(fat, inode)?;
+    for slot in 0..count {
+        data[offset + slot * 32] = 0xE5;
+    }
+    write_dir_data(fat, inode, &data)
+}
+
+/// Offset following the last entry in use of directory data
+fn end_of_entries(data: &[u8]) -> usize {
+    (0..data.len() / 32)
+        .find(|slot| data[slot * 32] == 0)
+        .unwrap_or(data.len() / 32)
+        * 32
+}
+
+/// Adds entry to directory under name, generating its short name
+/// Short entry is complete apart from the name field
+/// Entry at index ignore does not count when checking short names
+fn add_entry(
+    fat: &mut Fat,
+    inode: u32,
+    name: &str,
+    mut short_entry: [u8; 32],
+    ignore: Option<usize>,
+) -> Result<(), FatError> {
+    validate_name(name)?;
+    let existing: HashSet<[u8; 11]> = get_dir(fat, inode)?
+        .ok_or(FatError::NotFound)?
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| Some(*index) != ignore)
+        .map(|(_, entry)| entry.short_entry.name)
+        .collect();
+    let (short_name, needs_long_name) = generate_short_name(name, &existing)?;
+    short_entry[0..11].copy_from_slice(&short_name);
+
+    let mut slots = if needs_long_name {
+        long_entries(name, chksum(&short_name))
+    } else {
+        vec![]
+    };
+    slots.push(short_entry);
+
+    // Append after the last entry in use, leaving free slots alone
+    let mut data = read_dir_data(fat, inode)?;
+    let end = end_of_entries(&data);
+    let needed = end + slots.len() * 32;
+    if needed > data.len() {
+        if is_fixed_root(fat, inode) {
+            return Err(FatError::NoSpace);
+        }
+        // New clusters of a directory must be zeroed
+        let cluster_size = cluster_size(fat) as usize;
+        data.resize(needed.div_ceil(cluster_size) * cluster_size, 0);
+    }
+    for (index, slot) in slots.iter().enumerate() {
+        let offset = end + index * 32;
+        data[offset..offset + 32].copy_from_slice(slot);
+    }
+    // Keep the end of the directory marked
+    if needed < data.len() {
+        data[needed] = 0;
+    }
+    write_dir_data(fat, inode, &data)
+}
+
+/// Checks that name can be stored as a long name
+fn validate_name(name: &str) -> Result<(), FatError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.ends_with('.')
+        || name.ends_with(' ')
+        || name.encode_utf16().count() > 255
+        || name
+            .chars()
+            .any(|c| (c as u32) < 0x20 || INVALID_CHARACTERS.contains(c))
+    {
+        return Err(FatError::InvalidName);
+    }
+    Ok(())
+}
+
+/// Converts part of a name to short name characters
+/// Returns the characters and whether any had to be dropped or replaced
+fn short_name_part(part: &str) -> (Vec<u8>, bool) {
+    let mut lossy = false;
+    let mut bytes = vec![];
+    for c in part.chars() {
+        if c == ' ' || c == '.' {
+            lossy = true;
+        } else if c.is_ascii_uppercase()
+            || c.is_ascii_digit()
+            || SHORT_NAME_CHARACTERS.contains(c)
+        {
+            bytes.push(c as u8);
+        } else {
+            lossy = true;
+            bytes.push(b'_');
+        }
+    }
+    (bytes, lossy)
+}
+
+/// Generates an 8.3 name not in existing, such as "LONGFI~1.BIN"
+/// Returns the name and whether a long name is needed to record name exactly
+fn generate_short_name(
+    name: &str,
+    existing: &HashSet<[u8; 11]>,
+) -> Result<([u8; 11], bool), FatError> {
+    let upper = name.to_uppercase();
+    // Leading dots belong to the name, the last other dot starts extension
+    let stripped = upper.trim_start_matches('.');
+    let (base, ext) = match stripped.rfind('.') {
+        Some(index) => (&stripped[..index], &stripped[index + 1..]),
+        None => (stripped, ""),
+    };
+    let (mut base, base_lossy) = short_name_part(base);
+    let (ext, ext_lossy) = short_name_part(ext);
+
+    let mut short_name = [b' '; 11];
+    let ext_len = ext.len().min(3);
+    short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+
+    // Names which are already 8.3 are kept as they are
+    let fits = !base_lossy
+        && !ext_lossy
+        && stripped.len() == upper.len()
+        && !base.is_empty()
+        && base.len() <= 8
+        && ext.len() <= 3;
+    if fits {
+        short_name[..base.len()].copy_from_slice(&base);
+        if !existing.contains(&short_name) {
+            // Lower case letters are only kept by the long name
+            return Ok((short_name, name != upper));
+        }
+    }
+
+    // Otherwise add a numeric tail to the basis name
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        short_name[..8].copy_from_slice(b"        ");
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !existing.contains(&short_name) {
+            return Ok((short_name, true));
+        }
+    }
+    Err(FatError::NoSpace)
+}
+
+/// Builds long entries holding name, in on-disk order
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+    // Names not filling the last entry are terminated, then padded
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(13) {
+        units.push(0);
+        units.resize(units.len().div_ceil(13) * 13, 0xFFFF);
+    }
+    let count = units.len() / 13;
+
+    (0..count)
+        .rev()
+        .map(|n| {
+            let mut slot = [0u8; 32];
+            slot[0] = (n + 1) as u8;
+            if n + 1 == count {
+                slot[0] |= LAST_LONG_ENTRY;
+            }
+            slot[11] = FatFileType::AttrLongname as u8;
+            slot[13] = checksum;
+            for (index, unit) in units[n * 13..(n + 1) * 13].iter().enumerate()
+            {
+                // Characters are split over 3 fields
+                let position = match index {
+                    0..=4 => 1 + index * 2,
+                    5..=10 => 14 + (index - 5) * 2,
+                    _ => 28 + (index - 11) * 2,
+                };
+                slot[position..position + 2]
+                    .copy_from_slice(&unit.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+/// Builds short entry without a name
+/// Last accessed date is the date of creation
+pub fn new_short_entry(
+    attribute: u8,
+    cluster_number: u32,
+    size: u32,
+    created: &FatDateTime,
+    modified: &FatDateTime,
+) -> [u8; 32] {
+    let mut slot = [0u8; 32];
+    slot[0..11].copy_from_slice(b"           ");
+    slot[11] = attribute;
+    let (created_time, centiseconds) = encode_time(created);
+    slot[13] = centiseconds;
+    slot[14..16].copy_from_slice(&created_time.to_le_bytes());
+    slot[16..18].copy_from_slice(&encode_date(created).to_le_bytes());
+    slot[18..20].copy_from_slice(&encode_date(created).to_le_bytes());
+    set_modified(&mut slot, modified);
+    set_cluster_number(&mut slot, cluster_number);
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+    slot
+}
+
+/// Sets first cluster of short entry
+pub fn set_cluster_number(slot: &mut [u8], cluster_number: u32) {
+    slot[20..22]
+        .copy_from_slice(&((cluster_number >> 16) as u16).to_le_bytes());
+    slot[26..28].copy_from_slice(&(cluster_number as u16).to_le_bytes());
+}
+
+/// Sets write time and date of short entry
+fn set_modified(slot: &mut [u8], modified: &FatDateTime) {
+    let (write_time, _) = encode_time(modified);
+    slot[22..24].copy_from_slice(&write_time.to_le_bytes());
+    slot[24..26].copy_from_slice(&encode_date(modified).to_le_bytes());
+}
+
+/// Frees the chain starting at cluster number
+fn free_chain(fat: &mut Fat, cluster_number: u32) -> Result<(), FatError> {
+    let chain = cluster_chain(fat, cluster_number)?;
+    free_clusters(fat, &chain);
+    Ok(())
+}
+
+/// Creates file at path holding data, or replaces contents of existing file
+pub fn write_file(
+    fat: &mut Fat,
+    path: &str,
+    data: &[u8],
+    created: &FatDateTime,
+    modified: &FatDateTime,
+) -> Result<(), FatError> {
+    let size = u32::try_from(data.len()).map_err(|_| FatError::NoSpace)?;
+    let (dirs, name) = walk_parent(fat, path)?;
+    validate_name(name)?;
+    let parent = *dirs.last().unwrap();
+
+    match find_entry(fat, parent, name)? {
+        Some(index) => {
+            let entry = &fat.dir_cache[&parent][index];
+            if is_dir(entry) {
+                return Err(FatError::IsADirectory);
+            }
+            let (cluster_number, offset) =
+                (entry.cluster_number(), entry.offset);
+            let mut slot = entry.short_entry.to_bytes();
+
+            let cluster_number = write_chain(fat, cluster_number, data)?;
+            slot[11] |= FatFileType::AttrArchive as u8;
+            set_modified(&mut slot, modified);
+            set_cluster_number(&mut slot, cluster_number);
+            slot[28..32].copy_from_slice(&size.to_le_bytes());
+            write_dir_slot(fat, parent, offset, &slot)
+        }
+        None => {
+            let cluster_number = write_chain(fat, 0, data)?;
+            let slot = new_short_entry(
+                FatFileType::AttrArchive as u8,
+                cluster_number,
+                size,
+                created,
+                modified,
+            );
+            // Release the data again if there is no room for the entry
+            add_entry(fat, parent, name, slot, None).or_else(|e| {
+                free_chain(fat, cluster_number)?;
+                Err(e)
+            })
+        }
+    }
+}
+
+/// Creates empty directory at path, with room for a number of entries
+/// Slots taken by long names count as entries
+pub fn create_dir(
+    fat: &mut Fat,
+    path: &str,
+    created: &FatDateTime,
+    slots: usize,
+) -> Result<(), FatError> {
+    let (dirs, name) = walk_parent(fat, path)?;
+    validate_name(name)?;
+    let parent = *dirs.last().unwrap();
+    if find_entry(fat, parent, name)?.is_some() {
+        return Err(FatError::AlreadyExists);
+    }
+
+    // Directories start with entries for themselves and their parent
+    let cluster_size = cluster_size(fat) as usize;
+    let count = ((2 + slots) * 32).div_ceil(cluster_size);
+    let clusters = allocate_clusters(fat, count as u32)?;
+    let cluster_number = clusters[0];
+    let attribute = FatFileType::AttrDirectory as u8;
+    let mut dot =
+        new_short_entry(attribute, cluster_number, 0, created, created);
+    dot[0] = b'.';
+    let parent_cluster = parent_cluster(fat, parent);
+    let mut dot_dot =
+        new_short_entry(attribute, parent_cluster, 0, created, created);
+    dot_dot[0..2].copy_from_slice(b"..");
+    let mut data = dot.to_vec();
+    data.extend_from_slice(&dot_dot);
+    data.resize(count * cluster_size, 0);
+    for (cluster, chunk) in clusters.iter().zip(data.chunks(cluster_size)) {
+        write_cluster(fat, *cluster, chunk)?;
+    }
+
+    let slot = new_short_entry(attribute, cluster_number, 0, created, created);
+    add_entry(fat, parent, name, slot, None)
+        .inspect_err(|_| free_clusters(fat, &clusters))
+}
+
+/// Grows directory so a number of entries can be added without growing it
+/// The fixed root directory of FAT12/16 is left alone
+pub fn reserve_slots(
+    fat: &mut Fat,
+    inode: u32,
+    slots: usize,
+) -> Result<(), FatError> {
+    if is_fixed_root(fat, inode) {
+        return Ok(());
+    }
+    let mut data = read_dir_data(fat, inode)?;
+    let end = end_of_entries(&data);
+    let needed = end + slots * 32;
+    if needed > data.len() {
+        let cluster_size = cluster_size(fat) as usize;
+        data.resize(needed.div_ceil(cluster_size) * cluster_size, 0);
+        write_dir_data(fat, inode, &data)?;
+    }
+    Ok(())
+}
+
+/// Removes file or empty directory at path
+pub fn remove(fat: &mut Fat, path: &str) -> Result<(), FatError> {
+    let (_, location) = locate(fat, path)?;
+    let entry = &fat.dir_cache[&location.parent][location.index];
+    let cluster_number = entry.cluster_number();
+    let (offset, count) = entry_slots(entry);
+
+    if is_dir(entry) && cluster_number != 0 {
+        let children =
+            get_dir(fat, cluster_number)?.ok_or(FatError::NotFound)?;
+        if children
+            .iter()
+            .any(|child| child.get_name() != "." && child.get_name() != "..")
+        {
+            return Err(FatError::DirectoryNotEmpty);
+        }
+        invalidate_dir(fat, cluster_number);
+    }
+    free_chain(fat, cluster_number)?;
+    remove_slots(fat, location.parent, offset, count)
+}
+
+/// Moves entry at path from to path to, which must not exist yet
+pub fn rename(fat: &mut Fat, from: &str, to: &str) -> Result<(), FatError> {
+    let (_, source) = locate(fat, from)?;
+    let (dirs, name) = walk_parent(fat, to)?;
+    validate_name(name)?;
+    let parent = *dirs.last().unwrap();
+
+    let entry = &fat.dir_cache[&source.parent][source.index];
+    let cluster_number = entry.cluster_number();
+    let moves_dir = is_dir(entry);
+    let (offset, count) = entry_slots(entry);
+    let slot = entry.short_entry.to_bytes();
+
+    // A directory cannot be moved below itself
+    if moves_dir && dirs.contains(&cluster_number) {
+        return Err(FatError::InvalidName);
+    }
+    // Changing only the case of a name finds the entry itself
+    let same_dir = parent == source.parent;
+    match find_entry(fat, parent, name)? {
+        Some(index) if !(same_dir && index == source.index) => {
+            return Err(FatError::AlreadyExists)
+        }
+        _ => {}
+    }
+
+    // Add the new entry first so a full directory loses nothing
+    let ignore = if same_dir { Some(source.index) } else { None };
+    add_entry(fat, parent, name, slot, ignore)?;
+    remove_slots(fat, source.parent, offset, count)?;
+
+    // Point ".." of a moved directory at its new parent
+    if moves_dir && !same_dir {
+        set_parent(fat, cluster_number, parent)?;
+    }
+    Ok(())
+}
+
+/// Points ".." entry of directory with inode at directory with parent inode
+pub fn set_parent(
+    fat: &mut Fat,
+    inode: u32,
+    parent: u32,
+) -> Result<(), FatError> {
+    let mut data = read_dir_data(fat, inode)?;
+    if data.len() >= 64 && data[32..43] == *b"..         " {
+        let parent_cluster = parent_cluster(fat, parent);
+        set_cluster_number(&mut data[32..64], parent_cluster);
+        write_dir_data(fat, inode, &data)?;
+    }
+    Ok(())
+}
+
+/// Rewrites entries of directory at path without free slots, and drops the
+/// clusters this leaves unused, returns the count of free slots dropped
+pub fn compact_directory(fat: &mut Fat, path: &str) -> Result<usize, FatError> {
+    let inode = dir_at(fat, path)?;
+    let entries: Vec<FatDirectoryEntryContainer> =
+        get_dir(fat, inode)?.ok_or(FatError::NotFound)?.clone();
+    let used: usize = entries
+        .iter()
+        .map(|entry| entry.long_entries.len() + 1)
+        .sum();
+    let end = end_of_entries(&read_dir_data(fat, inode)?) / 32;
+    write_entries(fat, inode, &entries, true)?;
+    Ok(end.saturating_sub(used))
+}
+
+/// Sets read-only, hidden, system and archive attributes of entry at path
+pub fn set_attributes(
+    fat: &mut Fat,
+    path: &str,
+    attribute: u8,
+) -> Result<(), FatError> {
+    let (_, location) = locate(fat, path)?;
+    set_slot_attributes(fat, location.parent, location.index, attribute)
+}
+
+/// Sets read-only, hidden, system and archive attributes of entry with inode
+/// The entry must have been looked up, and is found again afterwards
+pub fn set_inode_attributes(
+    fat: &mut Fat,
+    inode: u32,
+    attribute: u8,
+) -> Result<(), FatError> {
+    let parent = *fat.inode_cache.get(&inode).ok_or(FatError::NotFound)?;
+    let index = fat
+        .dir_cache
+        .get(&parent)
+        .and_then(|dir| {
+            dir.iter().position(|entry| entry.cluster_number() == inode)
+        })
+        .ok_or(FatError::NotFound)?;
+    set_slot_attributes(fat, parent, index, attribute)?;
+    get_dir(fat, parent)?;
+    Ok(())
+}
+
+/// Sets changeable attributes of entry at index of directory with inode
+fn set_slot_attributes(
+    fat: &mut Fat,
+    inode: u32,
+    index: usize,
+    attribute: u8,
+) -> Result<(), FatError> {
+    let entry = &fat.dir_cache[&inode][index];
+    let offset = entry.offset;
+    let mut slot = entry.short_entry.to_bytes();
+    slot[11] = (slot[11] & !CHANGEABLE_ATTRIBUTES)
+        | (attribute & CHANGEABLE_ATTRIBUTES);
+    write_dir_slot(fat, inode, offset, &slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::super::fat_helper::fat_entry;
+    use super::super::fat_test::{pattern, volumes, Scratch, STAMP};
+    use super::super::{
+        cluster_chain, cluster_size, FatError, FatFileType, FatType,
+    };
+    use super::{
+        generate_short_name, long_entries, CHANGEABLE_ATTRIBUTES,
+        LAST_LONG_ENTRY,
+    };
+
+    /// Names of entries in directory at path, other than dot entries
+    fn names(fat: &mut Scratch, path: &str) -> HashSet<String> {
+        let inode = match fat.entry_at(path).unwrap() {
+            None => fat.get_root_cluster_number(),
+            Some(entry) => entry.cluster_number(),
+        };
+        fat.list_directory(inode)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.get_name().clone())
+            .filter(|name| name != "." && name != "..")
+            .collect()
+    }
+
+    /// Free clusters of volume
+    fn unused(fat: &Scratch) -> Vec<u32> {
+        (2..fat.cluster_count() + 2)
+            .filter(|cluster| fat_entry(fat, *cluster) == 0)
+            .collect()
+    }
+
+    /// Count of free clusters
+    fn free(fat: &Scratch) -> u32 {
+        unused(fat).len() as u32
+    }
+
+    /// Clusters of file at path
+    fn chain(fat: &mut Scratch, path: &str) -> Vec<u32> {
+        let entry = fat.entry_at(path).unwrap().unwrap();
+        cluster_chain(fat, entry.cluster_number()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_files() {
+        let sizes = [0, 1, 511, 512, 513, 5000];
+        for mut fat in volumes("round-trip") {
+            for (seed, size) in sizes.iter().enumerate() {
+                let data = pattern(*size, seed as u8);
+                let path = format!("/f{}.bin", size);
+                fat.write_file(&path, &data, &STAMP, &STAMP).unwrap();
+            }
+            fat.create_dir("/Sub Dir", &STAMP).unwrap();
+            let nested = pattern(2000, 7);
+            fat.write_file("/Sub Dir/nested file.txt", &nested, &STAMP, &STAMP)
+                .unwrap();
+            fat.remount();
+
+            for (seed, size) in sizes.iter().enumerate() {
+                let path = format!("/f{}.bin", size);
+                assert_eq!(fat.contents(&path), pattern(*size, seed as u8));
+            }
+            assert_eq!(fat.contents("/Sub Dir/nested file.txt"), nested);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn replaces_contents() {
+        for mut fat in volumes("replace") {
+            let before = free(&fat);
+            for (seed, size) in [5000, 100, 9000, 0, 1500].iter().enumerate() {
+                let data = pattern(*size, seed as u8);
+                fat.write_file("/file", &data, &STAMP, &STAMP).unwrap();
+                // FSInfo free count is kept up to date between flushes
+                fat.check();
+            }
+            fat.remount();
+            assert_eq!(fat.contents("/file"), pattern(1500, 4));
+            assert_eq!(free(&fat), before - 3);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn round_trips_long_names() {
+        let long = "x".repeat(255);
+        let names_written = [
+            "README.TXT",
+            "lower.txt",
+            "Long File Name.bin",
+            ".hidden",
+            "a.b.c",
+            "thirteen char",
+            "fourteen chars",
+            "ünïcödé.txt",
+            "twenty six characters.abc",
+            "twenty seven characters.ab",
+            &long,
+        ];
+        for mut fat in volumes("long-names") {
+            fat.create_dir("/dir", &STAMP).unwrap();
+            for name in names_written {
+                let path = format!("/dir/{}", name);
+                fat.write_file(&path, name.as_bytes(), &STAMP, &STAMP)
+                    .unwrap();
+            }
+            fat.remount();
+
+            let expected: HashSet<String> =
+                names_written.iter().map(|name| name.to_string()).collect();
+            assert_eq!(names(&mut fat, "/dir"), expected);
+            for name in names_written {
+                let path = format!("/dir/{}", name);
+                assert_eq!(fat.contents(&path), name.as_bytes());
+            }
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let mut fat = Scratch::new("invalid-names", 1 << 20, FatType::Fat12);
+        let long = format!("/{}", "x".repeat(256));
+        for path in ["/a:b", "/trailing.", "/trailing ", "/a\u{1}", &long] {
+            let result = fat.write_file(path, b"", &STAMP, &STAMP);
+            assert_eq!(result, Err(FatError::InvalidName), "{}", path);
+        }
+    }
+
+    #[test]
+    fn generates_short_names() {
+        let mut existing = HashSet::new();
+        let mut generate = |name: &str| {
+            let (short_name, long) =
+                generate_short_name(name, &existing).unwrap();
+            existing.insert(short_name);
+            (String::from_utf8(short_name.to_vec()).unwrap(), long)
+        };
+        assert_eq!(generate("README.TXT"), ("README  TXT".into(), false));
+        assert_eq!(generate("readme.txt"), ("README~1TXT".into(), true));
+        assert_eq!(generate("Makefile"), ("MAKEFILE   ".into(), true));
+        assert_eq!(generate("Long Name.bin"), ("LONGNA~1BIN".into(), true));
+        assert_eq!(generate("Long Nose.bin"), ("LONGNO~1BIN".into(), true));
+        assert_eq!(generate("longname.binary"), ("LONGNA~2BIN".into(), true));
+        assert_eq!(generate(".hidden"), ("HIDDEN~1   ".into(), true));
+        assert_eq!(generate("a.b.c"), ("AB~1    C  ".into(), true));
+        assert_eq!(generate("ü.txt"), ("_~1     TXT".into(), true));
+    }
+
+    #[test]
+    fn splits_long_names() {
+        // Exactly 13 characters fill one entry without a terminator
+        let entries = long_entries("thirteen char", 0xAB);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0][0], 1 | LAST_LONG_ENTRY);
+        assert_eq!(entries[0][13], 0xAB);
+        assert_eq!(entries[0][30..32], *b"r\0");
+
+        // Entries are stored last first, the name is terminated and padded
+        let entries = long_entries("fourteen chars", 0xAB);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0][0], 2 | LAST_LONG_ENTRY);
+        assert_eq!(entries[1][0], 1);
+        assert_eq!(entries[0][1..7], [b's', 0, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(entries[0][30..32], [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn allocation_wraps_around() {
+        let mut fat = Scratch::new("wrap", 256 << 10, FatType::Fat12);
+        let cluster_size = cluster_size(&fat) as usize;
+        let end = fat.cluster_count() + 2;
+        let free_before = free(&fat);
+        fat.write_file("/a", &pattern(10 * cluster_size, 1), &STAMP, &STAMP)
+            .unwrap();
+        let a = chain(&mut fat, "/a");
+        let rest = (free_before - 15) as usize * cluster_size;
+        fat.write_file("/b", &pattern(rest, 2), &STAMP, &STAMP)
+            .unwrap();
+        fat.remove("/a").unwrap();
+
+        // Allocation carries on after "/b", then wraps round to where "/a" was
+        let c = pattern(8 * cluster_size, 3);
+        fat.write_file("/c", &c, &STAMP, &STAMP).unwrap();
+        let expected: Vec<u32> = (end - 5..end).chain(a[0]..a[0] + 3).collect();
+        assert_eq!(chain(&mut fat, "/c"), expected);
+        fat.remount();
+        assert_eq!(fat.contents("/c"), c);
+        fat.check();
+    }
+
+    #[test]
+    fn full_volume_keeps_nothing() {
+        for mut fat in volumes("full") {
+            let before = unused(&fat);
+            let size = (free(&fat) as usize + 1) * cluster_size(&fat) as usize;
+            let result =
+                fat.write_file("/big", &pattern(size, 0), &STAMP, &STAMP);
+            assert_eq!(result, Err(FatError::NoSpace));
+            assert_eq!(unused(&fat), before);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn removes_and_renames() {
+        for mut fat in volumes("remove-rename") {
+            let before = unused(&fat);
+            fat.create_dir("/x", &STAMP).unwrap();
+            fat.create_dir("/x/y", &STAMP).unwrap();
+            let data = pattern(3000, 9);
+            fat.write_file("/x/y/file", &data, &STAMP, &STAMP).unwrap();
+            assert_eq!(fat.remove("/x"), Err(FatError::DirectoryNotEmpty));
+            assert_eq!(fat.rename("/x", "/x/y/x"), Err(FatError::InvalidName));
+
+            // Moving a directory points its ".." at the new parent
+            fat.rename("/x/y", "/moved").unwrap();
+            fat.rename("/moved/file", "/x/Renamed File").unwrap();
+            fat.write_file("/other", b"", &STAMP, &STAMP).unwrap();
+            let result = fat.rename("/other", "/x/renamed file");
+            assert_eq!(result, Err(FatError::AlreadyExists));
+            fat.rename("/moved", "/MOVED").unwrap();
+            fat.remount();
+
+            assert_eq!(fat.contents("/x/Renamed File"), data);
+            assert!(names(&mut fat, "/").contains("MOVED"));
+            assert!(names(&mut fat, "/MOVED").is_empty());
+            fat.check();
+
+            for path in ["/x/Renamed File", "/x", "/MOVED", "/other"] {
+                fat.remove(path).unwrap();
+            }
+            fat.remount();
+            assert_eq!(unused(&fat), before);
+            assert!(names(&mut fat, "/").len() <= 1);
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn changes_attributes_in_place() {
+        let hidden = FatFileType::AttrHidden as u8;
+        let changed = [0, 9, 23];
+        for mut fat in volumes("attributes") {
+            // Entries take 3 slots each, spread over several clusters
+            fat.create_dir("/dir", &STAMP).unwrap();
+            for i in 0..24 {
+                let path = format!("/dir/file number {}", i);
+                fat.write_file(&path, b"", &STAMP, &STAMP).unwrap();
+            }
+            fat.remount();
+            assert!(chain(&mut fat, "/dir").len() > 3);
+
+            for i in changed {
+                let before = std::fs::read(&fat.path).unwrap();
+                let path = format!("/dir/file number {}", i);
+                fat.set_attributes(&path, hidden).unwrap();
+                let after = std::fs::read(&fat.path).unwrap();
+                // Only the attribute byte of the short entry is written
+                let differing: Vec<usize> = (0..before.len())
+                    .filter(|offset| before[*offset] != after[*offset])
+                    .collect();
+                assert_eq!(differing.len(), 1, "{}", path);
+                assert_eq!(differing[0] % 32, 11, "{}", path);
+            }
+            fat.remount();
+
+            for i in 0..24 {
+                let path = format!("/dir/file number {}", i);
+                let entry = fat.entry_at(&path).unwrap().unwrap();
+                let attribute = entry.attribute() & CHANGEABLE_ATTRIBUTES;
+                assert_eq!(
+                    attribute == hidden,
+                    changed.contains(&i),
+                    "{}",
+                    path
+                );
+            }
+            fat.check();
+        }
+    }
+
+    /// Clusters of directory at path
+    fn dir_clusters(fat: &mut Scratch, path: &str) -> u32 {
+        let extents = fat.file_extents(path).unwrap();
+        extents.iter().map(|extent| extent.length).sum()
+    }
+
+    #[test]
+    fn compacts_directories() {
+        for mut fat in volumes("compact") {
+            let fixed_root = fat.get_root_cluster_number() == 0;
+            fat.create_dir("/d", &STAMP).unwrap();
+            fat.create_dir("/empty", &STAMP).unwrap();
+            for dir in ["", "/d", "/empty"] {
+                for i in 0..40 {
+                    let path = format!("{}/file {}", dir, i);
+                    fat.write_file(&path, &pattern(i, 1), &STAMP, &STAMP)
+                        .unwrap();
+                }
+            }
+            // Every entry takes a long name entry besides its short entry
+            for dir in ["", "/d"] {
+                for i in (0..40).filter(|i| i % 4 != 0) {
+                    fat.remove(&format!("{}/file {}", dir, i)).unwrap();
+                }
+            }
+            for i in 0..40 {
+                fat.remove(&format!("/empty/file {}", i)).unwrap();
+            }
+            let root_before = dir_clusters(&mut fat, "/");
+            assert_eq!(dir_clusters(&mut fat, "/d"), 6);
+
+            assert_eq!(fat.compact_directory("/d").unwrap(), 60);
+            assert_eq!(dir_clusters(&mut fat, "/d"), 2);
+            assert_eq!(fat.compact_directory("/d").unwrap(), 0);
+            // Directories keep at least one cluster
+            assert_eq!(fat.compact_directory("/empty").unwrap(), 80);
+            assert_eq!(dir_clusters(&mut fat, "/empty"), 1);
+            // Label and the two directories are entries of the root as well
+            assert_eq!(fat.compact_directory("/").unwrap(), 60);
+            let root_after = dir_clusters(&mut fat, "/");
+            if fixed_root {
+                assert_eq!(root_after, 0);
+            } else {
+                assert!(root_after < root_before);
+            }
+            fat.remount();
+
+            // Root also holds the label and both directories
+            for (dir, count) in [("", 13), ("/d", 10)] {
+                for i in (0..40).step_by(4) {
+                    let path = format!("{}/file {}", dir, i);
+                    assert_eq!(fat.contents(&path), pattern(i, 1));
+                }
+                let listed = names(&mut fat, &format!("{}/", dir));
+                assert_eq!(listed.len(), count);
+            }
+            assert!(names(&mut fat, "/empty").is_empty());
+            fat.check();
+        }
+    }
+
+    #[test]
+    fn sets_attributes_of_listed_directories_by_inode() {
+        let hidden = FatFileType::AttrHidden as u8;
+        let read_only = FatFileType::AttrReadOnly as u8;
+        for mut fat in volumes("inode-attributes") {
+            fat.create_dir("/sub", &STAMP).unwrap();
+            fat.write_file("/sub/file", b"file", &STAMP, &STAMP)
+                .unwrap();
+            fat.remount();
+
+            // Listing a directory reads its own dot entries
+            let root = fat.get_root_cluster_number();
+            fat.list_directory(root).unwrap();
+            let sub = fat.entry_at("/sub").unwrap().unwrap().cluster_number();
+            fat.list_directory(sub).unwrap();
+            let file =
+                fat.entry_at("/sub/file").unwrap().unwrap().cluster_number();
+            assert_eq!(fat.get_inode(sub).unwrap().get_name(), "sub");
+            assert_eq!(fat.get_inode(file).unwrap().get_name(), "file");
+
+            fat.set_inode_attributes(sub, hidden).unwrap();
+            fat.set_inode_attributes(file, read_only).unwrap();
+            assert_eq!(
+                fat.get_inode(sub).unwrap().attribute() & hidden,
+                hidden
+            );
+            fat.remount();
+
+            let entry = fat.entry_at("/sub").unwrap().unwrap();
+            let expected = FatFileType::AttrDirectory as u8 | hidden;
+            assert_eq!(entry.attribute(), expected);
+            let entry = fat.entry_at("/sub/file").unwrap().unwrap();
+            assert_eq!(entry.attribute() & CHANGEABLE_ATTRIBUTES, read_only);
+            let dots = fat.list_directory(sub).unwrap().unwrap();
+            let dot = dots.iter().find(|e| e.get_name() == ".").unwrap();
+            assert_eq!(dot.attribute(), FatFileType::AttrDirectory as u8);
+            fat.check();
+        }
+    }
+}
@@ -1,25 +1,69 @@
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::str;
 
+mod fat_error;
+pub use fat_error::FatError;
+
 mod fat_struct;
-use fat_struct::{
-    Fat32Ebpb, FatBpb, FatBs, FatDirectoryEntry, FatEbpb,
-    FatLongDirectoryEntry, FatType,
+pub use fat_struct::{
+    Fat32Ebpb, FatBpb, FatDateTime, FatDeletedEntry,
+    FatDirectoryEntryContainer, FatEbpb, FatExtent, FatFileType, FatType,
 };
-pub use fat_struct::{FatDirectoryEntryContainer, FatFileType};
+use fat_struct::{FatBs, FatDirectoryEntry, FatLongDirectoryEntry};
 
 mod fat_reserved;
 use fat_reserved::read_reserved;
 
 mod fat_helper;
 use fat_helper::{
-    file_cluster_count, first_sector_of_cluster, read_file_full, read_sector,
-    root_dir_sectors,
+    allocate_clusters, calculate_fat_size, cluster_chain, cluster_size,
+    eof_marker, fat_entry, file_cluster_count, first_sector_of_cluster,
+    flush_fat, free_clusters, link_clusters, read_cluster, read_file_full,
+    read_sector, root_dir_sectors, write_chain, write_cluster, write_sectors,
 };
 
 mod fat_dir;
-use fat_dir::{get_dir, read_root_dir};
+use fat_dir::{
+    chksum, encode_date, encode_time, fill_cluster_count,
+    first_root_dir_sector, get_dir, read_deleted_entries, read_dir_data,
+    read_root_dir, verify_cluster_count, FIRST_CHARACTERS, LAST_LONG_ENTRY,
+};
+
+mod fat_path;
+use fat_path::{dir_at, find_entry, locate, walk_parent};
+pub use fat_path::{dir_inode, is_dir};
+
+mod fat_write;
+pub use fat_write::CHANGEABLE_ATTRIBUTES;
+use fat_write::{
+    invalidate_dir, new_short_entry, reserve_slots, set_cluster_number,
+    set_parent, write_dir_data, write_dir_slot, write_entries,
+};
+
+mod fat_format;
+pub use fat_format::FatFormat;
+
+mod fat_tar;
+
+mod fat_extent;
+use fat_extent::{chain_extents, extents, free_extents};
+
+mod fat_defrag;
+
+mod fat_sort;
+pub use fat_sort::FatSortOrder;
+
+mod fat_undelete;
+
+mod fat_slack;
+
+mod fat_carve;
+pub use fat_carve::{FatCarveKind, FatCarvedFile};
+
+#[cfg(test)]
+mod fat_test;
 
 // Wrapper
 #[derive(Debug)]
@@ -34,25 +78,52 @@ pub struct Fat {
     pub(crate) image: File,
     // FAT
     pub(crate) fat: HashMap<u32, Vec<u8>>,
+    // FAT sectors changed since the last flush
+    pub(crate) dirty_fat_sectors: HashSet<u32>,
+    // Cluster to start looking for free clusters at
+    pub(crate) next_free: u32,
     // Caches directories, (inode, directory entries) of directory
     pub(crate) dir_cache: HashMap<u32, Vec<FatDirectoryEntryContainer>>,
     // Caches inode attr locations, (child inode, parent inode)
     pub(crate) inode_cache: HashMap<u32, u32>,
+    // Caches runs of free clusters, dropped whenever the FAT changes
+    pub(crate) free_cache: Option<Vec<FatExtent>>,
+    // Count of free clusters, counted once needed and kept up to date after
+    pub(crate) free_count: Option<u32>,
 
     // Determined/derived
     pub(crate) fat_type: FatType,
+    // Count of clusters in data region
+    pub(crate) cluster_count: u32,
 }
 
 impl Fat {
     /// Mount FAT volume
-    pub fn mount_volume(filename: &str) -> Fat {
+    pub fn mount_volume(filename: &str) -> Result<Fat, FatError> {
         // Open file
-        let f = File::open(filename).expect("Bad file");
+        let f = File::open(filename)?;
+        Fat::open(f)
+    }
+
+    /// Mount FAT volume for writing
+    /// Changes to the FAT are kept in memory until flushed
+    pub fn mount_volume_rw(filename: &str) -> Result<Fat, FatError> {
+        let f = OpenOptions::new().read(true).write(true).open(filename)?;
+        Fat::open(f)
+    }
+
+    /// Create image file holding a new, empty volume and mount it for writing
+    pub fn format(filename: &str, format: &FatFormat) -> Result<Fat, FatError> {
+        fat_format::format(filename, format)
+    }
+
+    /// Reads reserved sectors and root directory of opened image
+    fn open(f: File) -> Result<Fat, FatError> {
         // Read reserved sectors
-        let mut fat = read_reserved(f);
+        let mut fat = read_reserved(f)?;
         // Read the root directory
-        read_root_dir(&mut fat);
-        fat
+        read_root_dir(&mut fat)?;
+        Ok(fat)
     }
 
     /// Get root dir cluster number
@@ -70,19 +141,22 @@ impl Fat {
         ino: u32,
         offset: u64,
         size: u32,
-    ) -> Option<Vec<u8>> {
-        if !self.inode_cache.contains_key(&ino) {
-            return None;
-        }
+    ) -> Result<Option<Vec<u8>>, FatError> {
+        let file_size = match self.get_inode(ino) {
+            None => return Ok(None),
+            Some(entry) => entry.size() as usize,
+        };
 
         // Read whole file. TODO: seek...
-        let data = read_file_full(self, ino);
+        let mut data = read_file_full(self, ino)?;
+        // Drop the unused end of the last cluster
+        data.truncate(file_size);
         let head: usize = offset as usize;
         let mut tail: usize = head + size as usize;
 
         // Front is beyond length of data
         if offset as usize > data.len() {
-            return Some(vec![]);
+            return Ok(Some(vec![]));
         }
 
         // Tail is beyond size of file
@@ -90,32 +164,27 @@ impl Fat {
             tail = data.len();
         }
 
-        Some(data[head..tail].to_vec())
+        Ok(Some(data[head..tail].to_vec()))
     }
 
-    /// Lookup child of parent by name
+    /// Lookup child of parent by long or short name, case insensitive
     pub fn lookup(
         &mut self,
         parent_inode: u32,
         name: &str,
-    ) -> Option<&FatDirectoryEntryContainer> {
-        let name = name.to_lowercase();
-
-        // If not cached, parse parent first
-        if self.dir_cache.get(&parent_inode).is_none() {
-            self.list_directory(parent_inode);
-        }
-
-        match self.dir_cache.get(&parent_inode) {
-            None => None,
-            Some(dir) => {
-                // Look for child in parent directory
-                for child in dir {
-                    if child.get_name().to_lowercase() == name {
-                        return Some(child);
-                    }
-                }
-                None
+    ) -> Result<Option<&FatDirectoryEntryContainer>, FatError> {
+        // Names resolve as they do in paths
+        let index = match find_entry(self, parent_inode, name) {
+            Ok(index) => index,
+            Err(FatError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        match index {
+            None => Ok(None),
+            Some(index) => {
+                let child = &self.dir_cache[&parent_inode][index];
+                fill_cluster_count(self, child);
+                Ok(Some(child))
             }
         }
     }
@@ -127,6 +196,7 @@ impl Fat {
             Some(parent_inode) => {
                 for child in self.dir_cache.get(parent_inode).unwrap() {
                     if child.cluster_number() == inode {
+                        fill_cluster_count(self, child);
                         return Some(child);
                     }
                 }
@@ -135,14 +205,246 @@ impl Fat {
         }
     }
 
+    /// Get bytes past the end of file with inode, up to the end of its last
+    /// cluster, which get_data leaves out
+    pub fn get_slack(
+        &mut self,
+        ino: u32,
+        offset: u64,
+        size: u32,
+    ) -> Result<Option<Vec<u8>>, FatError> {
+        fat_slack::get_slack(self, ino, offset, size)
+    }
+
+    /// Get count of bytes past the end of file up to the end of its last
+    /// cluster
+    pub fn slack_size(&self, entry: &FatDirectoryEntryContainer) -> u32 {
+        fat_slack::slack_size(self, entry)
+    }
+
+    /// Get count of bytes in free clusters
+    pub fn unallocated_size(&mut self) -> u64 {
+        fat_slack::unallocated_size(self)
+    }
+
+    /// Get contents of free clusters, one after the other in cluster order
+    pub fn read_unallocated(
+        &mut self,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, FatError> {
+        fat_slack::read_unallocated(self, offset, size)
+    }
+
+    /// Check that the cluster chain of an entry matches its size
+    pub fn verify_cluster_count(
+        &self,
+        entry: &FatDirectoryEntryContainer,
+    ) -> Result<bool, FatError> {
+        verify_cluster_count(self, entry)
+    }
+
     /// List directory
     pub fn list_directory(
         &mut self,
         inode: u32,
-    ) -> Option<&Vec<FatDirectoryEntryContainer>> {
+    ) -> Result<Option<&Vec<FatDirectoryEntryContainer>>, FatError> {
         get_dir(self, inode)
     }
 
+    /// Get entry at path, None for the root directory which has no entry
+    pub fn entry_at(
+        &mut self,
+        path: &str,
+    ) -> Result<Option<FatDirectoryEntryContainer>, FatError> {
+        if path.trim_end_matches('/').is_empty() {
+            return Ok(None);
+        }
+        let (_, location) = locate(self, path)?;
+        let entry = &self.dir_cache[&location.parent][location.index];
+        fill_cluster_count(self, entry);
+        Ok(Some(entry.clone()))
+    }
+
+    /// List entries below path in pre-order, along with their paths
+    pub fn walk(
+        &mut self,
+        path: &str,
+    ) -> Result<Vec<(String, FatDirectoryEntryContainer)>, FatError> {
+        fat_path::walk(self, path)
+    }
+
+    /// Get runs of clusters holding file or directory at path, in chain order
+    pub fn file_extents(
+        &mut self,
+        path: &str,
+    ) -> Result<Vec<FatExtent>, FatError> {
+        fat_extent::file_extents(self, path)
+    }
+
+    /// Get runs of clusters of the chain starting at cluster number
+    pub fn chain_extents(
+        &self,
+        cluster_number: u32,
+    ) -> Result<Vec<FatExtent>, FatError> {
+        chain_extents(self, cluster_number)
+    }
+
+    /// Get runs of free clusters, in cluster order
+    pub fn free_extents(&self) -> Vec<FatExtent> {
+        free_extents(self)
+    }
+
+    /// Find files in free clusters by their signatures
+    pub fn carve(&mut self) -> Result<Vec<FatCarvedFile>, FatError> {
+        fat_carve::carve(self)
+    }
+
+    /// Read contents of file found by carve
+    pub fn read_carved(
+        &mut self,
+        carved: &FatCarvedFile,
+    ) -> Result<Vec<u8>, FatError> {
+        fat_carve::read_carved(self, carved)
+    }
+
+    /// Move file or directory at path into a single run of free clusters
+    /// Returns whether it was fragmented, changes are flushed as they are made
+    pub fn defragment(&mut self, path: &str) -> Result<bool, FatError> {
+        fat_defrag::defragment(self, path)
+    }
+
+    /// Move files or directories at paths, in order, into single runs of
+    /// clusters from the start of the data region
+    /// Other entries are moved out of the way, changes are flushed as made
+    /// The FAT32 root directory is left where it is
+    pub fn place_at_start(&mut self, paths: &[&str]) -> Result<(), FatError> {
+        fat_defrag::place_at_start(self, paths)
+    }
+
+    /// Rewrite entries of directory at path in order, dropping free slots
+    pub fn sort_directory(
+        &mut self,
+        path: &str,
+        order: &FatSortOrder,
+    ) -> Result<(), FatError> {
+        fat_sort::sort_directory(self, path, order)
+    }
+
+    /// Rewrite entries of directory at path without free slots, dropping the
+    /// clusters this leaves unused, returns the count of free slots dropped
+    pub fn compact_directory(&mut self, path: &str) -> Result<usize, FatError> {
+        fat_write::compact_directory(self, path)
+    }
+
+    /// List entries removed from directory at path
+    pub fn deleted_entries(
+        &mut self,
+        path: &str,
+    ) -> Result<Vec<FatDeletedEntry>, FatError> {
+        fat_undelete::deleted_entries(self, path)
+    }
+
+    /// Read contents of removed entry from the clusters guessed for it
+    pub fn read_deleted(
+        &mut self,
+        deleted: &FatDeletedEntry,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, FatError> {
+        fat_undelete::read_deleted(self, deleted, offset, size)
+    }
+
+    /// Restore removed entry, first_char replaces a first character not known
+    /// Fails if the clusters guessed for its contents have been reused
+    pub fn undelete(
+        &mut self,
+        deleted: &FatDeletedEntry,
+        first_char: Option<u8>,
+    ) -> Result<(), FatError> {
+        fat_undelete::undelete(self, deleted, first_char)
+    }
+
+    /// Write tar archive of entries below path to writer
+    /// Modification times are unix timestamps given by mtime for each entry
+    pub fn export_tar<W, F>(
+        &mut self,
+        path: &str,
+        writer: W,
+        mtime: F,
+    ) -> Result<(), FatError>
+    where
+        W: Write,
+        F: Fn(&FatDirectoryEntryContainer) -> i64,
+    {
+        fat_tar::export_tar(self, path, writer, mtime)
+    }
+
+    /// Create file at path holding data, or replace contents of existing file
+    pub fn write_file(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        created: &FatDateTime,
+        modified: &FatDateTime,
+    ) -> Result<(), FatError> {
+        fat_write::write_file(self, path, data, created, modified)
+    }
+
+    /// Create empty directory at path
+    pub fn create_dir(
+        &mut self,
+        path: &str,
+        created: &FatDateTime,
+    ) -> Result<(), FatError> {
+        fat_write::create_dir(self, path, created, 0)
+    }
+
+    /// Create empty directory at path, with room for a number of entries
+    /// Long names take a slot for every 13 characters besides their entry
+    pub fn create_dir_with_capacity(
+        &mut self,
+        path: &str,
+        created: &FatDateTime,
+        slots: usize,
+    ) -> Result<(), FatError> {
+        fat_write::create_dir(self, path, created, slots)
+    }
+
+    /// Remove file or empty directory at path
+    pub fn remove(&mut self, path: &str) -> Result<(), FatError> {
+        fat_write::remove(self, path)
+    }
+
+    /// Move file or directory to a path which does not exist yet
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FatError> {
+        fat_write::rename(self, from, to)
+    }
+
+    /// Set read-only, hidden, system and archive attributes of entry at path
+    pub fn set_attributes(
+        &mut self,
+        path: &str,
+        attribute: u8,
+    ) -> Result<(), FatError> {
+        fat_write::set_attributes(self, path, attribute)
+    }
+
+    /// Set read-only, hidden, system and archive attributes of entry with
+    /// inode, which must have been looked up
+    pub fn set_inode_attributes(
+        &mut self,
+        inode: u32,
+        attribute: u8,
+    ) -> Result<(), FatError> {
+        fat_write::set_inode_attributes(self, inode, attribute)
+    }
+
+    /// Write changes to the FAT out to every FAT of the image
+    pub fn flush(&mut self) -> Result<(), FatError> {
+        flush_fat(self)
+    }
+
     /// Get OEM name
     pub fn oem_name(&self) -> &str {
         str::from_utf8(&self.bs.oem_name).unwrap()
@@ -157,4 +459,48 @@ impl Fat {
     pub fn is_fat32(&self) -> bool {
         self.fat_type == FatType::Fat32
     }
+
+    /// Get count of clusters in data region
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// Get size of clusters in bytes
+    pub fn cluster_size(&self) -> u32 {
+        cluster_size(self)
+    }
+
+    /// Get BIOS parameter block
+    pub fn bpb(&self) -> &FatBpb {
+        &self.bpb
+    }
+
+    /// Get extended BIOS parameter block of FAT12/16 volume
+    pub fn ebpb(&self) -> Option<&FatEbpb> {
+        self.ebpb16.as_ref()
+    }
+
+    /// Get extended BIOS parameter block of FAT32 volume
+    pub fn ebpb32(&self) -> Option<&Fat32Ebpb> {
+        self.ebpb32.as_ref()
+    }
+
+    /// Get volume ID (serial number) from the EBPB
+    pub fn volume_id(&self) -> u32 {
+        u32::from_le_bytes(match (&self.ebpb16, &self.ebpb32) {
+            (Some(ebpb), _) => ebpb.volume_id,
+            (_, Some(ebpb)) => ebpb.volume_id,
+            _ => unreachable!(),
+        })
+    }
+
+    /// Get volume label from the EBPB, without padding
+    pub fn volume_label(&self) -> String {
+        let label = match (&self.ebpb16, &self.ebpb32) {
+            (Some(ebpb), _) => &ebpb.volume_label,
+            (_, Some(ebpb)) => &ebpb.volume_label,
+            _ => unreachable!(),
+        };
+        String::from_utf8_lossy(label).trim_end().to_string()
+    }
 }
@@ -0,0 +1,1470 @@
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+extern crate libc;
+
+extern crate lib_fat;
+use lib_fat::{
+    dir_inode, is_dir, Fat, FatCarvedFile, FatDateTime, FatDeletedEntry,
+    FatDirectoryEntryContainer, FatError, FatExtent, FatFileType, FatFormat,
+    FatSortOrder, FatType, CHANGEABLE_ATTRIBUTES,
+};
+
+use super::fat_time::{
+    parse_access_date, parse_create_time, parse_modify_time, systemtime_to_fat,
+    FatTimeZone,
+};
+
+/// Result of a subcommand, errors are printed before exiting
+pub type CliResult = Result<(), String>;
+
+/// Resolves path such as "/dir/file.txt", names are case insensitive
+/// Returns the entry at path, None for the root directory
+pub fn resolve(
+    fat: &mut Fat,
+    path: &str,
+) -> Result<Option<FatDirectoryEntryContainer>, String> {
+    fat.entry_at(path).map_err(path_error(path))
+}
+
+/// Whether entry is listed, volume labels and dot entries are not files
+fn is_listed(entry: &FatDirectoryEntryContainer) -> bool {
+    let name = entry.get_name();
+    entry.attribute() & FatFileType::AttrVolumeId as u8 == 0
+        && name != "."
+        && name != ".."
+}
+
+/// Formats attributes in the order R, H, S, V, D, A, '-' if not set
+pub fn attribute_string(attribute: u8) -> String {
+    let flags = [
+        (FatFileType::AttrReadOnly as u8, 'R'),
+        (FatFileType::AttrHidden as u8, 'H'),
+        (FatFileType::AttrSystem as u8, 'S'),
+        (FatFileType::AttrVolumeId as u8, 'V'),
+        (FatFileType::AttrDirectory as u8, 'D'),
+        (FatFileType::AttrArchive as u8, 'A'),
+    ];
+    flags
+        .iter()
+        .map(|(flag, c)| if attribute & flag != 0 { *c } else { '-' })
+        .collect()
+}
+
+/// Formats date and time as recorded, "YYYY-MM-DD HH:MM:SS"
+fn time_string(time: (u16, u8, u8, u8, u8, u8)) -> String {
+    let (year, month, day, hour, minute, second) = time;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Lists entries of directory with given inode
+fn list_entries(
+    fat: &mut Fat,
+    inode: u32,
+) -> Result<&Vec<FatDirectoryEntryContainer>, String> {
+    match fat.list_directory(inode) {
+        Err(e) => Err(e.to_string()),
+        Ok(None) => Err(format!("Cannot read directory {}", inode)),
+        Ok(Some(dir)) => Ok(dir),
+    }
+}
+
+/// Prints entry on a line, with attributes, size and time if long
+fn print_entry(entry: &FatDirectoryEntryContainer, long: bool) {
+    if long {
+        println!(
+            "{} {:>10} {} {}",
+            attribute_string(entry.attribute()),
+            entry.size(),
+            time_string(entry.get_write_time()),
+            entry.get_name()
+        );
+    } else {
+        println!("{}", entry.get_name());
+    }
+}
+
+/// ls: list directory, or a single file
+pub fn ls(fat: &mut Fat, path: &str, long: bool) -> CliResult {
+    let inode = match resolve(fat, path)? {
+        None => fat.get_root_cluster_number(),
+        Some(entry) if !is_dir(&entry) => {
+            print_entry(&entry, long);
+            return Ok(());
+        }
+        Some(entry) => dir_inode(fat, entry.cluster_number()),
+    };
+    for entry in list_entries(fat, inode)? {
+        if is_listed(entry) {
+            print_entry(entry, long);
+        }
+    }
+    Ok(())
+}
+
+/// Reads contents of file at path, along with its name
+fn read_file(fat: &mut Fat, path: &str) -> Result<(String, Vec<u8>), String> {
+    let entry = match resolve(fat, path)? {
+        Some(entry) if !is_dir(&entry) => entry,
+        _ => return Err(format!("{}: Is a directory", path)),
+    };
+    let (name, cluster_number, size) = (
+        entry.get_name().clone(),
+        entry.cluster_number(),
+        entry.size(),
+    );
+    // Empty files have no clusters
+    if size == 0 {
+        return Ok((name, vec![]));
+    }
+    match fat.get_data(cluster_number, 0, size) {
+        Err(e) => Err(format!("{}: {}", path, e)),
+        Ok(None) => Err(format!("{}: Cannot read file", path)),
+        Ok(Some(data)) => Ok((name, data)),
+    }
+}
+
+/// cat: write contents of file to stdout
+pub fn cat(fat: &mut Fat, path: &str) -> CliResult {
+    let (_, data) = read_file(fat, path)?;
+    std::io::stdout()
+        .write_all(&data)
+        .map_err(|e| e.to_string())
+}
+
+/// stat: print everything known about an entry
+pub fn stat(fat: &mut Fat, path: &str) -> CliResult {
+    let is_fat32 = fat.is_fat32();
+    let entry = match resolve(fat, path)? {
+        None => {
+            println!("{:<16}/", "Name:");
+            println!("{:<16}directory", "Type:");
+            println!(
+                "{:<16}{}",
+                "First cluster:",
+                fat.get_root_cluster_number()
+            );
+            return Ok(());
+        }
+        Some(entry) => entry,
+    };
+    let kind = if is_dir(&entry) { "directory" } else { "file" };
+    let (year, month, day, hour, minute, second, centiseconds) =
+        entry.get_creation_time();
+    let (access_year, access_month, access_day) =
+        entry.get_last_accessed_date();
+
+    println!("{:<16}{}", "Name:", entry.get_name());
+    println!("{:<16}{}", "Short name:", entry.get_short_name());
+    println!("{:<16}{}", "Type:", kind);
+    println!("{:<16}{}", "Size:", entry.size());
+    println!(
+        "{:<16}{} (0x{:02X})",
+        "Attributes:",
+        attribute_string(entry.attribute()),
+        entry.attribute()
+    );
+    println!("{:<16}{}", "First cluster:", entry.cluster_number());
+    println!("{:<16}{}", "Clusters:", entry.cluster_count(is_fat32));
+    println!("{:<16}{}", "Modified:", time_string(entry.get_write_time()));
+    println!(
+        "{:<16}{:04}-{:02}-{:02}",
+        "Accessed:", access_year, access_month, access_day
+    );
+    // Centiseconds count up to 1.99 seconds past the 2 second stamp
+    let centiseconds = centiseconds.min(199);
+    let second = second + centiseconds / 100;
+    println!(
+        "{:<16}{}.{:02}",
+        "Created:",
+        time_string((year, month, day, hour, minute, second)),
+        centiseconds % 100
+    );
+    Ok(())
+}
+
+/// tree: print directory hierarchy of volume
+pub fn tree(fat: &mut Fat) -> CliResult {
+    let entries = fat.walk("/").map_err(path_error("/"))?;
+    let depths: Vec<usize> = entries
+        .iter()
+        .map(|(path, _)| path.matches('/').count())
+        .collect();
+
+    println!("/");
+    // Whether the directory at each level above the entry was the last one
+    let mut last_above: Vec<bool> = vec![];
+    for (index, (_, entry)) in entries.iter().enumerate() {
+        let depth = depths[index];
+        // Siblings follow the contents of an entry, before any shallower one
+        let next = depths[index + 1..].iter().find(|next| **next <= depth);
+        let last = next != Some(&depth);
+        last_above.truncate(depth - 1);
+        let prefix: String = last_above
+            .iter()
+            .map(|last| if *last { "    " } else { "|   " })
+            .collect();
+        let branch = if last { "`-- " } else { "|-- " };
+        println!("{}{}{}", prefix, branch, entry.get_name());
+        last_above.push(last);
+    }
+    Ok(())
+}
+
+/// info: dump boot sector and parameter blocks
+pub fn info(fat: &Fat) -> CliResult {
+    println!("{:<24}{}", "OEM name:", fat.oem_name().trim_end());
+    println!("{:<24}{}", "FAT type:", fat.fat_type());
+    println!("{:<24}{}", "Cluster count:", fat.cluster_count());
+    print!("{}", fat.bpb());
+    if let Some(ebpb) = fat.ebpb() {
+        print!("{}", ebpb);
+    }
+    if let Some(ebpb32) = fat.ebpb32() {
+        print!("{}", ebpb32);
+    }
+    Ok(())
+}
+
+/// Splits "IMAGE::/path" into the image and the path within it
+pub fn image_path(arg: &str) -> Option<(&str, &str)> {
+    arg.find("::")
+        .map(|index| (&arg[..index], &arg[index + 2..]))
+}
+
+/// Path within the volume for an entry named name, placed inside path if
+/// it is an existing directory
+fn target_path(fat: &mut Fat, path: &str, name: &str) -> String {
+    let inside = match resolve(fat, path) {
+        Ok(None) => true,
+        Ok(Some(entry)) => is_dir(&entry),
+        Err(_) => false,
+    };
+    if inside {
+        format!("{}/{}", path.trim_end_matches('/'), name)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Writes changes out to the image
+fn flush(fat: &mut Fat) -> CliResult {
+    fat.flush()
+        .map_err(|e| format!("Cannot write image: {}", e))
+}
+
+/// Formats error of operation on path
+fn path_error(path: &str) -> impl Fn(FatError) -> String + '_ {
+    move |e| format!("{}: {}", path, e)
+}
+
+/// cp: copy host file into the volume
+pub fn copy_in(fat: &mut Fat, host_path: &str, path: &str) -> CliResult {
+    let host_error = |e: std::io::Error| format!("{}: {}", host_path, e);
+    let data = std::fs::read(host_path).map_err(host_error)?;
+    let modified = std::fs::metadata(host_path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(host_error)?;
+
+    let name = Path::new(host_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let path = target_path(fat, path, &name);
+    let created = systemtime_to_fat(SystemTime::now(), FatTimeZone::Local);
+    let modified = systemtime_to_fat(modified, FatTimeZone::Local);
+    fat.write_file(&path, &data, &created, &modified)
+        .map_err(path_error(&path))?;
+    flush(fat)
+}
+
+/// cp: copy file out of the volume to the host
+pub fn copy_out(fat: &mut Fat, path: &str, host_path: &str) -> CliResult {
+    let (name, data) = read_file(fat, path)?;
+    // Copying onto a directory places the file inside it
+    let host_path = Path::new(host_path);
+    let host_path = if host_path.is_dir() {
+        host_path.join(name)
+    } else {
+        host_path.to_path_buf()
+    };
+    std::fs::write(&host_path, data)
+        .map_err(|e| format!("{}: {}", host_path.display(), e))
+}
+
+/// mkdir: create directory
+pub fn mkdir(fat: &mut Fat, path: &str) -> CliResult {
+    let created = systemtime_to_fat(SystemTime::now(), FatTimeZone::Local);
+    fat.create_dir(path, &created).map_err(path_error(path))?;
+    flush(fat)
+}
+
+/// rm: remove file or empty directory
+pub fn rm(fat: &mut Fat, path: &str) -> CliResult {
+    fat.remove(path).map_err(path_error(path))?;
+    flush(fat)
+}
+
+/// mv: move or rename file or directory
+pub fn mv(fat: &mut Fat, from: &str, to: &str) -> CliResult {
+    let name = from.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    let to = target_path(fat, to, name);
+    fat.rename(from, &to).map_err(path_error(from))?;
+    flush(fat)
+}
+
+/// attrib: show or change attributes, with changes such as "+r" or "-ah"
+pub fn attrib(fat: &mut Fat, path: &str, changes: &[&str]) -> CliResult {
+    let (name, mut attribute) = match resolve(fat, path)? {
+        None => {
+            return Err(format!("{}: Root directory has no attributes", path))
+        }
+        Some(entry) => (entry.get_name().clone(), entry.attribute()),
+    };
+    if changes.is_empty() {
+        println!("{} {}", attribute_string(attribute), name);
+        return Ok(());
+    }
+
+    for change in changes {
+        let invalid = || format!("Invalid attribute change '{}'", change);
+        let (set, flags) = match change.chars().next() {
+            Some('+') => (true, &change[1..]),
+            Some('-') => (false, &change[1..]),
+            _ => return Err(invalid()),
+        };
+        if flags.is_empty() {
+            return Err(invalid());
+        }
+        for flag in flags.chars() {
+            let bit = match flag.to_ascii_lowercase() {
+                'r' => FatFileType::AttrReadOnly as u8,
+                'h' => FatFileType::AttrHidden as u8,
+                's' => FatFileType::AttrSystem as u8,
+                'a' => FatFileType::AttrArchive as u8,
+                _ => return Err(invalid()),
+            };
+            if set {
+                attribute |= bit;
+            } else {
+                attribute &= !bit;
+            }
+        }
+    }
+    fat.set_attributes(path, attribute & CHANGEABLE_ATTRIBUTES)
+        .map_err(path_error(path))?;
+    flush(fat)
+}
+
+/// Path as C string for libc calls
+fn c_path(path: &Path) -> Result<CString, String> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| format!("{}: Invalid path", path.display()))
+}
+
+/// Formats last OS error for host path
+fn host_error(path: &Path) -> String {
+    format!("{}: {}", path.display(), std::io::Error::last_os_error())
+}
+
+/// Sets access and modification times of host file, in seconds
+fn set_times(path: &Path, atime: i64, mtime: i64) -> CliResult {
+    let c_path = c_path(path)?;
+    let times = [
+        libc::timespec {
+            tv_sec: atime as libc::time_t,
+            tv_nsec: 0,
+        },
+        libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0,
+        },
+    ];
+    let result = unsafe {
+        libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0)
+    };
+    if result != 0 {
+        return Err(host_error(path));
+    }
+    Ok(())
+}
+
+/// Sets extended attribute of host file
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> CliResult {
+    let c_path = c_path(path)?;
+    let c_name = CString::new(name).unwrap();
+    let result = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(host_error(path));
+    }
+    Ok(())
+}
+
+/// extract: recreate tree below path inside host directory
+/// Contents of a directory are placed in dest, a file is placed inside it
+pub fn extract(
+    fat: &mut Fat,
+    path: &str,
+    dest: &str,
+    xattrs: bool,
+    tz: FatTimeZone,
+) -> CliResult {
+    let entries = fat.walk(path).map_err(path_error(path))?;
+    let dest = Path::new(dest);
+    std::fs::create_dir_all(dest)
+        .map_err(|e| format!("{}: {}", dest.display(), e))?;
+
+    // Paths are spelled as stored, starting with path itself unless the root
+    let prefix = match entries.first() {
+        Some((first, _)) if !path.trim_end_matches('/').is_empty() => {
+            first.clone()
+        }
+        _ => String::new(),
+    };
+    let mut extracted: Vec<(PathBuf, &FatDirectoryEntryContainer)> = vec![];
+    for (entry_path, entry) in entries.iter() {
+        let relative = if *entry_path == prefix {
+            if is_dir(entry) {
+                continue;
+            }
+            entry.get_name().as_str()
+        } else {
+            &entry_path[prefix.len()..]
+        };
+        // Broken names must not lead outside of dest
+        let host_path = relative.split('/').filter(|c| !c.is_empty()).fold(
+            dest.to_path_buf(),
+            |host_path, component| match component {
+                "." | ".." => host_path.join("_"),
+                _ => host_path.join(component),
+            },
+        );
+        let io_error =
+            |e: std::io::Error| format!("{}: {}", host_path.display(), e);
+
+        if is_dir(entry) {
+            if !host_path.is_dir() {
+                std::fs::create_dir(&host_path).map_err(io_error)?;
+            }
+        } else {
+            let data = if entry.size() == 0 {
+                vec![]
+            } else {
+                match fat.get_data(entry.cluster_number(), 0, entry.size()) {
+                    Err(e) => return Err(format!("{}: {}", entry_path, e)),
+                    Ok(None) => {
+                        return Err(format!("{}: Cannot read file", entry_path))
+                    }
+                    Ok(Some(data)) => data,
+                }
+            };
+            std::fs::write(&host_path, data).map_err(io_error)?;
+        }
+
+        if xattrs {
+            set_xattr(&host_path, "user.fat.attributes", &[entry.attribute()])?;
+            set_xattr(
+                &host_path,
+                "user.fat.shortname",
+                entry.get_short_name().as_bytes(),
+            )?;
+        }
+        // Read-only files lose their write permissions
+        if !is_dir(entry)
+            && entry.attribute() & FatFileType::AttrReadOnly as u8 != 0
+        {
+            let mut permissions = std::fs::metadata(&host_path)
+                .map_err(io_error)?
+                .permissions();
+            permissions.set_mode(permissions.mode() & !0o222);
+            std::fs::set_permissions(&host_path, permissions)
+                .map_err(io_error)?;
+        }
+        extracted.push((host_path, entry));
+    }
+
+    // Set times last, as creating files updates times of their directory
+    for (host_path, entry) in extracted.iter().rev() {
+        let atime = parse_access_date(entry, tz);
+        let mtime = parse_modify_time(entry, tz);
+        set_times(host_path, atime, mtime)?;
+    }
+    Ok(())
+}
+
+/// tar: write tar archive of tree below path to stdout
+pub fn tar(fat: &mut Fat, path: &str, tz: FatTimeZone) -> CliResult {
+    let stdout = std::io::stdout();
+    let writer = std::io::BufWriter::new(stdout.lock());
+    fat.export_tar(path, writer, |entry| parse_modify_time(entry, tz))
+        .map_err(path_error(path))
+}
+
+/// Permissions in the form of ls, preceded by the type, such as
+/// "d/drwxr-xr-x", read-only entries lose their write permissions
+fn mode_string(entry: &FatDirectoryEntryContainer) -> String {
+    let (kind, mut mode) = if is_dir(entry) {
+        ('d', 0o755)
+    } else {
+        ('r', 0o644)
+    };
+    if entry.attribute() & FatFileType::AttrReadOnly as u8 != 0 {
+        mode &= !0o222;
+    }
+    let permissions: String = (0..9)
+        .rev()
+        .map(|bit| match mode & 1 << bit {
+            0 => '-',
+            _ => ['x', 'w', 'r'][bit % 3],
+        })
+        .collect();
+    format!("{}/{}{}", kind, kind, permissions)
+}
+
+/// Line of bodyfile for entry at path, FAT records no change time
+fn body_line(
+    path: &str,
+    entry: &FatDirectoryEntryContainer,
+    tz: FatTimeZone,
+) -> String {
+    format!(
+        "0|{}|{}|{}|0|0|{}|{}|{}|0|{}",
+        path,
+        entry.cluster_number(),
+        mode_string(entry),
+        entry.size(),
+        parse_access_date(entry, tz),
+        parse_modify_time(entry, tz),
+        parse_create_time(entry, tz).0
+    )
+}
+
+/// timeline: print bodyfile of every entry, and of removed entries if deleted
+/// Removed entries are marked as such after their name, as by fls
+pub fn timeline(fat: &mut Fat, deleted: bool, tz: FatTimeZone) -> CliResult {
+    let entries = fat.walk("/").map_err(path_error("/"))?;
+    for (entry_path, entry) in entries.iter() {
+        println!("{}", body_line(entry_path, entry, tz));
+    }
+    if !deleted {
+        return Ok(());
+    }
+
+    let mut dirs = vec!["/".to_string()];
+    dirs.extend(
+        entries
+            .into_iter()
+            .filter(|(_, entry)| is_dir(entry))
+            .map(|(entry_path, _)| entry_path),
+    );
+    for dir in dirs.iter() {
+        for removed in fat.deleted_entries(dir).map_err(path_error(dir))? {
+            let entry_path = format!(
+                "{}/{} (deleted)",
+                dir.trim_end_matches('/'),
+                removed.entry.get_name()
+            );
+            println!("{}", body_line(&entry_path, &removed.entry, tz));
+        }
+    }
+    Ok(())
+}
+
+/// Formats extent as a range of clusters
+pub fn extent_string(extent: &FatExtent) -> String {
+    if extent.length == 1 {
+        format!("{}", extent.start)
+    } else {
+        format!("{}-{}", extent.start, extent.start + extent.length - 1)
+    }
+}
+
+/// Share of part in whole in percent, none of nothing
+fn percentage(part: u32, whole: u32) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// frag: print fragments of entries below path, and free space of volume
+/// Entries without clusters are left out, map lists the runs of clusters
+pub fn frag(fat: &mut Fat, path: &str, map: bool) -> CliResult {
+    let mut paths: Vec<String> = vec![];
+    // Only the FAT32 root directory has clusters of its own
+    if path.trim_end_matches('/').is_empty() && fat.is_fat32() {
+        paths.push("/".to_string());
+    }
+    for (entry_path, entry) in fat.walk(path).map_err(path_error(path))? {
+        if entry.cluster_number() != 0 {
+            paths.push(entry_path);
+        }
+    }
+
+    println!("{:>9} {:>9}  Path", "Fragments", "Clusters");
+    let mut files = 0;
+    let mut fragmented = 0;
+    for entry_path in paths.iter() {
+        let extents = match fat.file_extents(entry_path) {
+            Ok(extents) => extents,
+            // Broken chains do not stop the report
+            Err(e) => {
+                eprintln!("{}: {}", entry_path, e);
+                continue;
+            }
+        };
+        let clusters: u32 = extents.iter().map(|extent| extent.length).sum();
+        println!("{:>9} {:>9}  {}", extents.len(), clusters, entry_path);
+        if map {
+            for extent in extents.iter() {
+                println!("{:>21}{}", "", extent_string(extent));
+            }
+        }
+        files += 1;
+        if extents.len() > 1 {
+            fragmented += 1;
+        }
+    }
+
+    let free_extents = fat.free_extents();
+    let free: u32 = free_extents.iter().map(|extent| extent.length).sum();
+    println!();
+    println!("{:<24}{}", "Files:", files);
+    let percentage = percentage(fragmented, files);
+    println!("{:<24}{} ({:.1}%)", "Fragmented:", fragmented, percentage);
+    println!("{:<24}{}", "Free clusters:", free);
+    match free_extents.iter().max_by_key(|extent| extent.length) {
+        None => println!("{:<24}none", "Largest free extent:"),
+        Some(largest) => println!(
+            "{:<24}{} clusters ({} bytes) at {}",
+            "Largest free extent:",
+            largest.length,
+            largest.length as u64 * fat.cluster_size() as u64,
+            extent_string(largest)
+        ),
+    }
+    Ok(())
+}
+
+/// carve: write files found in free clusters into host directory, with the
+/// clusters they were found in
+pub fn carve(fat: &mut Fat, dest: &str) -> CliResult {
+    let dest = Path::new(dest);
+    std::fs::create_dir_all(dest).map_err(|_| host_error(dest))?;
+    let carved: Vec<FatCarvedFile> = fat
+        .carve()
+        .map_err(|e| format!("Cannot scan free clusters: {}", e))?;
+
+    println!("{:>10}  {:<14}Clusters", "Size", "Name");
+    for file in carved.iter() {
+        // Named by the first cluster, which tells files apart
+        let name =
+            format!("{:08}.{}", file.extents[0].start, file.kind.extension());
+        let data = fat.read_carved(file).map_err(path_error(&name))?;
+        let host_path = dest.join(&name);
+        std::fs::write(&host_path, data).map_err(|_| host_error(&host_path))?;
+        let clusters: Vec<String> =
+            file.extents.iter().map(extent_string).collect();
+        println!("{:>10}  {:<14}{}", file.size, name, clusters.join(","));
+    }
+    Ok(())
+}
+
+/// Parses sort order, a list file takes precedence over the order by name
+pub fn parse_sort_order(
+    by: &str,
+    list_file: Option<&str>,
+) -> Result<FatSortOrder, String> {
+    if let Some(list_file) = list_file {
+        let list = std::fs::read_to_string(list_file)
+            .map_err(|e| format!("{}: {}", list_file, e))?;
+        let names = list
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect();
+        return Ok(FatSortOrder::List(names));
+    }
+    match by.to_lowercase().as_str() {
+        "natural" => Ok(FatSortOrder::Natural),
+        "ascii" => Ok(FatSortOrder::Ascii),
+        "mtime" => Ok(FatSortOrder::Modified),
+        _ => Err(format!("Invalid sort order '{}'", by)),
+    }
+}
+
+/// Path of directory followed by those of its subdirectories if recursive
+fn dirs_below(
+    fat: &mut Fat,
+    path: &str,
+    recursive: bool,
+) -> Result<Vec<String>, String> {
+    let mut dirs = vec![path.to_string()];
+    if recursive {
+        let entries = fat.walk(path).map_err(path_error(path))?;
+        // Below the root, path itself comes first
+        let skip = if path.trim_end_matches('/').is_empty() {
+            0
+        } else {
+            1
+        };
+        dirs.extend(
+            entries
+                .into_iter()
+                .skip(skip)
+                .filter(|(_, entry)| is_dir(entry))
+                .map(|(entry_path, _)| entry_path),
+        );
+    }
+    Ok(dirs)
+}
+
+/// sort: rewrite entries of directory in order, and of subdirectories
+pub fn sort(
+    fat: &mut Fat,
+    path: &str,
+    order: &FatSortOrder,
+    recursive: bool,
+) -> CliResult {
+    for dir in dirs_below(fat, path, recursive)?.iter() {
+        fat.sort_directory(dir, order).map_err(path_error(dir))?;
+    }
+    flush(fat)
+}
+
+/// compact: drop free slots of directory, and of subdirectories
+pub fn compact(fat: &mut Fat, path: &str, recursive: bool) -> CliResult {
+    for dir in dirs_below(fat, path, recursive)?.iter() {
+        let dropped = fat.compact_directory(dir).map_err(path_error(dir))?;
+        if dropped > 0 {
+            println!("{}: {} free slots dropped", dir, dropped);
+        }
+    }
+    flush(fat)
+}
+
+/// Whether deleted entry goes by name, "?" in its name matches any character
+fn deleted_matches(deleted: &FatDeletedEntry, name: &str) -> bool {
+    let name = name.to_lowercase();
+    [deleted.entry.get_name(), &deleted.entry.get_short_name()]
+        .iter()
+        .any(|deleted_name| {
+            let deleted_name = deleted_name.to_lowercase();
+            deleted_name.chars().count() == name.chars().count()
+                && deleted_name
+                    .chars()
+                    .zip(name.chars())
+                    .all(|(a, b)| a == b || a == '?')
+        })
+}
+
+/// Asks for the first character of the short name of entry on the terminal
+fn ask_first_char(name: &str) -> Result<u8, String> {
+    let unknown = format!("{}: First character of name is not known", name);
+    if unsafe { libc::isatty(0) } == 0 {
+        return Err(unknown);
+    }
+    print!("First character of {}: ", name);
+    std::io::stdout().flush().map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    match line.trim().as_bytes() {
+        [c] => Ok(*c),
+        _ => Err(unknown),
+    }
+}
+
+/// What undelete does for a path
+pub enum Undelete {
+    // List entries removed from the directory at path
+    List(Vec<FatDeletedEntry>),
+    // Restore removed entry, given the first character of its name if needed
+    Restore(FatDeletedEntry, Option<u8>),
+}
+
+/// Works out whether path names a directory to list or a removed entry to
+/// restore, without writing to the image
+pub fn plan_undelete(fat: &mut Fat, path: &str) -> Result<Undelete, String> {
+    // Paths of removed entries are not found, or name a file which took over
+    // the name since, other errors are left to the caller
+    match fat.deleted_entries(path) {
+        Ok(deleted) => return Ok(Undelete::List(deleted)),
+        Err(FatError::NotFound) | Err(FatError::NotADirectory) => {}
+        Err(e) => return Err(path_error(path)(e)),
+    }
+
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let parent = if parent.is_empty() { "/" } else { parent };
+    let candidates: Vec<FatDeletedEntry> = fat
+        .deleted_entries(parent)
+        .map_err(path_error(parent))?
+        .into_iter()
+        .filter(|deleted| deleted_matches(deleted, name))
+        .collect();
+    if candidates.is_empty() {
+        return Err(format!("{}: No deleted entry of that name", path));
+    }
+    let deleted = candidates
+        .into_iter()
+        .find(|deleted| deleted.clusters.is_some())
+        .ok_or_else(|| path_error(path)(FatError::Overwritten))?;
+
+    // A name given in full supplies the first character
+    let first_char = match name.as_bytes().first() {
+        _ if deleted.first_char_known => None,
+        Some(c) if *c != b'?' => Some(*c),
+        _ => Some(ask_first_char(path)?),
+    };
+    Ok(Undelete::Restore(deleted, first_char))
+}
+
+/// undelete: list entries removed from directory, or restore entry at path
+pub fn undelete(fat: &mut Fat, path: &str, plan: &Undelete) -> CliResult {
+    match plan {
+        Undelete::List(deleted) => list_deleted(deleted),
+        Undelete::Restore(deleted, first_char) => {
+            fat.undelete(deleted, *first_char)
+                .map_err(path_error(path))?;
+            flush(fat)
+        }
+    }
+}
+
+/// Lists removed entries of a directory, whether their clusters are free
+fn list_deleted(deleted: &[FatDeletedEntry]) -> CliResult {
+    println!("{:>10} {:>8}  {:<12}Name", "Size", "Cluster", "Status");
+    for deleted in deleted {
+        let status = match deleted.clusters {
+            Some(_) => "recoverable",
+            None => "overwritten",
+        };
+        println!(
+            "{:>10} {:>8}  {:<12}{}",
+            deleted.entry.size(),
+            deleted.entry.cluster_number(),
+            status,
+            deleted.entry.get_name()
+        );
+    }
+    Ok(())
+}
+
+/// defrag: move fragmented entries below paths into single runs of clusters
+/// Entries given as first are placed at the start of the data region before
+pub fn defrag(fat: &mut Fat, paths: &[&str], first: &[&str]) -> CliResult {
+    if !first.is_empty() {
+        fat.place_at_start(first)
+            .map_err(|e| format!("Cannot place files first: {}", e))?;
+    }
+
+    let mut failed = 0;
+    for path in paths {
+        for (entry_path, _) in fat.walk(path).map_err(path_error(path))? {
+            match fat.defragment(&entry_path) {
+                Ok(true) => println!("{}", entry_path),
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("{}: {}", entry_path, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} entries are still fragmented", failed));
+    }
+    Ok(())
+}
+
+/// File or directory to copy into a new volume
+struct HostEntry {
+    name: String,
+    path: PathBuf,
+    modified: SystemTime,
+    // Entries of directory, sorted by name, None for files
+    children: Option<Vec<HostEntry>>,
+}
+
+/// Reads host directory tree, entries are sorted by name
+fn scan_host_dir(path: &Path) -> Result<Vec<HostEntry>, String> {
+    let io_error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let mut entries = vec![];
+    let mut names: HashSet<String> = HashSet::new();
+    for dir_entry in std::fs::read_dir(path).map_err(io_error)? {
+        let dir_entry = dir_entry.map_err(io_error)?;
+        let entry_path = dir_entry.path();
+        let name = dir_entry.file_name().into_string().map_err(|_| {
+            format!("{}: Name is not UTF-8", entry_path.display())
+        })?;
+        if !names.insert(name.to_lowercase()) {
+            return Err(format!(
+                "{}: Name differs from another only in case",
+                entry_path.display()
+            ));
+        }
+
+        // Links to files are followed, links to directories are not
+        let metadata = std::fs::metadata(&entry_path)
+            .map_err(|e| format!("{}: {}", entry_path.display(), e))?;
+        let is_link = dir_entry.file_type().map_err(io_error)?.is_symlink();
+        let children = if metadata.is_dir() && !is_link {
+            Some(scan_host_dir(&entry_path)?)
+        } else if metadata.is_file() {
+            None
+        } else {
+            eprintln!("{}: Skipping, not a regular file", entry_path.display());
+            continue;
+        };
+        entries.push(HostEntry {
+            name,
+            path: entry_path,
+            modified: metadata.modified().map_err(io_error)?,
+            children,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Directory slots taken by entries, counting long names generously
+fn slot_count(entries: &[HostEntry]) -> usize {
+    entries
+        .iter()
+        .map(|entry| 1 + entry.name.encode_utf16().count().div_ceil(13))
+        .sum()
+}
+
+/// Bytes taken by entries, rounded up to generous clusters
+fn content_size(entries: &[HostEntry]) -> u64 {
+    let cluster = 4096;
+    entries
+        .iter()
+        .map(|entry| match &entry.children {
+            Some(children) => cluster + content_size(children),
+            None => {
+                let size = std::fs::metadata(&entry.path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+                size.div_ceil(cluster) * cluster
+            }
+        })
+        .sum()
+}
+
+/// Time stamps of new entries, fixed if reproducible
+enum BuildTimes {
+    Fixed(FatDateTime),
+    Host(FatDateTime),
+}
+
+impl BuildTimes {
+    /// Creation and modification time of entry
+    fn of(&self, entry: &HostEntry) -> (FatDateTime, FatDateTime) {
+        match self {
+            BuildTimes::Fixed(time) => (*time, *time),
+            BuildTimes::Host(now) => {
+                (*now, systemtime_to_fat(entry.modified, FatTimeZone::Local))
+            }
+        }
+    }
+}
+
+/// Error while populating a new volume
+enum BuildError {
+    // Reading the host file failed
+    Host(String),
+    // Writing to the volume failed, at path
+    Volume(String, FatError),
+}
+
+/// Copies host entries into directory of volume at path
+fn populate(
+    fat: &mut Fat,
+    path: &str,
+    entries: &[HostEntry],
+    times: &BuildTimes,
+) -> Result<(), BuildError> {
+    let volume_error = |path: &str| {
+        let path = path.to_string();
+        move |e| BuildError::Volume(path, e)
+    };
+    for entry in entries {
+        let entry_path = format!("{}/{}", path, entry.name);
+        let (created, modified) = times.of(entry);
+        match &entry.children {
+            Some(children) => {
+                // Sized up front so directories stay contiguous
+                fat.create_dir_with_capacity(
+                    &entry_path,
+                    &created,
+                    slot_count(children),
+                )
+                .map_err(volume_error(&entry_path))?;
+                populate(fat, &entry_path, children, times)?;
+            }
+            None => {
+                let data = std::fs::read(&entry.path).map_err(|e| {
+                    BuildError::Host(format!("{}: {}", entry.path.display(), e))
+                })?;
+                fat.write_file(&entry_path, &data, &created, &modified)
+                    .map_err(volume_error(&entry_path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses size such as "1474560", "64M" or "2G"
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size '{}'", value);
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let size: u64 = digits.parse().map_err(|_| invalid())?;
+    size.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// Parses FAT type such as "fat16" or "16"
+pub fn parse_fat_type(value: &str) -> Result<FatType, String> {
+    match value.to_lowercase().trim_start_matches("fat") {
+        "12" => Ok(FatType::Fat12),
+        "16" => Ok(FatType::Fat16),
+        "32" => Ok(FatType::Fat32),
+        _ => Err(format!("Invalid FAT type '{}'", value)),
+    }
+}
+
+/// build: create image holding a copy of host directory
+/// Reproducible builds take time stamps and serial from SOURCE_DATE_EPOCH
+pub fn build(
+    src_dir: &str,
+    image_file: &str,
+    size: Option<u64>,
+    fat_type: Option<FatType>,
+    label: Option<&str>,
+    reproducible: bool,
+) -> CliResult {
+    let entries = scan_host_dir(Path::new(src_dir))?;
+
+    let now = SystemTime::now();
+    let (times, volume_id, created) = if reproducible {
+        // Start of FAT time if not set
+        let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+            Err(_) => 315_532_800,
+            Ok(value) => value.parse::<u64>().map_err(|_| {
+                format!("Invalid SOURCE_DATE_EPOCH '{}'", value)
+            })?,
+        };
+        let time = systemtime_to_fat(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(epoch),
+            FatTimeZone::Utc,
+        );
+        (BuildTimes::Fixed(time), epoch as u32, time)
+    } else {
+        let elapsed = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let time = systemtime_to_fat(now, FatTimeZone::Local);
+        let volume_id = elapsed.as_secs() as u32 ^ elapsed.subsec_nanos();
+        (BuildTimes::Host(time), volume_id, time)
+    };
+
+    // Without a size, start from an estimate and grow until everything fits
+    let minimum = match fat_type {
+        Some(FatType::Fat32) => 34 << 20,
+        Some(FatType::Fat16) => 4 << 20,
+        _ => 1_474_560,
+    };
+    let mut format = FatFormat {
+        size: size.unwrap_or_else(|| {
+            (content_size(&entries) * 5 / 4 + (1 << 20)).max(minimum)
+        }),
+        fat_type,
+        label: label.map(|label| label.to_string()),
+        volume_id,
+        root_slots: slot_count(&entries),
+        created,
+    };
+    loop {
+        let mut fat = Fat::format(image_file, &format)
+            .map_err(|e| format!("{}: {}", image_file, e))?;
+        match populate(&mut fat, "", &entries, &times) {
+            Ok(()) => return flush(&mut fat),
+            Err(BuildError::Volume(_, FatError::NoSpace)) if size.is_none() => {
+                format.size = format.size * 3 / 2;
+            }
+            Err(BuildError::Volume(path, e)) => {
+                return Err(format!("{}: {}", path, e))
+            }
+            Err(BuildError::Host(e)) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    use lib_fat::{Fat, FatExtent, FatType};
+    use time::{Date, Month, PrimitiveDateTime, Time};
+
+    use super::super::fat_test::{scratch_image, TempPath, STAMP};
+    use super::super::fat_time::{parse_modify_time, FatTimeZone};
+    use super::{
+        attribute_string, body_line, build, extent_string, extract, image_path,
+        parse_fat_type, parse_size, percentage, plan_undelete, resolve,
+        set_times, target_path, time_string, undelete, Undelete,
+    };
+
+    /// Image with a file in a subdirectory, DIR in cluster 3
+    const TIMES_IMAGE: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/times.img");
+
+    #[test]
+    fn resolves_paths_case_insensitively() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        assert!(resolve(&mut fat, "/").unwrap().is_none());
+        let carry = resolve(&mut fat, "carry.txt").unwrap().unwrap();
+        assert_eq!(carry.get_name(), "CARRY.TXT");
+        let inner = resolve(&mut fat, "/Dir//inner.TXT").unwrap().unwrap();
+        assert_eq!(inner.get_name(), "INNER.TXT");
+        assert_eq!(inner.cluster_number(), 4);
+    }
+
+    #[test]
+    fn reports_unresolved_paths() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        let error = |fat: &mut Fat, path| resolve(fat, path).unwrap_err();
+        assert_eq!(
+            error(&mut fat, "/missing.txt"),
+            "/missing.txt: No such file or directory"
+        );
+        assert_eq!(
+            error(&mut fat, "/DIR/missing/INNER.TXT"),
+            "/DIR/missing/INNER.TXT: No such file or directory"
+        );
+        assert_eq!(
+            error(&mut fat, "/CARRY.TXT/INNER.TXT"),
+            "/CARRY.TXT/INNER.TXT: Not a directory"
+        );
+    }
+
+    #[test]
+    fn formats_attributes_and_times() {
+        assert_eq!(attribute_string(0x00), "------");
+        assert_eq!(attribute_string(0x21), "R----A");
+        assert_eq!(attribute_string(0x10), "----D-");
+        assert_eq!(attribute_string(0x3F), "RHSVDA");
+        assert_eq!(
+            time_string((2020, 2, 29, 13, 37, 42)),
+            "2020-02-29 13:37:42"
+        );
+        assert_eq!(time_string((1980, 1, 1, 0, 0, 0)), "1980-01-01 00:00:00");
+    }
+
+    #[test]
+    fn splits_image_paths() {
+        assert_eq!(
+            image_path("disk.img::/DIR/INNER.TXT"),
+            Some(("disk.img", "/DIR/INNER.TXT"))
+        );
+        assert_eq!(image_path("disk.img::"), Some(("disk.img", "")));
+        assert_eq!(image_path("a::/b::c"), Some(("a", "/b::c")));
+        assert_eq!(image_path("notes.txt"), None);
+        assert_eq!(image_path("c:/notes.txt"), None);
+    }
+
+    #[test]
+    fn places_targets_inside_directories() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        assert_eq!(target_path(&mut fat, "/", "a.txt"), "/a.txt");
+        assert_eq!(target_path(&mut fat, "/dir/", "a.txt"), "/dir/a.txt");
+        assert_eq!(target_path(&mut fat, "/DIR", "a.txt"), "/DIR/a.txt");
+        assert_eq!(target_path(&mut fat, "/new.txt", "a.txt"), "/new.txt");
+        assert_eq!(target_path(&mut fat, "/CARRY.TXT", "a.txt"), "/CARRY.TXT");
+    }
+
+    /// Timestamp of local date and time
+    fn local(date: (i32, Month, u8), time: (u8, u8, u8)) -> i64 {
+        let date = Date::from_calendar_date(date.0, date.1, date.2).unwrap();
+        let time = Time::from_hms(time.0, time.1, time.2).unwrap();
+        FatTimeZone::Local.to_unix(PrimitiveDateTime::new(date, time))
+    }
+
+    /// Access and modification times of host file
+    fn times(path: &Path) -> (i64, i64) {
+        let metadata = std::fs::metadata(path).unwrap();
+        (metadata.atime(), metadata.mtime())
+    }
+
+    #[test]
+    fn extracts_tree_with_times_of_entries() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        let dest = std::env::temp_dir()
+            .join(format!("fat-fuse-extract-{}", std::process::id()));
+        let dest_dir = dest.to_str().unwrap();
+        let result =
+            extract(&mut fat, "/", dest_dir, false, FatTimeZone::Local);
+        let inner = dest.join("DIR/INNER.TXT");
+        // Times first, reading contents updates access times
+        let carry_times = times(&dest.join("CARRY.TXT"));
+        let dir_times = times(&dest.join("DIR"));
+        let inner_times = times(&inner);
+        let contents =
+            (std::fs::read(dest.join("CARRY.TXT")), std::fs::read(&inner));
+        let inner_mode = std::fs::metadata(&inner).map(|m| m.permissions());
+        std::fs::remove_dir_all(&dest).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(contents.1.unwrap(), b"inner\n");
+        assert_eq!(contents.0.unwrap().len(), 6);
+        // Read-only attribute removes write permissions
+        assert_eq!(inner_mode.unwrap().mode() & 0o222, 0);
+        let leap_day = (2020, Month::February, 29);
+        assert_eq!(
+            carry_times,
+            (
+                local((2020, Month::March, 1), (0, 0, 0)),
+                local(leap_day, (13, 37, 44))
+            )
+        );
+        // Times of directories are kept while files are created inside
+        assert_eq!(
+            dir_times,
+            (
+                local((2020, Month::January, 1), (0, 0, 0)),
+                local((2019, Month::December, 31), (23, 59, 58))
+            )
+        );
+        assert_eq!(
+            inner_times,
+            (
+                local((2021, Month::June, 16), (0, 0, 0)),
+                local((2021, Month::June, 15), (8, 0, 0))
+            )
+        );
+    }
+
+    #[test]
+    fn extracts_times_in_zone_of_image() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        let dest = std::env::temp_dir()
+            .join(format!("fat-fuse-extract-tz-{}", std::process::id()));
+        let tz = FatTimeZone::parse("+10:00").unwrap();
+        let result =
+            extract(&mut fat, "/CARRY.TXT", dest.to_str().unwrap(), false, tz);
+        let carry_times = times(&dest.join("CARRY.TXT"));
+        std::fs::remove_dir_all(&dest).unwrap();
+
+        assert_eq!(result, Ok(()));
+        let date = Date::from_calendar_date(2020, Month::February, 29).unwrap();
+        let time = Time::from_hms(13, 37, 44).unwrap();
+        let utc = FatTimeZone::Utc.to_unix(PrimitiveDateTime::new(date, time));
+        assert_eq!(carry_times.1, utc - 10 * 3600);
+    }
+
+    #[test]
+    fn formats_fragmentation() {
+        let extent = |start, length| FatExtent { start, length };
+        assert_eq!(extent_string(&extent(7, 1)), "7");
+        assert_eq!(extent_string(&extent(7, 3)), "7-9");
+        assert_eq!(percentage(0, 0), 0.0);
+        assert_eq!(percentage(0, 4), 0.0);
+        assert_eq!(percentage(1, 4), 25.0);
+        assert_eq!(format!("{:.1}", percentage(1, 3)), "33.3");
+        assert_eq!(percentage(5, 5), 100.0);
+    }
+
+    #[test]
+    fn formats_bodyfile_lines() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        let lines: Vec<String> = fat
+            .walk("/")
+            .unwrap()
+            .iter()
+            .map(|(path, entry)| body_line(path, entry, FatTimeZone::Utc))
+            .collect();
+        // Creation times are cut to seconds, FAT records no change time
+        assert_eq!(
+            lines,
+            [
+                "0|/CARRY.TXT|2|r/rrw-r--r--|0|0|6|1583020800|1582983464|0|1582983463",
+                "0|/CLAMP.TXT|0|r/rrw-r--r--|0|0|0|1609459200|1609459198|0|1609459199",
+                "0|/DIR|3|d/drwxr-xr-x|0|0|0|1577836800|1577836798|0|1577779200",
+                "0|/DIR/INNER.TXT|4|r/rr--r--r--|0|0|6|1623801600|1623744000|0|1623744000",
+            ]
+        );
+    }
+
+    /// Creates host tree below root from (path, contents), directories
+    /// having no contents
+    fn write_tree(root: &Path, entries: &[(&str, Option<&[u8]>)]) {
+        std::fs::create_dir_all(root).unwrap();
+        for (path, contents) in entries {
+            match contents {
+                None => std::fs::create_dir(root.join(path)).unwrap(),
+                Some(data) => std::fs::write(root.join(path), data).unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn builds_reproducible_images() {
+        let dir = TempPath::new("build-reproducible");
+        let src = dir.join("src");
+        write_tree(
+            &src,
+            &[
+                ("sub", None),
+                ("sub/inner.bin", Some(&[7; 5000])),
+                ("b.txt", Some(b"b")),
+                ("alpha", Some(b"")),
+                ("Zeta", Some(b"z")),
+                ("A long name.txt", Some(b"long")),
+            ],
+        );
+        std::env::set_var("SOURCE_DATE_EPOCH", "1600000000");
+        let images = [dir.join("one.img"), dir.join("two.img")];
+        for (seed, image) in images.iter().enumerate() {
+            // Times of host files do not matter
+            set_times(&src.join("b.txt"), seed as i64, seed as i64).unwrap();
+            let (src, image) = (src.to_str().unwrap(), image.to_str().unwrap());
+            build(src, image, None, None, Some("BUILT"), true).unwrap();
+        }
+        let first = std::fs::read(&images[0]).unwrap();
+        assert!(first == std::fs::read(&images[1]).unwrap());
+
+        let mut fat = Fat::mount_volume(images[0].to_str().unwrap()).unwrap();
+        assert_eq!(fat.volume_id(), 1_600_000_000);
+        assert_eq!(fat.volume_label(), "BUILT");
+        let entries = fat.walk("/").unwrap();
+        let paths: Vec<&str> =
+            entries.iter().map(|(path, _)| path.as_str()).collect();
+        // Entries are stored sorted by name
+        assert_eq!(
+            paths,
+            [
+                "/A long name.txt",
+                "/Zeta",
+                "/alpha",
+                "/b.txt",
+                "/sub",
+                "/sub/inner.bin"
+            ]
+        );
+        for (path, entry) in &entries {
+            let mtime = parse_modify_time(entry, FatTimeZone::Utc);
+            assert_eq!(mtime, 1_600_000_000, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_names_differing_only_in_case() {
+        let dir = TempPath::new("build-case");
+        let src = dir.join("src");
+        write_tree(
+            &src,
+            &[
+                ("sub", None),
+                ("sub/readme", Some(b"")),
+                ("sub/README", Some(b"")),
+            ],
+        );
+        let image = dir.join("image.img");
+        let (src, image) = (src.to_str().unwrap(), image.to_str().unwrap());
+        let result = build(src, image, Some(1_474_560), None, None, false);
+        let error = result.unwrap_err();
+        assert!(error.ends_with(": Name differs from another only in case"));
+    }
+
+    #[test]
+    fn grows_image_until_contents_fit() {
+        // Directories full of long names take far more room than estimated
+        let dir = TempPath::new("build-grow");
+        let src = dir.join("src");
+        for i in 0..60 {
+            let sub = src.join(format!("dir{}", i));
+            std::fs::create_dir_all(&sub).unwrap();
+            for j in 0..50 {
+                let name = format!("{:02}{}", j, "n".repeat(240));
+                std::fs::write(sub.join(name), b"").unwrap();
+            }
+        }
+        let image = dir.join("image.img");
+        let (src, image) = (src.to_str().unwrap(), image.to_str().unwrap());
+        build(src, image, None, None, None, true).unwrap();
+
+        assert!(std::fs::metadata(image).unwrap().len() > 1_474_560);
+        let mut fat = Fat::mount_volume(image).unwrap();
+        assert_eq!(fat.walk("/").unwrap().len(), 60 * 51);
+    }
+
+    #[test]
+    fn parses_sizes_and_fat_types() {
+        assert_eq!(parse_size("1474560"), Ok(1_474_560));
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        for invalid in ["", "M", "1.5M", "-1", "64MB", "99999999999G"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(parse_fat_type("fat12"), Ok(FatType::Fat12));
+        assert_eq!(parse_fat_type("FAT16"), Ok(FatType::Fat16));
+        assert_eq!(parse_fat_type("32"), Ok(FatType::Fat32));
+        for invalid in ["", "fat", "fat64", "vfat"] {
+            assert!(parse_fat_type(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn lists_removed_entries_without_writing() {
+        let (image, mut fat) = scratch_image("undelete-plan");
+        fat.write_file("/notes.txt", b"notes", &STAMP, &STAMP)
+            .unwrap();
+        fat.remove("/notes.txt").unwrap();
+        fat.flush().unwrap();
+        let before = std::fs::read(&*image).unwrap();
+
+        // Images opened read-only can be listed, restoring needs writing
+        let mut fat = Fat::mount_volume(image.to_str().unwrap()).unwrap();
+        let plan = plan_undelete(&mut fat, "/").unwrap();
+        match &plan {
+            Undelete::List(deleted) => assert_eq!(deleted.len(), 1),
+            Undelete::Restore(..) => panic!("Directory is not restored"),
+        }
+        assert_eq!(undelete(&mut fat, "/", &plan), Ok(()));
+        match plan_undelete(&mut fat, "/notes.txt").unwrap() {
+            Undelete::Restore(deleted, _) => {
+                assert_eq!(deleted.entry.get_name(), "notes.txt")
+            }
+            Undelete::List(_) => panic!("File is not listed"),
+        }
+        assert!(std::fs::read(&*image).unwrap() == before);
+    }
+}
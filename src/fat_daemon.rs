@@ -0,0 +1,174 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::thread::JoinHandle;
+
+extern crate libc;
+
+use fuser::BackgroundSession;
+
+/// Signals which unmount the filesystem
+const EXIT_SIGNALS: [libc::c_int; 3] =
+    [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Child process which reports back to the waiting parent
+pub struct Daemon {
+    // Write end of pipe to parent
+    pipe: File,
+}
+
+/// Forks into the background
+/// The parent waits for the child to report and exits, 0 if the child reports
+/// it is ready, 1 if the child exits without doing so
+pub fn daemonize() -> Daemon {
+    let (read_end, write_end) = match pipe() {
+        Ok(ends) => ends,
+        Err(_) => {
+            eprintln!("Cannot create pipe for daemon");
+            std::process::exit(1);
+        }
+    };
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            eprintln!("Cannot fork daemon");
+            std::process::exit(1);
+        }
+        0 => {
+            // Child, detach from the session of the parent
+            drop(read_end);
+            unsafe { libc::setsid() };
+            Daemon { pipe: write_end }
+        }
+        _ => {
+            // Parent, wait for child to report or exit
+            drop(write_end);
+            std::process::exit(child_status(read_end))
+        }
+    }
+}
+
+/// Creates pipe, returning its read and write ends
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Exit status of the parent once the child reports on pipe or exits
+fn child_status(mut pipe: File) -> i32 {
+    let mut status = [0u8; 1];
+    match pipe.read(&mut status) {
+        Ok(1) if status[0] == 0 => 0,
+        _ => 1,
+    }
+}
+
+impl Daemon {
+    /// Report readiness to parent and release the terminal
+    pub fn ready(mut self) {
+        let _ = self.pipe.write_all(&[0]);
+        unsafe {
+            libc::chdir(b"/\0".as_ptr() as *const libc::c_char);
+            let null = CString::new("/dev/null").unwrap();
+            let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+            if fd >= 0 {
+                libc::dup2(fd, libc::STDIN_FILENO);
+                libc::dup2(fd, libc::STDOUT_FILENO);
+                libc::dup2(fd, libc::STDERR_FILENO);
+                if fd > libc::STDERR_FILENO {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+}
+
+/// Blocks exit signals so they are only picked up by wait_for_exit
+/// Must be called before the session thread is spawned, which inherits it
+pub fn block_exit_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in EXIT_SIGNALS.iter() {
+            libc::sigaddset(&mut set, *signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    }
+}
+
+/// Waits until an exit signal arrives or the filesystem is unmounted, which
+/// ends the session
+pub fn wait_for_exit(set: &libc::sigset_t, finished: impl Fn() -> bool) {
+    let timeout = libc::timespec {
+        tv_sec: 1,
+        tv_nsec: 0,
+    };
+    while !finished() {
+        let signal =
+            unsafe { libc::sigtimedwait(set, std::ptr::null_mut(), &timeout) };
+        if signal > 0 {
+            break;
+        }
+    }
+}
+
+/// Unmounts the filesystem and waits for its session to end
+/// Unlike BackgroundSession::join, errors are returned rather than panicking
+pub fn unmount(session: BackgroundSession) -> io::Result<()> {
+    match session_thread(session).join() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::other("Session panicked")),
+    }
+}
+
+/// Takes thread out of session, the rest of the session is dropped on return,
+/// which unmounts the filesystem and so ends the thread
+fn session_thread(session: BackgroundSession) -> JoinHandle<io::Result<()>> {
+    session.guard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{block_exit_signals, child_status, pipe, wait_for_exit};
+
+    #[test]
+    fn reports_status_of_child() {
+        let (read_end, mut write_end) = pipe().unwrap();
+        write_end.write_all(&[0]).unwrap();
+        assert_eq!(child_status(read_end), 0);
+
+        // Child exits before it is ready
+        let (read_end, write_end) = pipe().unwrap();
+        drop(write_end);
+        assert_eq!(child_status(read_end), 1);
+    }
+
+    #[test]
+    fn exits_on_signal_while_mount_is_busy() {
+        // Signal is sent to this thread only, so it stays pending until waited
+        // for instead of ending the test process
+        let set = block_exit_signals();
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGTERM) };
+        let start = Instant::now();
+        wait_for_exit(&set, || false);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn exits_once_session_ends() {
+        let set = block_exit_signals();
+        let session =
+            thread::spawn(|| thread::sleep(Duration::from_millis(10)));
+        wait_for_exit(&set, || session.is_finished());
+        assert!(session.is_finished());
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashSet;
+
+extern crate lib_fat;
+use lib_fat::{is_dir, Fat, FatDeletedEntry};
+
+use fuser::FileType;
+
+/// Name of directory in the root holding removed files
+pub const DELETED_DIR_NAME: &str = ".deleted";
+/// Inode of that directory, above any cluster number, its contents follow
+pub const DELETED_DIR_INODE: u64 = 1 << 32;
+
+/// Directory holding further nodes, or removed file
+enum DeletedKind {
+    Dir(Vec<usize>),
+    File(FatDeletedEntry),
+}
+
+/// Node of the tree of removed files
+struct DeletedNode {
+    name: String,
+    // Index of directory holding node, the top directory holds itself
+    parent: usize,
+    kind: DeletedKind,
+}
+
+/// Removed files which can still be read, placed at their original paths
+/// below the top directory
+pub struct DeletedTree {
+    nodes: Vec<DeletedNode>,
+}
+
+impl DeletedTree {
+    /// Collects removed files of every directory whose clusters are still free
+    /// Removed directories are left out, as their entries cannot be trusted
+    /// Directories which cannot be read are skipped with a warning
+    pub fn scan(fat: &mut Fat) -> DeletedTree {
+        let mut found: Vec<(String, FatDeletedEntry)> = vec![];
+        let mut visited = HashSet::new();
+        let mut dirs = vec![("/".to_string(), fat.get_root_cluster_number())];
+        while let Some((dir, inode)) = dirs.pop() {
+            // Directories reached twice are broken, and would loop
+            if !visited.insert(inode) {
+                continue;
+            }
+            let subdirs = match fat.list_directory(inode) {
+                Ok(Some(entries)) => entries
+                    .iter()
+                    // Directories with cluster 0 are broken, not the root
+                    .filter(|entry| {
+                        is_dir(entry)
+                            && entry.cluster_number() != 0
+                            && entry.get_name() != "."
+                            && entry.get_name() != ".."
+                    })
+                    .map(|entry| {
+                        let path = format!(
+                            "{}/{}",
+                            dir.trim_end_matches('/'),
+                            entry.get_name()
+                        );
+                        (path, entry.cluster_number())
+                    })
+                    .collect(),
+                Ok(None) => vec![],
+                Err(e) => {
+                    eprintln!("Skipping removed files of {}: {}", dir, e);
+                    continue;
+                }
+            };
+            match fat.deleted_entries(&dir) {
+                Ok(deleted) => found.extend(
+                    deleted
+                        .into_iter()
+                        .filter(|deleted| {
+                            deleted.clusters.is_some()
+                                && !is_dir(&deleted.entry)
+                        })
+                        .map(|deleted| (dir.clone(), deleted)),
+                ),
+                Err(e) => {
+                    eprintln!("Skipping removed files of {}: {}", dir, e)
+                }
+            }
+            // Subdirectories are visited in order, before later siblings
+            dirs.extend(subdirs.into_iter().rev());
+        }
+
+        let mut tree = DeletedTree {
+            nodes: vec![DeletedNode {
+                name: DELETED_DIR_NAME.to_string(),
+                parent: 0,
+                kind: DeletedKind::Dir(vec![]),
+            }],
+        };
+        // Directories first, so files never take the name of one
+        let parents: Vec<usize> =
+            found.iter().map(|(dir, _)| tree.dir_node(dir)).collect();
+        for ((_, deleted), parent) in found.into_iter().zip(parents) {
+            let name = tree.unique_name(parent, deleted.entry.get_name());
+            tree.add(parent, name, DeletedKind::File(deleted));
+        }
+        tree
+    }
+
+    /// Adds node below directory with index parent, returns its index
+    fn add(&mut self, parent: usize, name: String, kind: DeletedKind) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(DeletedNode { name, parent, kind });
+        if let DeletedKind::Dir(children) = &mut self.nodes[parent].kind {
+            children.push(index);
+        }
+        index
+    }
+
+    /// Index of child of directory with index parent by name
+    fn child(&self, parent: usize, name: &str) -> Option<usize> {
+        match &self.nodes[parent].kind {
+            DeletedKind::Dir(children) => children
+                .iter()
+                .copied()
+                .find(|child| self.nodes[*child].name == name),
+            DeletedKind::File(_) => None,
+        }
+    }
+
+    /// Index of directory at path of the volume, created with its parents
+    fn dir_node(&mut self, path: &str) -> usize {
+        let mut parent = 0;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            parent = match self.child(parent, component) {
+                Some(child) => child,
+                None => self.add(
+                    parent,
+                    component.to_string(),
+                    DeletedKind::Dir(vec![]),
+                ),
+            };
+        }
+        parent
+    }
+
+    /// Name not yet taken in directory, files removed under the same name
+    /// are told apart by a number
+    fn unique_name(&self, parent: usize, name: &str) -> String {
+        let mut unique = name.to_string();
+        let mut number = 2;
+        while self.child(parent, &unique).is_some() {
+            unique = format!("{} ({})", name, number);
+            number += 1;
+        }
+        unique
+    }
+
+    /// Index of node with inode
+    fn index(&self, ino: u64) -> Option<usize> {
+        ino.checked_sub(DELETED_DIR_INODE)
+            .map(|index| index as usize)
+            .filter(|index| *index < self.nodes.len())
+    }
+
+    /// Whether inode belongs to the tree
+    pub fn contains(&self, ino: u64) -> bool {
+        self.index(ino).is_some()
+    }
+
+    /// Inode of child of directory with inode by name
+    pub fn lookup(&self, ino: u64, name: &str) -> Option<u64> {
+        let child = self.child(self.index(ino)?, name)?;
+        Some(DELETED_DIR_INODE + child as u64)
+    }
+
+    /// Removed file with inode, None for directories
+    pub fn file(&self, ino: u64) -> Option<&FatDeletedEntry> {
+        match &self.nodes[self.index(ino)?].kind {
+            DeletedKind::File(deleted) => Some(deleted),
+            DeletedKind::Dir(_) => None,
+        }
+    }
+
+    /// Entries of directory with inode, including "." and ".."
+    /// The top directory refers to the root of the volume as its parent
+    pub fn children(&self, ino: u64) -> Option<Vec<(u64, FileType, String)>> {
+        let index = self.index(ino)?;
+        let children = match &self.nodes[index].kind {
+            DeletedKind::Dir(children) => children,
+            DeletedKind::File(_) => return None,
+        };
+        let parent = match index {
+            0 => 1,
+            _ => DELETED_DIR_INODE + self.nodes[index].parent as u64,
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        for child in children {
+            let kind = match self.nodes[*child].kind {
+                DeletedKind::Dir(_) => FileType::Directory,
+                DeletedKind::File(_) => FileType::RegularFile,
+            };
+            let name = self.nodes[*child].name.clone();
+            entries.push((DELETED_DIR_INODE + *child as u64, kind, name));
+        }
+        Some(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fat_test::{scratch_image, STAMP};
+    use super::{DeletedTree, DELETED_DIR_INODE};
+
+    /// Names of children of directory with inode, without dot entries
+    fn names(tree: &DeletedTree, ino: u64) -> Vec<String> {
+        let children = tree.children(ino).unwrap();
+        children
+            .into_iter()
+            .skip(2)
+            .map(|(_, _, name)| name)
+            .collect()
+    }
+
+    #[test]
+    fn tells_apart_files_removed_under_the_same_name() {
+        let (_image, mut fat) = scratch_image("deleted");
+        // Removed twice in a directory, and once where a directory took over
+        for contents in [b"first", b"again"] {
+            fat.write_file("/notes.txt", contents, &STAMP, &STAMP)
+                .unwrap();
+            fat.remove("/notes.txt").unwrap();
+        }
+        fat.write_file("/docs", b"file", &STAMP, &STAMP).unwrap();
+        fat.remove("/docs").unwrap();
+        fat.create_dir("/docs", &STAMP).unwrap();
+        fat.write_file("/docs/old.txt", b"old", &STAMP, &STAMP)
+            .unwrap();
+        fat.remove("/docs/old.txt").unwrap();
+        let tree = DeletedTree::scan(&mut fat);
+
+        let top = names(&tree, DELETED_DIR_INODE);
+        assert_eq!(top, ["docs", "notes.txt", "notes.txt (2)", "docs (2)"]);
+        let docs = tree.lookup(DELETED_DIR_INODE, "docs").unwrap();
+        assert_eq!(names(&tree, docs), ["old.txt"]);
+        assert!(tree.file(docs).is_none());
+        let file = tree.lookup(DELETED_DIR_INODE, "docs (2)").unwrap();
+        assert_eq!(tree.file(file).unwrap().entry.size(), 4);
+        assert_eq!(tree.lookup(DELETED_DIR_INODE, "missing"), None);
+    }
+}
@@ -1,35 +1,198 @@
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryInto;
 use std::ffi::OsStr;
+use std::path::Path;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 extern crate lib_fat;
-use lib_fat::{Fat, FatDirectoryEntryContainer, FatFileType};
+use lib_fat::{is_dir, Fat, FatDirectoryEntryContainer, FatError, FatFileType};
 
 extern crate libc;
-use libc::ENOENT;
-extern crate time;
-use time::{Date, Month, PrimitiveDateTime, Time};
+use libc::{
+    c_int, EEXIST, EINVAL, EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, ENOTSUP,
+    ENOTTY, EPERM, ERANGE, EROFS, XATTR_CREATE,
+};
 
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory,
-    ReplyEntry, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
+};
+
+use super::fat_cli::extent_string;
+use super::fat_deleted::{DeletedTree, DELETED_DIR_INODE, DELETED_DIR_NAME};
+use super::fat_options::FatOptions;
+use super::fat_time::{
+    parse_access_date, parse_create_time, parse_modify_time,
+    unix_timestamp_to_systemtime,
 };
 
 pub struct FatFS {
     fat: Fat,
+    options: FatOptions,
+    // Removed files below /.deleted, with the showdeleted option
+    deleted: Option<DeletedTree>,
 }
 
 const TTL: Duration = Duration::from_secs(1);
 
+/// Name of directory in the root mirroring the volume with the slack of files
+const SLACK_DIR_NAME: &str = ".slack";
+/// Base of inodes below it, added to the cluster numbers of entries
+const SLACK_INODE: u64 = 2 << 32;
+/// Name and inode of file in the root holding the contents of free clusters
+const UNALLOCATED_NAME: &str = ".unallocated";
+const UNALLOCATED_INODE: u64 = 3 << 32;
+
+/// Extended attributes of entries
+const ATTRIBUTES_XATTR: &str = "user.fat.attributes";
+const SHORTNAME_XATTR: &str = "user.fat.shortname";
+const FIRST_CLUSTER_XATTR: &str = "user.fat.first_cluster";
+const CLUSTERS_XATTR: &str = "user.fat.clusters";
+const CENTISECONDS_XATTR: &str = "user.fat.created_centiseconds";
+
+/// Number of ioctl command, in the encoding shared by most architectures
+const fn ioctl_number(write: bool, kind: u8, number: u8, size: usize) -> u32 {
+    // Read and write are from the point of view of the caller
+    let direction = if write { 1 } else { 2 };
+    direction << 30 | (size as u32) << 16 | (kind as u32) << 8 | number as u32
+}
+
+/// Commands of the kernel vfat driver
+const FAT_IOCTL_GET_ATTRIBUTES: u32 = ioctl_number(false, b'r', 0x10, 4);
+const FAT_IOCTL_SET_ATTRIBUTES: u32 = ioctl_number(true, b'r', 0x11, 4);
+const FAT_IOCTL_GET_VOLUME_ID: u32 = ioctl_number(false, b'r', 0x13, 4);
+/// Generic command reading the label, into a buffer of FSLABEL_MAX bytes
+const FSLABEL_MAX: usize = 256;
+const FS_IOC_GETFSLABEL: u32 = ioctl_number(false, 0x94, 49, FSLABEL_MAX);
+
 impl FatFS {
-    pub fn new(filename: &str) -> FatFS {
-        let fat = Fat::mount_volume(filename);
-        println!("Volume type: {}", fat.fat_type());
-        FatFS { fat }
+    pub fn new(filename: &str, options: FatOptions) -> Result<FatFS, FatError> {
+        let mut fat = if options.rw {
+            Fat::mount_volume_rw(filename)?
+        } else {
+            Fat::mount_volume(filename)?
+        };
+        let deleted = if options.showdeleted {
+            Some(DeletedTree::scan(&mut fat))
+        } else {
+            None
+        };
+        Ok(FatFS {
+            fat,
+            options,
+            deleted,
+        })
+    }
+
+    /// Tree of removed files, if inode belongs to it
+    fn deleted_tree(&self, ino: u64) -> Option<&DeletedTree> {
+        self.deleted.as_ref().filter(|tree| tree.contains(ino))
+    }
+
+    /// Attributes of inode of removed file or directory holding them
+    fn deleted_attr(&self, ino: u64) -> Option<FileAttr> {
+        let tree = self.deleted_tree(ino)?;
+        Some(match tree.file(ino) {
+            None => dir_attr(ino, &self.options),
+            Some(deleted) => {
+                let mut attr =
+                    attr(&deleted.entry, self.fat.is_fat32(), &self.options);
+                attr.ino = ino;
+                attr.blocks =
+                    deleted.clusters.as_ref().map_or(0, |c| c.len()) as u64;
+                attr.perm &= !0o222;
+                attr
+            }
+        })
+    }
+
+    /// Whether inode is below /.slack
+    fn is_slack(&self, ino: u64) -> bool {
+        self.options.showslack
+            && (SLACK_INODE..UNALLOCATED_INODE).contains(&ino)
+    }
+
+    /// Inode below /.slack of entry with cluster number, 0 for the root
+    fn slack_inode(&self, cluster_number: u32) -> u64 {
+        match cluster_number {
+            0 => SLACK_INODE + self.fat.get_root_cluster_number() as u64,
+            _ => SLACK_INODE + cluster_number as u64,
+        }
+    }
+
+    /// Attributes of entry as mirrored below /.slack, files hold their slack
+    fn slack_attr(&self, entry: &FatDirectoryEntryContainer) -> FileAttr {
+        let mut attr = attr(entry, self.fat.is_fat32(), &self.options);
+        attr.ino = self.slack_inode(entry.cluster_number());
+        if attr.kind == FileType::RegularFile {
+            attr.size = self.fat.slack_size(entry) as u64;
+            attr.perm &= !0o222;
+        }
+        attr
+    }
+
+    /// Attributes of /.unallocated
+    fn unallocated_attr(&mut self) -> FileAttr {
+        let size = self.fat.unallocated_size();
+        FileAttr {
+            ino: UNALLOCATED_INODE,
+            size,
+            blocks: size / self.fat.cluster_size() as u64,
+            kind: FileType::RegularFile,
+            perm: self.options.file_perm(UNALLOCATED_NAME, true),
+            ..dir_attr(UNALLOCATED_INODE, &self.options)
+        }
+    }
+
+    /// Directory entry of inode, None for the root and virtual inodes
+    fn volume_entry(&self, ino: u64) -> Option<&FatDirectoryEntryContainer> {
+        if ino <= 1 || ino >= DELETED_DIR_INODE {
+            return None;
+        }
+        self.fat.get_inode(ino as u32)
+    }
+
+    /// Change attributes of entry with inode for the caller of setxattr or
+    /// ioctl, failing with errno
+    fn set_attributes(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        attribute: u8,
+    ) -> Result<(), c_int> {
+        let old = match self.volume_entry(ino) {
+            Some(entry) => entry.attribute(),
+            None => return Err(EPERM),
+        };
+        if !self.options.rw {
+            return Err(EROFS);
+        }
+        if !self.options.may_set_attributes(req.uid(), old, attribute) {
+            return Err(EPERM);
+        }
+        self.fat
+            .set_inode_attributes(ino as u32, attribute)
+            .and_then(|_| self.fat.flush())
+            .map_err(|e| {
+                eprintln!("Cannot set attributes of inode {}: {}", ino, e);
+                EIO
+            })
+    }
+
+    /// Get FAT type of volume
+    pub fn fat_type(&self) -> String {
+        self.fat.fat_type()
     }
 }
 
 impl Filesystem for FatFS {
+    /// Write out pending changes before the filesystem goes away
+    fn destroy(&mut self) {
+        if let Err(e) = self.fat.flush() {
+            eprintln!("Cannot write changes: {}", e);
+        }
+    }
+
     /// Read data of specified ino
     fn read(
         &mut self,
@@ -42,17 +205,56 @@ impl Filesystem for FatFS {
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
-        match self.fat.get_data(
-            ino.try_into().unwrap(),
-            offset.try_into().unwrap(),
-            size,
-        ) {
-            Some(data) => {
+        if let Some(tree) = self.deleted.as_ref() {
+            if tree.contains(ino) {
+                let deleted = match tree.file(ino) {
+                    Some(deleted) => deleted,
+                    None => return reply.error(EISDIR),
+                };
+                let offset = offset.try_into().unwrap();
+                return match self.fat.read_deleted(deleted, offset, size) {
+                    Ok(data) => reply.data(&data),
+                    Err(e) => {
+                        eprintln!("Cannot read removed file {}: {}", ino, e);
+                        reply.error(EIO)
+                    }
+                };
+            }
+        }
+        if self.options.showslack && ino == UNALLOCATED_INODE {
+            let offset = offset.try_into().unwrap();
+            return match self.fat.read_unallocated(offset, size) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    eprintln!("Cannot read free clusters: {}", e);
+                    reply.error(EIO)
+                }
+            };
+        }
+        let result = if self.is_slack(ino) {
+            self.fat.get_slack(
+                (ino - SLACK_INODE) as u32,
+                offset.try_into().unwrap(),
+                size,
+            )
+        } else {
+            self.fat.get_data(
+                ino.try_into().unwrap(),
+                offset.try_into().unwrap(),
+                size,
+            )
+        };
+        match result {
+            Ok(Some(data)) => {
                 reply.data(&data);
             }
-            None => {
+            Ok(None) => {
                 reply.error(ENOENT);
             }
+            Err(e) => {
+                eprintln!("Cannot read inode {}: {}", ino, e);
+                reply.error(EIO);
+            }
         }
     }
 
@@ -64,6 +266,38 @@ impl Filesystem for FatFS {
         name: &OsStr,
         reply: ReplyEntry,
     ) {
+        if let Some(tree) = self.deleted.as_ref() {
+            let child = match name.to_str() {
+                Some(DELETED_DIR_NAME) if parent == 1 => {
+                    Some(DELETED_DIR_INODE)
+                }
+                Some(name) if tree.contains(parent) => {
+                    tree.lookup(parent, name)
+                }
+                _ => None,
+            };
+            if let Some(attr) = child.and_then(|ino| self.deleted_attr(ino)) {
+                return reply.entry(&TTL, &attr, 0);
+            }
+            if tree.contains(parent) {
+                return reply.error(ENOENT);
+            }
+        }
+
+        if self.options.showslack && parent == 1 {
+            match name.to_str() {
+                Some(SLACK_DIR_NAME) => {
+                    let ino = self.slack_inode(0);
+                    return reply.entry(&TTL, &dir_attr(ino, &self.options), 0);
+                }
+                Some(UNALLOCATED_NAME) => {
+                    return reply.entry(&TTL, &self.unallocated_attr(), 0)
+                }
+                _ => {}
+            }
+        }
+        let slack = self.is_slack(parent);
+
         // If parent inode is 1, corresponds to FAT12/16 cluster number of 0
         let parent_inode = match parent {
             1 => {
@@ -73,41 +307,55 @@ impl Filesystem for FatFS {
                     0
                 }
             }
+            _ if slack => (parent - SLACK_INODE) as u32,
             _ => parent.try_into().unwrap(),
         };
 
         let is_fat32 = self.fat.is_fat32();
-        if let Some(entry) =
-            self.fat.lookup(parent_inode, name.to_str().unwrap())
-        {
-            reply.entry(&TTL, &attr(entry, is_fat32), 0)
-        } else {
-            reply.error(ENOENT)
+        match self.fat.lookup(parent_inode, name.to_str().unwrap()) {
+            // Files without clusters have no slack, nor an inode of their own
+            Ok(Some(entry))
+                if slack && !is_dir(entry) && entry.cluster_number() == 0 =>
+            {
+                reply.error(ENOENT)
+            }
+            Ok(Some(entry))
+                if slack && self.options.is_visible(entry.attribute()) =>
+            {
+                let entry = entry.clone();
+                reply.entry(&TTL, &self.slack_attr(&entry), 0)
+            }
+            // Entries hidden from readdir cannot be looked up either
+            Ok(Some(entry)) if self.options.is_visible(entry.attribute()) => {
+                reply.entry(&TTL, &attr(entry, is_fat32, &self.options), 0)
+            }
+            Ok(_) => reply.error(ENOENT),
+            Err(e) => {
+                eprintln!("Cannot read directory {}: {}", parent_inode, e);
+                reply.error(EIO)
+            }
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         match ino {
             // Root directory
-            1 => {
-                let attr = FileAttr {
-                    ino: 1,
-                    size: 0,
-                    blocks: 1,
-                    blksize: 0,
-                    atime: UNIX_EPOCH,
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    crtime: UNIX_EPOCH,
-                    kind: FileType::Directory,
-                    perm: 0o755,
-                    nlink: 1,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                    flags: 0,
-                };
-                reply.attr(&TTL, &attr);
+            1 => reply.attr(&TTL, &dir_attr(1, &self.options)),
+            _ if self.deleted_tree(ino).is_some() => {
+                reply.attr(&TTL, &self.deleted_attr(ino).unwrap())
+            }
+            UNALLOCATED_INODE if self.options.showslack => {
+                reply.attr(&TTL, &self.unallocated_attr())
+            }
+            _ if self.is_slack(ino) => {
+                let inode = (ino - SLACK_INODE) as u32;
+                if inode == self.fat.get_root_cluster_number() {
+                    return reply.attr(&TTL, &dir_attr(ino, &self.options));
+                }
+                match self.fat.get_inode(inode).cloned() {
+                    None => reply.error(ENOENT),
+                    Some(entry) => reply.attr(&TTL, &self.slack_attr(&entry)),
+                }
             }
             _ => {
                 // File or subdirectory
@@ -116,35 +364,181 @@ impl Filesystem for FatFS {
                     None => reply.error(ENOENT),
                     Some(entry) => reply.attr(
                         &TTL,
-                        &attr(
-                            entry,
-                            self.fat.is_fat32(),
-                        ),
+                        &attr(entry, self.fat.is_fat32(), &self.options),
                     ),
                 }
             }
         }
     }
 
+    /// Get extended attribute of entry, the attribute byte is given raw
+    fn getxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let entry = match self.volume_entry(ino) {
+            None => return reply.error(ENODATA),
+            Some(entry) => entry,
+        };
+        let value = match name.to_str() {
+            Some(ATTRIBUTES_XATTR) => vec![entry.attribute()],
+            Some(SHORTNAME_XATTR) => entry.get_short_name().into_bytes(),
+            Some(FIRST_CLUSTER_XATTR) => {
+                entry.cluster_number().to_string().into_bytes()
+            }
+            Some(CLUSTERS_XATTR) => {
+                match self.fat.chain_extents(entry.cluster_number()) {
+                    Ok(extents) => {
+                        let extents: Vec<String> =
+                            extents.iter().map(extent_string).collect();
+                        extents.join(",").into_bytes()
+                    }
+                    Err(e) => {
+                        eprintln!("Cannot read chain of inode {}: {}", ino, e);
+                        return reply.error(EIO);
+                    }
+                }
+            }
+            Some(CENTISECONDS_XATTR) => {
+                entry.get_creation_time().6.to_string().into_bytes()
+            }
+            _ => return reply.error(ENODATA),
+        };
+        reply_xattr(reply, &value, size)
+    }
+
+    /// List extended attributes of entry
+    fn listxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let mut names: Vec<u8> = vec![];
+        if self.volume_entry(ino).is_some() {
+            for name in [
+                ATTRIBUTES_XATTR,
+                SHORTNAME_XATTR,
+                FIRST_CLUSTER_XATTR,
+                CLUSTERS_XATTR,
+                CENTISECONDS_XATTR,
+            ] {
+                names.extend(name.as_bytes());
+                names.push(0);
+            }
+        }
+        reply_xattr(reply, &names, size)
+    }
+
+    /// Set read-only, hidden, system and archive attributes of entry from
+    /// the attribute byte, other bits are left as they are
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if name.to_str() != Some(ATTRIBUTES_XATTR) {
+            return reply.error(ENOTSUP);
+        }
+        // Every entry has attributes already
+        if flags & XATTR_CREATE != 0 {
+            return reply.error(EEXIST);
+        }
+        if value.len() != 1 {
+            return reply.error(EINVAL);
+        }
+        match self.set_attributes(req, ino, value[0]) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Handle commands of the kernel vfat driver, and reading the label
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        match cmd {
+            FAT_IOCTL_GET_ATTRIBUTES => {
+                let attribute = match self.volume_entry(ino) {
+                    Some(entry) => entry.attribute(),
+                    None if ino == 1 => FatFileType::AttrDirectory as u8,
+                    None => return reply.error(ENOTTY),
+                };
+                reply.ioctl(0, &(attribute as u32).to_ne_bytes())
+            }
+            FAT_IOCTL_SET_ATTRIBUTES => {
+                let attribute = match in_data.try_into() {
+                    Ok(bytes) => u32::from_ne_bytes(bytes),
+                    Err(_) => return reply.error(EINVAL),
+                };
+                match self.set_attributes(req, ino, attribute as u8) {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            FAT_IOCTL_GET_VOLUME_ID => {
+                reply.ioctl(0, &self.fat.volume_id().to_ne_bytes())
+            }
+            FS_IOC_GETFSLABEL => {
+                let mut label = self.fat.volume_label().into_bytes();
+                label.resize(FSLABEL_MAX, 0);
+                reply.ioctl(0, &label)
+            }
+            _ => reply.error(ENOTTY),
+        }
+    }
+
     fn readdir(
         &mut self,
         _req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        reply: ReplyDirectory,
     ) {
+        if let Some(tree) = self.deleted_tree(ino) {
+            return match tree.children(ino) {
+                Some(entries) => reply_entries(reply, entries, offset),
+                None => reply.error(ENOTDIR),
+            };
+        }
+        let slack = self.is_slack(ino);
+
         // Get root inode number
         let root_inode = self.fat.get_root_cluster_number();
+        let slack_root = self.slack_inode(0);
 
         // Get directory entries
         let dir_option = match ino {
             1 => self.fat.list_directory(root_inode),
+            _ if slack => self.fat.list_directory((ino - SLACK_INODE) as u32),
             _ => self.fat.list_directory(ino.try_into().unwrap()),
         };
         let dir = match dir_option {
-            None => return reply.error(ENOENT),
-            Some(dir) => dir,
+            Ok(None) => return reply.error(ENOENT),
+            Ok(Some(dir)) => dir,
+            Err(e) => {
+                eprintln!("Cannot read directory {}: {}", ino, e);
+                return reply.error(EIO);
+            }
         };
 
         // Push . and .. for root
@@ -152,27 +546,57 @@ impl Filesystem for FatFS {
         if ino == 1 {
             entries.push((1, FileType::Directory, ".".to_string()));
             entries.push((1, FileType::Directory, "..".to_string()));
+            if self.deleted.is_some() {
+                entries.push((
+                    DELETED_DIR_INODE,
+                    FileType::Directory,
+                    DELETED_DIR_NAME.to_string(),
+                ));
+            }
+            if self.options.showslack {
+                entries.push((
+                    slack_root,
+                    FileType::Directory,
+                    SLACK_DIR_NAME.to_string(),
+                ));
+                entries.push((
+                    UNALLOCATED_INODE,
+                    FileType::RegularFile,
+                    UNALLOCATED_NAME.to_string(),
+                ));
+            }
+        }
+        if ino == slack_root {
+            entries.push((ino, FileType::Directory, ".".to_string()));
+            entries.push((1, FileType::Directory, "..".to_string()));
         }
 
         // Add entries
         for entry in dir {
-            if entry.attribute() & FatFileType::AttrHidden as u8 != 0
-                || entry.attribute() & FatFileType::AttrVolumeId as u8 != 0
-            {
+            if !self.options.is_visible(entry.attribute()) {
                 continue;
             }
 
             // Only process file and directories
-            let inode = entry.cluster_number().into();
-            let inode = if inode == root_inode.into() || inode == 0_u64 {
+            let cluster_number = entry.cluster_number();
+            let inode = if slack {
+                // Files without clusters have no slack
+                if !is_dir(entry) && cluster_number == 0 {
+                    continue;
+                }
+                match cluster_number {
+                    0 => slack_root,
+                    _ => SLACK_INODE + cluster_number as u64,
+                }
+            } else if cluster_number == root_inode || cluster_number == 0 {
                 1
             } else {
-                inode
+                cluster_number.into()
             };
             let entry_name = entry.get_name();
-            if entry.attribute() & FatFileType::AttrDirectory as u8 != 0 {
+            if is_dir(entry) {
                 entries.push((inode, FileType::Directory, entry_name.clone()));
-            } else if entry.attribute() & FatFileType::AttrArchive as u8 != 0 {
+            } else {
                 entries.push((
                     inode,
                     FileType::RegularFile,
@@ -180,14 +604,224 @@ impl Filesystem for FatFS {
                 ));
             }
         }
+        reply_entries(reply, entries, offset)
+    }
 
-        // From lib example
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize)
-        {
-            // i + 1 means the index of the next entry
-            let _ = reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
-        }
-        reply.ok();
+    // Contents cannot be changed, even on rw mounts, which only allow changing
+    // attributes of entries, so changes are refused as on a read-only mount
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn unlink(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn rmdir(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _link_name: &OsStr,
+        _target: &Path,
+        reply: ReplyEntry,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn removexattr(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(EROFS)
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        reply.error(EROFS)
+    }
+}
+
+/// Replies with entries from offset
+fn reply_entries(
+    mut reply: ReplyDirectory,
+    entries: Vec<(u64, FileType, String)>,
+    offset: i64,
+) {
+    // From lib example
+    for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+        // i + 1 means the index of the next entry
+        let _ = reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
+    }
+    reply.ok();
+}
+
+/// Replies with size of value if size is 0, or with value if it fits
+fn reply_xattr(reply: ReplyXattr, value: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(value.len() as u32)
+    } else if value.len() > size as usize {
+        reply.error(ERANGE)
+    } else {
+        reply.data(value)
+    }
+}
+
+/// Attributes of directory without a directory entry, such as the root
+fn dir_attr(ino: u64, options: &FatOptions) -> FileAttr {
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 1,
+        blksize: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: options.dir_perm(),
+        nlink: 1,
+        uid: options.uid,
+        gid: options.gid,
+        rdev: 0,
+        flags: 0,
     }
 }
 
@@ -195,96 +829,56 @@ impl Filesystem for FatFS {
 fn attr(
     entry: &FatDirectoryEntryContainer,
     is_fat32: bool,
+    options: &FatOptions,
 ) -> FileAttr {
     let kind;
+    let perm;
     if entry.attribute() & FatFileType::AttrDirectory as u8 != 0 {
         kind = FileType::Directory;
-    } else if entry.attribute() & FatFileType::AttrArchive as u8 != 0 {
-        kind = FileType::RegularFile;
+        perm = options.dir_perm();
     } else {
-        panic!("Unrecognized file type");
+        // Archive bit is optional, anything else is a file
+        kind = FileType::RegularFile;
+        // Read only attribute only applies to files, as with vfat
+        let read_only =
+            entry.attribute() & FatFileType::AttrReadOnly as u8 != 0;
+        perm = options.file_perm(entry.get_name(), read_only);
     }
 
+    // Timestamps are stored in the zone given by tz option
+    let atime = parse_access_date(entry, options.tz);
+    let mtime = parse_modify_time(entry, options.tz);
+    let crtime = parse_create_time(entry, options.tz);
+
     FileAttr {
-        ino: entry.cluster_number().try_into().unwrap(),
+        ino: entry.cluster_number().into(),
         size: entry.size() as u64,
         blocks: entry.cluster_count(is_fat32).into(),
         blksize: 0,
-        atime: unix_timestamp_to_systemtime(parse_access_date(entry)),
-        mtime: unix_timestamp_to_systemtime(parse_modify_time(entry)),
-        ctime: unix_timestamp_to_systemtime(parse_create_time(entry)),
-        crtime: unix_timestamp_to_systemtime(parse_create_time(entry)),
+        atime: unix_timestamp_to_systemtime(atime, 0),
+        mtime: unix_timestamp_to_systemtime(mtime, 0),
+        ctime: unix_timestamp_to_systemtime(crtime.0, crtime.1),
+        crtime: unix_timestamp_to_systemtime(crtime.0, crtime.1),
         kind,
-        perm: 0o755,
+        perm,
         nlink: 1,
-        uid: 0,
-        gid: 0,
+        uid: options.uid,
+        gid: options.gid,
         rdev: 0,
         flags: 0,
     }
 }
 
-fn unix_timestamp_to_systemtime(timestamp: i64) -> SystemTime {
-    if timestamp < 0 {
-        SystemTime::UNIX_EPOCH
-            .checked_sub(Duration::from_secs(-timestamp as u64))
-            .unwrap()
-    } else {
-        SystemTime::UNIX_EPOCH
-            .checked_add(Duration::from_secs(timestamp as u64))
-            .unwrap()
-    }
-}
-
-// Parse modify time into timestamp
-fn parse_modify_time(entry: &FatDirectoryEntryContainer) -> i64 {
-    let (year, month, day, hour, minute, second) = entry.get_write_time();
-    if month == 0 || day == 0 {
-        return 0;
-    }
-
-    let date = Date::from_calendar_date(
-        year.into(),
-        Month::try_from(month).unwrap(),
-        day,
-    )
-    .unwrap();
-    let time = Time::from_hms(hour, minute, second).unwrap();
-    let dt = PrimitiveDateTime::new(date, time);
-    dt.assume_utc().unix_timestamp()
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-// Parse create time into timestamp
-fn parse_create_time(entry: &FatDirectoryEntryContainer) -> i64 {
-    let (year, month, day, hour, minute, second) = entry.get_creation_time();
-    if month == 0 || day == 0 {
-        return 0;
-    }
-    let date = Date::from_calendar_date(
-        year.into(),
-        Month::try_from(month).unwrap(),
-        day,
-    )
-    .unwrap();
-    let time = Time::from_hms(hour, minute, second as u8).unwrap();
-    let dt = PrimitiveDateTime::new(date, time);
-    dt.assume_utc().unix_timestamp()
-}
-
-// Parse last access time into timestamp
-fn parse_access_date(entry: &FatDirectoryEntryContainer) -> i64 {
-    let (year, month, day) = entry.get_last_accessed_date();
-    if month == 0 || day == 0 {
-        return 0;
-    }
-
-    let date = Date::from_calendar_date(
-        year.into(),
-        Month::try_from(month).unwrap(),
-        day,
-    )
-    .unwrap();
-    let time = Time::MIDNIGHT;
-    let dt = PrimitiveDateTime::new(date, time);
-    dt.assume_utc().unix_timestamp()
+    #[test]
+    fn matches_ioctl_numbers_of_kernel() {
+        // As the headers of x86 and arm Linux define them
+        assert_eq!(FAT_IOCTL_GET_ATTRIBUTES, 0x8004_7210);
+        assert_eq!(FAT_IOCTL_SET_ATTRIBUTES, 0x4004_7211);
+        assert_eq!(FAT_IOCTL_GET_VOLUME_ID, 0x8004_7213);
+        assert_eq!(FS_IOC_GETFSLABEL, 0x8100_9431);
+    }
 }
@@ -0,0 +1,410 @@
+extern crate libc;
+
+extern crate lib_fat;
+use lib_fat::FatFileType;
+
+use fuser::MountOption;
+
+use super::fat_time::FatTimeZone;
+
+/// Filesystem specific mount options, following the kernel vfat driver
+#[derive(Debug, Clone)]
+pub struct FatOptions {
+    // Owner of all files and directories
+    pub uid: u32,
+    pub gid: u32,
+    // Permission bits removed from files and directories
+    pub fmask: u16,
+    pub dmask: u16,
+    // Only .EXE/.COM/.BAT files are executable
+    pub showexec: bool,
+    // List entries with the hidden attribute
+    pub showhidden: bool,
+    // Omit entries with the system attribute
+    pub hidesystem: bool,
+    // Zone timestamps are recorded in
+    pub tz: FatTimeZone,
+    // Present removed files which can still be read below /.deleted
+    pub showdeleted: bool,
+    // Present slack of files below /.slack and free clusters as /.unallocated
+    pub showslack: bool,
+    // Allow changing attributes of entries, contents of files stay read-only
+    pub rw: bool,
+}
+
+impl Default for FatOptions {
+    /// Owned by the mounting user, masked by its umask
+    fn default() -> FatOptions {
+        let umask = process_umask();
+        FatOptions {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            fmask: umask,
+            dmask: umask,
+            showexec: false,
+            showhidden: true,
+            hidesystem: false,
+            tz: FatTimeZone::Local,
+            showdeleted: false,
+            showslack: false,
+            rw: false,
+        }
+    }
+}
+
+impl FatOptions {
+    /// Parse comma separated option lists, such as "ro,uid=1000,umask=022"
+    /// Generic options are returned for FUSE, the rest are for FatFS
+    pub fn parse(
+        options: &[&str],
+    ) -> Result<(FatOptions, Vec<MountOption>), String> {
+        let mut fat_options = FatOptions::default();
+        let mut mount_options = vec![];
+        for option in options.iter().flat_map(|o| o.split(',')) {
+            let (key, value) = match option.find('=') {
+                Some(index) => (&option[..index], Some(&option[index + 1..])),
+                None => (option, None),
+            };
+            match (key, value) {
+                ("", None) => {}
+                // Generic options
+                ("ro", None) => fat_options.rw = false,
+                ("rw", None) => fat_options.rw = true,
+                ("allow_other", None) => {
+                    mount_options.push(MountOption::AllowOther)
+                }
+                ("allow_root", None) => {
+                    mount_options.push(MountOption::AllowRoot)
+                }
+                ("auto_unmount", None) => {
+                    mount_options.push(MountOption::AutoUnmount)
+                }
+                ("default_permissions", None) => {
+                    mount_options.push(MountOption::DefaultPermissions)
+                }
+                ("fsname", Some(value)) => {
+                    mount_options.push(MountOption::FSName(value.into()))
+                }
+                ("subtype", Some(value)) => {
+                    mount_options.push(MountOption::Subtype(value.into()))
+                }
+                ("dev", None) => mount_options.push(MountOption::Dev),
+                ("nodev", None) => mount_options.push(MountOption::NoDev),
+                ("suid", None) => mount_options.push(MountOption::Suid),
+                ("nosuid", None) => mount_options.push(MountOption::NoSuid),
+                ("exec", None) => mount_options.push(MountOption::Exec),
+                ("noexec", None) => mount_options.push(MountOption::NoExec),
+                ("atime", None) => mount_options.push(MountOption::Atime),
+                ("noatime", None) => mount_options.push(MountOption::NoAtime),
+                // Only meaningful to mount(8) and fstab
+                ("defaults", None)
+                | ("auto", None)
+                | ("noauto", None)
+                | ("nofail", None)
+                | ("user", None)
+                | ("users", None)
+                | ("owner", None)
+                | ("group", None)
+                | ("_netdev", None)
+                | ("comment", Some(_)) => {}
+                (key, _) if key.starts_with("x-") => {}
+                // FatFS options
+                ("uid", Some(value)) => fat_options.uid = parse_id(value)?,
+                ("gid", Some(value)) => fat_options.gid = parse_id(value)?,
+                ("umask", Some(value)) => {
+                    let mask = parse_mask(value)?;
+                    fat_options.fmask = mask;
+                    fat_options.dmask = mask;
+                }
+                ("fmask", Some(value)) => {
+                    fat_options.fmask = parse_mask(value)?
+                }
+                ("dmask", Some(value)) => {
+                    fat_options.dmask = parse_mask(value)?
+                }
+                ("showexec", None) => fat_options.showexec = true,
+                ("showhidden", None) => fat_options.showhidden = true,
+                ("noshowhidden", None) => fat_options.showhidden = false,
+                ("hidesystem", None) => fat_options.hidesystem = true,
+                ("nohidesystem", None) => fat_options.hidesystem = false,
+                ("tz", Some(value)) => {
+                    fat_options.tz = FatTimeZone::parse(value)?
+                }
+                ("showdeleted", None) => fat_options.showdeleted = true,
+                ("showslack", None) => fat_options.showslack = true,
+                // Options of the kernel vfat driver which have no effect,
+                // names are always presented as stored
+                ("codepage", Some(_)) | ("iocharset", Some(_)) => {}
+                ("utf8", _) | ("shortname", Some(_)) | ("quiet", None) => {}
+                _ => return Err(format!("Unknown option '{}'", option)),
+            }
+        }
+        mount_options.insert(
+            0,
+            if fat_options.rw {
+                MountOption::RW
+            } else {
+                MountOption::RO
+            },
+        );
+        Ok((fat_options, mount_options))
+    }
+
+    /// Whether entry with given attributes is listed and can be looked up
+    pub fn is_visible(&self, attribute: u8) -> bool {
+        // Volume label is not a file
+        if attribute & FatFileType::AttrVolumeId as u8 != 0 {
+            return false;
+        }
+        if !self.showhidden && attribute & FatFileType::AttrHidden as u8 != 0 {
+            return false;
+        }
+        !(self.hidesystem && attribute & FatFileType::AttrSystem as u8 != 0)
+    }
+
+    /// Whether user may change attributes of an entry from old to new
+    /// As with the kernel vfat driver, the owner of the entries and root may
+    /// change them, and only root may change the system attribute
+    pub fn may_set_attributes(&self, uid: u32, old: u8, new: u8) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        let system = FatFileType::AttrSystem as u8;
+        uid == self.uid && (old ^ new) & system == 0
+    }
+
+    /// Permission bits of a directory
+    pub fn dir_perm(&self) -> u16 {
+        0o777 & !self.dmask
+    }
+
+    /// Permission bits of a file with given name and FAT attributes
+    pub fn file_perm(&self, name: &str, read_only: bool) -> u16 {
+        let mut perm = 0o777 & !self.fmask;
+        if self.showexec && !is_executable_name(name) {
+            perm &= !0o111;
+        }
+        if read_only {
+            perm &= !0o222;
+        }
+        perm
+    }
+}
+
+/// Whether name has an extension DOS would execute
+fn is_executable_name(name: &str) -> bool {
+    match name.rfind('.') {
+        None => false,
+        Some(index) => {
+            let ext = name[index + 1..].to_lowercase();
+            ext == "exe" || ext == "com" || ext == "bat"
+        }
+    }
+}
+
+/// Parse user or group id
+fn parse_id(value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid id '{}'", value))
+}
+
+/// Parse octal permission mask
+fn parse_mask(value: &str) -> Result<u16, String> {
+    match u16::from_str_radix(value, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(format!("Invalid mask '{}'", value)),
+    }
+}
+
+/// Read umask of process, there is no way to do so without setting it
+fn process_umask() -> u16 {
+    unsafe {
+        let umask = libc::umask(0o022);
+        libc::umask(umask);
+        umask as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fuser::MountOption;
+    use lib_fat::FatFileType;
+
+    use super::super::fat_time::FatTimeZone;
+    use super::FatOptions;
+
+    #[test]
+    fn parses_generic_options_for_fuse() {
+        let (_, mount_options) = FatOptions::parse(&[
+            "ro,allow_other,fsname=disk.img",
+            "noatime,nodev,,nosuid",
+        ])
+        .unwrap();
+        assert_eq!(
+            mount_options,
+            vec![
+                MountOption::RO,
+                MountOption::AllowOther,
+                MountOption::FSName("disk.img".into()),
+                MountOption::NoAtime,
+                MountOption::NoDev,
+                MountOption::NoSuid,
+            ]
+        );
+        // Mounts are read-only without options, the last of ro and rw counts
+        let (options, mount_options) = FatOptions::parse(&[]).unwrap();
+        assert_eq!(mount_options, vec![MountOption::RO]);
+        assert!(!options.rw);
+        let (options, mount_options) = FatOptions::parse(&["rw"]).unwrap();
+        assert_eq!(mount_options, vec![MountOption::RW]);
+        assert!(options.rw);
+        let (options, mount_options) = FatOptions::parse(&["rw,ro"]).unwrap();
+        assert_eq!(mount_options, vec![MountOption::RO]);
+        assert!(!options.rw);
+    }
+
+    #[test]
+    fn parses_filesystem_options() {
+        let (options, mount_options) = FatOptions::parse(&[
+            "uid=1000,gid=100,umask=022,fmask=0133",
+            "showexec,noshowhidden,hidesystem,tz=UTC",
+        ])
+        .unwrap();
+        assert_eq!(mount_options, vec![MountOption::RO]);
+        assert_eq!((options.uid, options.gid), (1000, 100));
+        assert_eq!((options.fmask, options.dmask), (0o133, 0o022));
+        assert!(options.showexec);
+        assert!(!options.showhidden);
+        assert!(options.hidesystem);
+        assert_eq!(options.tz, FatTimeZone::Utc);
+    }
+
+    #[test]
+    fn accepts_vfat_options_without_effect() {
+        let options =
+            "codepage=850,iocharset=iso8859-1,utf8,shortname=mixed,quiet";
+        let (_, mount_options) = FatOptions::parse(&[options]).unwrap();
+        assert_eq!(mount_options, vec![MountOption::RO]);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let error = |options| FatOptions::parse(&[options]).unwrap_err();
+        assert_eq!(error("ro,bogus"), "Unknown option 'bogus'");
+        assert_eq!(error("uid"), "Unknown option 'uid'");
+        assert_eq!(error("showexec=1"), "Unknown option 'showexec=1'");
+        assert_eq!(error("uid=root"), "Invalid id 'root'");
+        assert_eq!(error("umask=999"), "Invalid mask '999'");
+        assert!(FatOptions::parse(&["tz=Mars"]).is_err());
+    }
+
+    #[test]
+    fn masks_permissions() {
+        let options = FatOptions {
+            fmask: 0o133,
+            dmask: 0o027,
+            ..FatOptions::default()
+        };
+        assert_eq!(options.dir_perm(), 0o750);
+        assert_eq!(options.file_perm("notes.txt", false), 0o644);
+        assert_eq!(options.file_perm("notes.txt", true), 0o444);
+        assert_eq!(options.file_perm("setup.exe", false), 0o644);
+    }
+
+    #[test]
+    fn read_only_files_lose_write_permissions() {
+        let options = FatOptions {
+            fmask: 0o002,
+            ..FatOptions::default()
+        };
+        assert_eq!(options.file_perm("notes.txt", false), 0o775);
+        assert_eq!(options.file_perm("notes.txt", true), 0o555);
+        // Directories have no read-only attribute
+        let options = FatOptions {
+            dmask: 0,
+            ..options
+        };
+        assert_eq!(options.dir_perm(), 0o777);
+    }
+
+    #[test]
+    fn showexec_keeps_execute_permission_of_programs() {
+        let options = FatOptions {
+            fmask: 0o022,
+            showexec: true,
+            ..FatOptions::default()
+        };
+        assert_eq!(options.file_perm("SETUP.EXE", false), 0o755);
+        assert_eq!(options.file_perm("command.com", false), 0o755);
+        assert_eq!(options.file_perm("Run.Bat", true), 0o555);
+        assert_eq!(options.file_perm("notes.txt", false), 0o644);
+        assert_eq!(options.file_perm("exe", false), 0o644);
+        assert_eq!(options.file_perm("setup.exe.txt", false), 0o644);
+        // Masked bits stay masked
+        let options = FatOptions {
+            fmask: 0o133,
+            ..options
+        };
+        assert_eq!(options.file_perm("setup.exe", false), 0o644);
+    }
+
+    #[test]
+    fn shows_hidden_and_system_entries_by_default() {
+        let options = FatOptions::default();
+        let hidden = FatFileType::AttrHidden as u8;
+        let system = FatFileType::AttrSystem as u8;
+        assert!(options.is_visible(FatFileType::AttrArchive as u8));
+        assert!(options.is_visible(FatFileType::AttrDirectory as u8));
+        assert!(options.is_visible(hidden));
+        assert!(options.is_visible(system | hidden));
+    }
+
+    #[test]
+    fn hides_entries_by_attributes() {
+        let hidden = FatFileType::AttrHidden as u8;
+        let system = FatFileType::AttrSystem as u8;
+        let options = FatOptions {
+            showhidden: false,
+            ..FatOptions::default()
+        };
+        assert!(!options.is_visible(hidden));
+        assert!(!options.is_visible(hidden | FatFileType::AttrDirectory as u8));
+        assert!(options.is_visible(system));
+
+        let options = FatOptions {
+            showhidden: true,
+            hidesystem: true,
+            ..options
+        };
+        assert!(options.is_visible(hidden));
+        assert!(!options.is_visible(system));
+        assert!(!options.is_visible(system | hidden));
+    }
+
+    #[test]
+    fn never_shows_volume_labels() {
+        let label = FatFileType::AttrVolumeId as u8;
+        assert!(!FatOptions::default().is_visible(label));
+        assert!(!FatOptions::default()
+            .is_visible(label | FatFileType::AttrArchive as u8));
+    }
+
+    #[test]
+    fn lets_owner_and_root_set_attributes() {
+        let hidden = FatFileType::AttrHidden as u8;
+        let system = FatFileType::AttrSystem as u8;
+        let archive = FatFileType::AttrArchive as u8;
+        let options = FatOptions {
+            uid: 1000,
+            ..FatOptions::default()
+        };
+        assert!(options.may_set_attributes(1000, archive, hidden));
+        assert!(!options.may_set_attributes(1001, archive, hidden));
+        // The system attribute needs root, whether it is set or cleared
+        assert!(!options.may_set_attributes(1000, archive, system));
+        assert!(!options.may_set_attributes(1000, system, archive));
+        assert!(options.may_set_attributes(1000, system, system | hidden));
+        assert!(options.may_set_attributes(0, archive, system));
+    }
+}
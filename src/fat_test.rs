@@ -0,0 +1,67 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use lib_fat::{Fat, FatDateTime, FatFormat, FatType};
+
+/// Time stamp of everything tests write
+pub const STAMP: FatDateTime = FatDateTime {
+    year: 2020,
+    month: 2,
+    day: 29,
+    hour: 13,
+    minute: 37,
+    second: 42,
+    centiseconds: 0,
+};
+
+/// Path in the temporary directory for a test, removed again when dropped
+pub struct TempPath {
+    pub path: PathBuf,
+}
+
+impl TempPath {
+    /// Path named after test so tests running at the same time do not share
+    /// it, nothing is created yet
+    pub fn new(test: &str) -> TempPath {
+        let path = std::env::temp_dir().join(format!(
+            "fat-fuse-{}-{}",
+            test,
+            std::process::id()
+        ));
+        TempPath { path }
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.path.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.path);
+        } else {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Formats empty FAT12 image of 1 MiB for test, along with its path, which
+/// removes the image when dropped
+pub fn scratch_image(test: &str) -> (TempPath, Fat) {
+    let path = TempPath::new(test);
+    let format = FatFormat {
+        size: 1 << 20,
+        fat_type: Some(FatType::Fat12),
+        label: None,
+        volume_id: 0,
+        root_slots: 0,
+        created: STAMP,
+    };
+    let fat = Fat::format(path.to_str().unwrap(), &format).unwrap();
+    (path, fat)
+}
@@ -0,0 +1,275 @@
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+extern crate lib_fat;
+use lib_fat::{FatDateTime, FatDirectoryEntryContainer};
+
+extern crate libc;
+extern crate time;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+/// Timezone which FAT timestamps are recorded in
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum FatTimeZone {
+    Utc,
+    // Fixed offset east of UTC, in seconds
+    Offset(i32),
+    // Zone of the host, including daylight saving
+    Local,
+}
+
+impl FatTimeZone {
+    /// Parse "UTC", "local" or an offset such as "+10:00" or "-0530"
+    pub fn parse(value: &str) -> Result<FatTimeZone, String> {
+        let invalid = || format!("Invalid timezone '{}'", value);
+        match value.to_lowercase().as_str() {
+            "utc" => return Ok(FatTimeZone::Utc),
+            "local" => return Ok(FatTimeZone::Local),
+            _ => {}
+        }
+
+        let sign = match value.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid()),
+        };
+        let digits: String = value[1..].chars().filter(|c| *c != ':').collect();
+        if digits.is_empty()
+            || digits.len() > 4
+            || !digits.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        // Hours only, or hours followed by 2 digits of minutes
+        let (hours, minutes) = if digits.len() <= 2 {
+            (digits.as_str(), "0")
+        } else {
+            digits.split_at(digits.len() - 2)
+        };
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(FatTimeZone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+
+    /// Convert local FAT date time into unix timestamp
+    pub fn to_unix(self, datetime: PrimitiveDateTime) -> i64 {
+        let utc = datetime.assume_utc().unix_timestamp();
+        match self {
+            FatTimeZone::Utc => utc,
+            FatTimeZone::Offset(offset) => utc - offset as i64,
+            FatTimeZone::Local => local_to_unix(datetime).unwrap_or(utc),
+        }
+    }
+
+    /// Convert unix timestamp into local FAT date time, for encoding
+    pub fn to_local(self, timestamp: i64) -> PrimitiveDateTime {
+        let offset = match self {
+            FatTimeZone::Utc => 0,
+            FatTimeZone::Offset(offset) => offset as i64,
+            FatTimeZone::Local => local_offset(timestamp),
+        };
+        let local = OffsetDateTime::from_unix_timestamp(timestamp + offset)
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        PrimitiveDateTime::new(local.date(), local.time())
+    }
+}
+
+/// Interpret date time in the zone of the host using mktime
+fn local_to_unix(datetime: PrimitiveDateTime) -> Option<i64> {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = datetime.year() - 1900;
+    tm.tm_mon = datetime.month() as i32 - 1;
+    tm.tm_mday = datetime.day() as i32;
+    tm.tm_hour = datetime.hour() as i32;
+    tm.tm_min = datetime.minute() as i32;
+    tm.tm_sec = datetime.second() as i32;
+    // Let mktime determine whether daylight saving applies
+    tm.tm_isdst = -1;
+    let timestamp = unsafe { libc::mktime(&mut tm) };
+    if timestamp == -1 {
+        None
+    } else {
+        Some(timestamp as i64)
+    }
+}
+
+/// Offset of the zone of the host at given time, in seconds
+fn local_offset(timestamp: i64) -> i64 {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let time = timestamp as libc::time_t;
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+/// Build date time from decoded FAT fields, None if not a valid date
+fn fat_datetime(
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+) -> Option<PrimitiveDateTime> {
+    let month = Month::try_from(month).ok()?;
+    let date = Date::from_calendar_date(year.into(), month, day).ok()?;
+    let time = Time::from_hms(hour, minute, second).ok()?;
+    Some(PrimitiveDateTime::new(date, time))
+}
+
+/// Converts unix timestamp and nanoseconds to SystemTime
+pub fn unix_timestamp_to_systemtime(timestamp: i64, nanos: u32) -> SystemTime {
+    if timestamp < 0 {
+        SystemTime::UNIX_EPOCH
+            .checked_sub(Duration::from_secs(-timestamp as u64))
+            .unwrap()
+            + Duration::from_nanos(nanos.into())
+    } else {
+        SystemTime::UNIX_EPOCH
+            .checked_add(Duration::new(timestamp as u64, nanos))
+            .unwrap()
+    }
+}
+
+/// Converts SystemTime to FAT date time in given zone
+pub fn systemtime_to_fat(time: SystemTime, tz: FatTimeZone) -> FatDateTime {
+    let (timestamp, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+        Err(e) => (-(e.duration().as_secs() as i64), 0),
+    };
+    let local = tz.to_local(timestamp);
+    FatDateTime {
+        year: local.year().max(0) as u16,
+        month: local.month() as u8,
+        day: local.day(),
+        hour: local.hour(),
+        minute: local.minute(),
+        second: local.second(),
+        centiseconds: (nanos / 10_000_000) as u8,
+    }
+}
+
+/// Parse modify time into timestamp
+pub fn parse_modify_time(
+    entry: &FatDirectoryEntryContainer,
+    tz: FatTimeZone,
+) -> i64 {
+    let (year, month, day, hour, minute, second) = entry.get_write_time();
+    match fat_datetime(year, month, day, hour, minute, second) {
+        None => 0,
+        Some(datetime) => tz.to_unix(datetime),
+    }
+}
+
+/// Parse create time into timestamp and nanoseconds
+pub fn parse_create_time(
+    entry: &FatDirectoryEntryContainer,
+    tz: FatTimeZone,
+) -> (i64, u32) {
+    let (year, month, day, hour, minute, second, centiseconds) =
+        entry.get_creation_time();
+    match fat_datetime(year, month, day, hour, minute, second) {
+        None => (0, 0),
+        Some(datetime) => {
+            // Centiseconds count up to 1.99 seconds past the 2 second stamp
+            let centiseconds = centiseconds.min(199);
+            let timestamp = tz.to_unix(datetime) + (centiseconds / 100) as i64;
+            (timestamp, (centiseconds % 100) as u32 * 10_000_000)
+        }
+    }
+}
+
+/// Parse last access date into timestamp
+pub fn parse_access_date(
+    entry: &FatDirectoryEntryContainer,
+    tz: FatTimeZone,
+) -> i64 {
+    let (year, month, day) = entry.get_last_accessed_date();
+    match fat_datetime(year, month, day, 0, 0, 0) {
+        None => 0,
+        Some(datetime) => tz.to_unix(datetime),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_fat::Fat;
+    use time::{Date, Month, PrimitiveDateTime, Time};
+
+    use super::{parse_create_time, FatTimeZone};
+
+    /// Image whose entries have known timestamps
+    const TIMES_IMAGE: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/times.img");
+
+    /// 2020-02-29 13:37:42, 1582983462 seconds after the epoch in UTC
+    fn leap_day() -> PrimitiveDateTime {
+        let date = Date::from_calendar_date(2020, Month::February, 29);
+        let time = Time::from_hms(13, 37, 42);
+        PrimitiveDateTime::new(date.unwrap(), time.unwrap())
+    }
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!(FatTimeZone::parse("UTC"), Ok(FatTimeZone::Utc));
+        assert_eq!(FatTimeZone::parse("utc"), Ok(FatTimeZone::Utc));
+        assert_eq!(FatTimeZone::parse("Local"), Ok(FatTimeZone::Local));
+        let offset = |value| FatTimeZone::parse(value).unwrap();
+        assert_eq!(offset("+05:30"), FatTimeZone::Offset(19800));
+        assert_eq!(offset("+0530"), FatTimeZone::Offset(19800));
+        assert_eq!(offset("-05:30"), FatTimeZone::Offset(-19800));
+        assert_eq!(offset("+10"), FatTimeZone::Offset(36000));
+        assert_eq!(offset("-3"), FatTimeZone::Offset(-10800));
+        assert_eq!(offset("+00:00"), FatTimeZone::Offset(0));
+    }
+
+    #[test]
+    fn rejects_invalid_offsets() {
+        for value in [
+            "", "+", "05:30", "+24:00", "+05:60", "+12345", "+ab", "-05:3x",
+            "EST", "UTC+1",
+        ] {
+            let error = format!("Invalid timezone '{}'", value);
+            assert_eq!(FatTimeZone::parse(value), Err(error));
+        }
+    }
+
+    #[test]
+    fn converts_between_zones_and_timestamps() {
+        let datetime = leap_day();
+        assert_eq!(FatTimeZone::Utc.to_unix(datetime), 1582983462);
+        // Clocks east of UTC show the same time earlier
+        let east = FatTimeZone::Offset(19800);
+        assert_eq!(east.to_unix(datetime), 1582983462 - 19800);
+        let west = FatTimeZone::Offset(-19800);
+        assert_eq!(west.to_unix(datetime), 1582983462 + 19800);
+
+        for tz in [FatTimeZone::Utc, east, west, FatTimeZone::Local] {
+            assert_eq!(tz.to_local(tz.to_unix(datetime)), datetime);
+        }
+        assert_eq!(east.to_local(1582983462 - 19800), datetime);
+    }
+
+    #[test]
+    fn carries_centiseconds_of_creation_time() {
+        let mut fat = Fat::mount_volume(TIMES_IMAGE).unwrap();
+        let mut created = |name: &str, tz: FatTimeZone| {
+            let entry = fat.lookup(0, name).unwrap().unwrap();
+            parse_create_time(entry, tz)
+        };
+        // Created at 13:37:42 with 150 centiseconds
+        let tz = FatTimeZone::Utc;
+        assert_eq!(created("CARRY.TXT", tz), (1582983463, 500_000_000));
+        let tz = FatTimeZone::Offset(3600);
+        assert_eq!(created("CARRY.TXT", tz), (1582979863, 500_000_000));
+        // Created at 2020-12-31 23:59:58, centiseconds beyond 199 are cut off
+        let tz = FatTimeZone::Utc;
+        assert_eq!(created("CLAMP.TXT", tz), (1609459199, 990_000_000));
+        // Created at 2019-12-31 08:00:00 without centiseconds
+        assert_eq!(created("DIR", tz), (1577779200, 0));
+    }
+}
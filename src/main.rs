@@ -1,20 +1,610 @@
 extern crate clap;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use std::path::Path;
+
+extern crate lib_fat;
+use lib_fat::Fat;
+
+mod fat_cli;
+
+mod fat_daemon;
+use fat_daemon::{block_exit_signals, daemonize, unmount, wait_for_exit};
+
+mod fat_deleted;
 
 mod fat_fuse;
 use fat_fuse::FatFS;
 
+mod fat_options;
+use fat_options::FatOptions;
+
+#[cfg(test)]
+mod fat_test;
+
+mod fat_time;
+use fat_time::FatTimeZone;
+
 fn main() {
-    let matches = App::new("fat-fuse")
+    // Invoked by mount(8) for fstab entries of type fuse.fat-fuse
+    let program = std::env::args().next().unwrap_or_default();
+    let mount_helper = is_mount_helper(&program);
+
+    let matches = if mount_helper {
+        mount_helper_app().get_matches()
+    } else {
+        app().get_matches()
+    };
+
+    // Offline subcommands work on the image without mounting it
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        if let Err(e) = run_subcommand(name, sub_matches) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let filename = matches.value_of("image_file").unwrap();
+    let mount_point = matches.value_of("mount_point").unwrap();
+    let mut options: Vec<&str> = match matches.values_of("options") {
+        None => vec![],
+        Some(values) => values.flat_map(|o| o.split(',')).collect(),
+    };
+    // Sloppy mode drops options which are not understood
+    if mount_helper && matches.is_present("sloppy") {
+        options.retain(|o| FatOptions::parse(&[o]).is_ok());
+    }
+    let (fat_options, mut mount_options) = match FatOptions::parse(&options) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Name the mount after the image unless told otherwise
+    if !mount_options
+        .iter()
+        .any(|o| matches!(o, fuser::MountOption::FSName(_)))
+    {
+        mount_options.push(fuser::MountOption::FSName(filename.into()));
+    }
+    if !mount_options
+        .iter()
+        .any(|o| matches!(o, fuser::MountOption::Subtype(_)))
+    {
+        mount_options.push(fuser::MountOption::Subtype("fat-fuse".into()));
+    }
+
+    // Fake mount only validates the arguments
+    if mount_helper && matches.is_present("fake") {
+        return;
+    }
+    // mount(8) expects helpers to return once mounted
+    let foreground = !mount_helper && matches.is_present("foreground");
+    mount(
+        filename,
+        mount_point,
+        fat_options,
+        &mount_options,
+        foreground,
+    );
+}
+
+/// Whether program is invoked under the name mount(8) uses for helpers
+fn is_mount_helper(program: &str) -> bool {
+    match Path::new(program).file_name() {
+        Some(name) => name == "mount.fat-fuse" || name == "mount.fuse.fat-fuse",
+        None => false,
+    }
+}
+
+/// Arguments shared by both ways of invocation
+fn base_app() -> App<'static> {
+    App::new("fat-fuse")
         .version("0.1.0")
-        .about("Readonly FUSE implemention of FAT12/16/32 filesystems")
+        .about("FUSE implementation of FAT12/16/32 filesystems")
         .arg(Arg::with_name("image_file").required(true))
         .arg(Arg::with_name("mount_point").required(true))
-        .get_matches();
-    let filename = matches.value_of("image_file").unwrap();
-    let mount_point = matches.value_of("mount_point").unwrap();
+        .arg(
+            Arg::with_name("options")
+                .short('o')
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Mount options, e.g. allow_other,uid=1000,umask=022"),
+        )
+}
+
+/// Arguments when invoked directly
+fn app() -> App<'static> {
+    base_app()
+        .arg(
+            Arg::with_name("foreground")
+                .short('f')
+                .long("foreground")
+                .help("Stay in the foreground instead of daemonizing"),
+        )
+        // Mounting remains the default without a subcommand
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            App::new("ls")
+                .about("List directory")
+                .arg(Arg::with_name("long").short('l').help("Long listing"))
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            App::new("cat")
+                .about("Write file to standard output")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            App::new("stat")
+                .about("Show details of file or directory")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            App::new("tree")
+                .about("Show directory hierarchy")
+                .arg(Arg::with_name("image_file").required(true)),
+        )
+        .subcommand(
+            App::new("info")
+                .about("Show boot sector and parameter blocks")
+                .arg(Arg::with_name("image_file").required(true)),
+        )
+        .subcommand(
+            App::new("extract")
+                .about("Copy directory tree out of image")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("dest").required(true))
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .default_value("/")
+                        .help("Directory or file within image to extract"),
+                )
+                .arg(Arg::with_name("xattrs").long("xattrs").help(
+                    "Record attributes and short names as user.fat.* \
+                     extended attributes",
+                ))
+                .arg(tz_arg()),
+        )
+        .subcommand(
+            App::new("frag")
+                .about("Show fragmentation of files and free space")
+                .arg(
+                    Arg::with_name("map")
+                        .long("map")
+                        .help("List the runs of clusters of every entry"),
+                )
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            App::new("tar")
+                .about("Write tar archive of directory tree to standard output")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").default_value("/"))
+                .arg(tz_arg()),
+        )
+        .subcommand(
+            App::new("build")
+                .about("Create image holding a copy of host directory")
+                .arg(Arg::with_name("src_dir").required(true))
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .takes_value(true)
+                        .help("Image size like 64M, fits content by default"),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .takes_value(true)
+                        .help("fat12, fat16 or fat32, from size by default"),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .long("label")
+                        .takes_value(true)
+                        .help("Volume label"),
+                )
+                .arg(Arg::with_name("reproducible").long("reproducible").help(
+                    "Take time stamps and serial from SOURCE_DATE_EPOCH for \
+                     byte-for-byte reproducible images",
+                )),
+        )
+        .subcommand(
+            App::new("cp")
+                .about("Copy file into or out of image, given as IMAGE::/path")
+                .arg(Arg::with_name("source").required(true))
+                .arg(Arg::with_name("dest").required(true)),
+        )
+        .subcommand(
+            App::new("mkdir")
+                .about("Create directory")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            App::new("rm")
+                .about("Remove file or empty directory")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            App::new("mv")
+                .about("Move or rename file or directory")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").required(true))
+                .arg(Arg::with_name("dest").required(true)),
+        )
+        .subcommand(
+            App::new("attrib")
+                .about("Show or change attributes, e.g. +r -a")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").required(true))
+                .arg(
+                    Arg::with_name("changes")
+                        .multiple_values(true)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .subcommand(
+            App::new("sort")
+                .about("Sort directory entries on disk")
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .takes_value(true)
+                        .default_value("natural")
+                        .help("natural, ascii or mtime"),
+                )
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .takes_value(true)
+                        .help("File naming entries to put first, one per line"),
+                )
+                .arg(
+                    Arg::with_name("recursive")
+                        .short('r')
+                        .long("recursive")
+                        .help("Sort subdirectories as well"),
+                )
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            App::new("compact")
+                .about("Drop free slots left by removed directory entries")
+                .arg(
+                    Arg::with_name("recursive")
+                        .short('r')
+                        .long("recursive")
+                        .help("Compact subdirectories as well"),
+                )
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            App::new("defrag")
+                .about("Move fragmented files into single runs of clusters")
+                .arg(
+                    Arg::with_name("first")
+                        .long("first")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .help("Place file at the start of the data region"),
+                )
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(
+                    Arg::with_name("paths")
+                        .multiple_values(true)
+                        .default_value("/"),
+                ),
+        )
+        .subcommand(
+            App::new("timeline")
+                .about("Print bodyfile of time stamps, as read by mactime")
+                .arg(
+                    Arg::with_name("deleted")
+                        .short('d')
+                        .long("deleted")
+                        .help("Include removed entries"),
+                )
+                .arg(tz_arg())
+                .arg(Arg::with_name("image_file").required(true)),
+        )
+        .subcommand(
+            App::new("carve")
+                .about("Recover files from free clusters by their signatures")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("dest").required(true)),
+        )
+        .subcommand(
+            App::new("undelete")
+                .about("List removed entries of a directory, or restore one")
+                .arg(Arg::with_name("image_file").required(true))
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+}
+
+/// Option giving the zone time stamps of the image are recorded in
+fn tz_arg() -> Arg<'static> {
+    Arg::with_name("tz")
+        .long("tz")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .default_value("local")
+        .help("Zone of time stamps, UTC, local or an offset")
+}
+
+/// Runs offline subcommand against image
+fn run_subcommand(name: &str, matches: &ArgMatches) -> fat_cli::CliResult {
+    match name {
+        "cp" => return copy(matches),
+        "build" => return build(matches),
+        "undelete" => return undelete(matches),
+        _ => {}
+    }
+    let image_file = matches.value_of("image_file").unwrap();
+    // Only attrib takes attribute changes
+    let changes: Vec<&str> = match name {
+        "attrib" => {
+            matches.values_of("changes").into_iter().flatten().collect()
+        }
+        _ => vec![],
+    };
+    let rw = match name {
+        "mkdir" | "rm" | "mv" | "sort" | "compact" | "defrag" => true,
+        "attrib" => !changes.is_empty(),
+        _ => false,
+    };
+    let mut fat = open_image(image_file, rw)?;
+    let path = || matches.value_of("path").unwrap();
+    match name {
+        "ls" => fat_cli::ls(&mut fat, path(), matches.is_present("long")),
+        "cat" => fat_cli::cat(&mut fat, path()),
+        "stat" => fat_cli::stat(&mut fat, path()),
+        "tree" => fat_cli::tree(&mut fat),
+        "info" => fat_cli::info(&fat),
+        "extract" => fat_cli::extract(
+            &mut fat,
+            path(),
+            matches.value_of("dest").unwrap(),
+            matches.is_present("xattrs"),
+            FatTimeZone::parse(matches.value_of("tz").unwrap())?,
+        ),
+        "tar" => {
+            let tz = FatTimeZone::parse(matches.value_of("tz").unwrap())?;
+            fat_cli::tar(&mut fat, path(), tz)
+        }
+        "frag" => fat_cli::frag(&mut fat, path(), matches.is_present("map")),
+        "mkdir" => fat_cli::mkdir(&mut fat, path()),
+        "rm" => fat_cli::rm(&mut fat, path()),
+        "mv" => {
+            fat_cli::mv(&mut fat, path(), matches.value_of("dest").unwrap())
+        }
+        "attrib" => fat_cli::attrib(&mut fat, path(), &changes),
+        "sort" => {
+            let order = fat_cli::parse_sort_order(
+                matches.value_of("by").unwrap(),
+                matches.value_of("list"),
+            )?;
+            let recursive = matches.is_present("recursive");
+            fat_cli::sort(&mut fat, path(), &order, recursive)
+        }
+        "compact" => {
+            fat_cli::compact(&mut fat, path(), matches.is_present("recursive"))
+        }
+        "defrag" => {
+            let paths: Vec<&str> =
+                matches.values_of("paths").into_iter().flatten().collect();
+            let first: Vec<&str> =
+                matches.values_of("first").into_iter().flatten().collect();
+            fat_cli::defrag(&mut fat, &paths, &first)
+        }
+        "carve" => fat_cli::carve(&mut fat, matches.value_of("dest").unwrap()),
+        "timeline" => {
+            let tz = FatTimeZone::parse(matches.value_of("tz").unwrap())?;
+            fat_cli::timeline(&mut fat, matches.is_present("deleted"), tz)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Opens image, for writing if rw
+fn open_image(image_file: &str, rw: bool) -> Result<Fat, String> {
+    let fat = if rw {
+        Fat::mount_volume_rw(image_file)
+    } else {
+        Fat::mount_volume(image_file)
+    };
+    fat.map_err(|e| format!("Cannot open {}: {}", image_file, e))
+}
+
+/// Runs build, which creates the image instead of opening it
+fn build(matches: &ArgMatches) -> fat_cli::CliResult {
+    let size = match matches.value_of("size") {
+        None => None,
+        Some(value) => Some(fat_cli::parse_size(value)?),
+    };
+    let fat_type = match matches.value_of("type") {
+        None => None,
+        Some(value) => Some(fat_cli::parse_fat_type(value)?),
+    };
+    fat_cli::build(
+        matches.value_of("src_dir").unwrap(),
+        matches.value_of("image_file").unwrap(),
+        size,
+        fat_type,
+        matches.value_of("label"),
+        matches.is_present("reproducible"),
+    )
+}
+
+/// Runs undelete, which only opens the image for writing to restore an entry,
+/// so removed entries of read-only images can be listed
+fn undelete(matches: &ArgMatches) -> fat_cli::CliResult {
+    let image_file = matches.value_of("image_file").unwrap();
+    let path = matches.value_of("path").unwrap();
+    let mut fat = open_image(image_file, false)?;
+    let plan = fat_cli::plan_undelete(&mut fat, path)?;
+    if let fat_cli::Undelete::Restore(..) = plan {
+        fat = open_image(image_file, true)?;
+    }
+    fat_cli::undelete(&mut fat, path, &plan)
+}
+
+/// Runs cp, where exactly one of source and destination is IMAGE::/path
+fn copy(matches: &ArgMatches) -> fat_cli::CliResult {
+    let source = matches.value_of("source").unwrap();
+    let dest = matches.value_of("dest").unwrap();
+    match (fat_cli::image_path(source), fat_cli::image_path(dest)) {
+        (None, Some((image_file, path))) => {
+            let mut fat = open_image(image_file, true)?;
+            fat_cli::copy_in(&mut fat, source, path)
+        }
+        (Some((image_file, path)), None) => {
+            let mut fat = open_image(image_file, false)?;
+            fat_cli::copy_out(&mut fat, path, dest)
+        }
+        _ => Err("Exactly one of source and destination must be \
+                  IMAGE::/path"
+            .into()),
+    }
+}
+
+/// Arguments passed by mount(8): spec dir [-sfnv] [-N ns] [-o opts] [-t type]
+fn mount_helper_app() -> App<'static> {
+    base_app()
+        .arg(Arg::with_name("sloppy").short('s'))
+        .arg(Arg::with_name("fake").short('f'))
+        .arg(Arg::with_name("no_mtab").short('n'))
+        .arg(Arg::with_name("verbose").short('v'))
+        .arg(Arg::with_name("namespace").short('N').takes_value(true))
+        .arg(Arg::with_name("type").short('t').takes_value(true))
+}
+
+/// Mounts and serves the filesystem until unmounted or signalled
+/// Unless in the foreground, returns to the caller once mounted
+fn mount(
+    filename: &str,
+    mount_point: &str,
+    fat_options: FatOptions,
+    mount_options: &[fuser::MountOption],
+    foreground: bool,
+) {
+    let daemon = if foreground { None } else { Some(daemonize()) };
+    let signals = block_exit_signals();
+
+    // Errors are reported to the parent by exiting before being ready
+    let fat_fs = match FatFS::new(filename, fat_options) {
+        Ok(fat_fs) => fat_fs,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    };
+    if foreground {
+        println!("Volume type: {}", fat_fs.fat_type());
+    }
+    let session = match fuser::spawn_mount2(fat_fs, mount_point, mount_options)
+    {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Cannot mount {}: {}", mount_point, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(daemon) = daemon {
+        daemon.ready();
+    }
+
+    // Unmount cleanly rather than leaving a stale mount behind
+    wait_for_exit(&signals, || session.guard.is_finished());
+    if let Err(e) = unmount(session) {
+        eprintln!("Cannot serve {}: {}", mount_point, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{app, is_mount_helper, mount_helper_app};
+
+    #[test]
+    fn recognises_mount_helper_names() {
+        assert!(is_mount_helper("/sbin/mount.fuse.fat-fuse"));
+        assert!(is_mount_helper("mount.fat-fuse"));
+        assert!(!is_mount_helper("/usr/bin/fat-fuse"));
+        assert!(!is_mount_helper("mount.fuse.fat-fuse.bak"));
+        assert!(!is_mount_helper(""));
+    }
+
+    #[test]
+    fn parses_mount_helper_arguments() {
+        let matches = mount_helper_app()
+            .try_get_matches_from([
+                "mount.fuse.fat-fuse",
+                "/dev/sdb1",
+                "/mnt/usb",
+                "-s",
+                "-n",
+                "-o",
+                "ro,uid=1000",
+                "-t",
+                "fuse.fat-fuse",
+            ])
+            .unwrap();
+        assert_eq!(matches.value_of("image_file"), Some("/dev/sdb1"));
+        assert_eq!(matches.value_of("mount_point"), Some("/mnt/usb"));
+        assert_eq!(matches.value_of("options"), Some("ro,uid=1000"));
+        assert!(matches.is_present("sloppy"));
+        assert!(matches.is_present("no_mtab"));
+        assert!(!matches.is_present("fake"));
+
+        // Options mount(8) passes which are only meant for the helper
+        let matches = mount_helper_app()
+            .try_get_matches_from([
+                "mount.fat-fuse",
+                "disk.img",
+                "/mnt",
+                "-fv",
+                "-N",
+                "/proc/1/ns/mnt",
+            ])
+            .unwrap();
+        assert!(matches.is_present("fake"));
+        assert!(matches.is_present("verbose"));
+        assert_eq!(matches.value_of("namespace"), Some("/proc/1/ns/mnt"));
+    }
+
+    #[test]
+    fn parses_zone_of_time_stamps() {
+        for subcommand in ["extract", "tar", "timeline"] {
+            let args = ["fat-fuse", subcommand, "disk.img", "dest"];
+            let args = &args[..if subcommand == "extract" { 4 } else { 3 }];
+            let matches = app().try_get_matches_from(args).unwrap();
+            let matches = matches.subcommand_matches(subcommand).unwrap();
+            assert_eq!(matches.value_of("tz"), Some("local"), "{}", subcommand);
 
-    // Init and mount
-    let fat_fs = FatFS::new(filename);
-    fuser::mount2(fat_fs, &mount_point, &[]).unwrap();
+            let args = [args, &["--tz", "-05:00"]].concat();
+            let matches = app().try_get_matches_from(args).unwrap();
+            let matches = matches.subcommand_matches(subcommand).unwrap();
+            assert_eq!(
+                matches.value_of("tz"),
+                Some("-05:00"),
+                "{}",
+                subcommand
+            );
+        }
+    }
 }